@@ -1,10 +1,18 @@
 //! Filters buckets using bloom(like) filter.
 
 use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::thread;
 
-use crate::{bucket::Bucket, evt::Event};
+use crate::{
+    bucket::{Bucket, DatePrefix},
+    evt::Event,
+};
 
 /// List of bloom check results.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum BloomResult {
     /// An item may exist.
     MayExist,
@@ -111,6 +119,88 @@ where
     }
 }
 
+/// How to report a skipped bucket.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmptyPolicy {
+    /// An empty vec(same as an empty bucket).
+    EmptyVec,
+
+    /// An error.
+    Error,
+
+    /// `None`(distinct from `Some(vec![])` for an empty bucket).
+    None,
+}
+
+impl EmptyPolicy {
+    /// Gets the result for the skipped bucket.
+    pub fn skipped<T>(&self, bucket: &Bucket) -> Result<Option<Vec<T>>, Event> {
+        match self {
+            Self::EmptyVec => Ok(Some(vec![])),
+            Self::Error => Err(Event::UnexpectedError(format!(
+                "The bucket has been skipped: {}",
+                bucket.as_str()
+            ))),
+            Self::None => Ok(None),
+        }
+    }
+}
+
+/// Gets values from a slow db if the values may exists; reports a skip by the policy.
+///
+/// # Arguments
+/// - policy: How to report a skipped bucket.
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn get_or_skip_if_missing_with_policy<B, D, G, F, T>(
+    policy: EmptyPolicy,
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Option<Vec<T>>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    match bloom(bucket, filter) {
+        BloomResult::Missing => policy.skipped(bucket),
+        BloomResult::MayExist => getter(shared_db, bucket, filter).map(Some),
+    }
+}
+
+/// Gets values from a slow db if the values may exists; always gets values without a filter.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket(all values if no filter).
+/// - filter: The filter to get values; `None` to scan the bucket(no bloom check).
+pub fn get_or_skip_opt_filter<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: Option<&F>,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, Option<&F>) -> Result<Vec<T>, Event>,
+{
+    let verdict: BloomResult = filter
+        .map(|f| bloom(bucket, f))
+        .unwrap_or(BloomResult::MayExist);
+    match verdict {
+        BloomResult::Missing => Ok(vec![]),
+        BloomResult::MayExist => getter(shared_db, bucket, filter),
+    }
+}
+
 /// Gets bloom bits and updates the bloom bits container.
 ///
 /// # Arguments
@@ -129,13 +219,104 @@ where
 {
     bloom_bits.clear();
     let v: Vec<_> = get_bloom_bits(shared_db, bloom_bucket)?;
-    Ok(v.into_iter().fold(0, |tot, pair| {
+    Ok(insert_bloom_bits(bloom_bits, v))
+}
+
+fn insert_bloom_bits<B>(bloom_bits: &mut BTreeMap<Bucket, B>, v: Vec<(Bucket, B)>) -> u64 {
+    v.into_iter().fold(0, |tot, pair| {
         let (bucket, bits) = pair;
         match bloom_bits.insert(bucket, bits) {
             None => 1 + tot,
             Some(_) => tot,
         }
-    }))
+    })
+}
+
+/// Gets bloom bits changed since the last version and merges them into the container.
+///
+/// # Arguments
+/// - bloom_bits: The bloom bits container to be updated.
+/// - shared_db: The db which contains bloom bits.
+/// - get_changed: Gets the bloom bits(with versions) of buckets changed after the version.
+/// - last_version: The version of the last load; advanced to the max version seen.
+///
+/// # Returns
+/// The number of updated buckets.
+pub fn update_bloom_bits_since<D, B, G>(
+    bloom_bits: &mut BTreeMap<Bucket, B>,
+    shared_db: &mut D,
+    get_changed: &mut G,
+    last_version: &mut u64,
+) -> Result<u64, Event>
+where
+    G: FnMut(&mut D, u64) -> Result<Vec<(Bucket, B, u64)>, Event>,
+{
+    let changed: Vec<(Bucket, B, u64)> = get_changed(shared_db, *last_version)?;
+    let mut updated: u64 = 0;
+    for (bucket, bits, version) in changed {
+        bloom_bits.insert(bucket, bits);
+        *last_version = (*last_version).max(version);
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// List of refresh results.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RefreshResult {
+    /// The bloom bits have been refreshed(the number of buckets).
+    Refreshed(u64),
+
+    /// Another caller is refreshing the bloom bits.
+    InProgress,
+}
+
+struct RefreshGuard<'a> {
+    flag: &'a AtomicBool,
+}
+
+impl Drop for RefreshGuard<'_> {
+    fn drop(&mut self) {
+        self.flag.store(false, Ordering::Release);
+    }
+}
+
+/// Refreshes bloom bits unless another caller is refreshing them.
+///
+/// The bloom bits are fetched without holding the lock;
+/// the container is replaced only if the fetch succeeds.
+///
+/// # Arguments
+/// - flag: Set while a refresh is in progress.
+/// - bloom_bits: The bloom bits container to be updated.
+/// - shared_db: The db which contains bloom bits.
+/// - get_bloom_bits: Gets bloom bits for each bucket.
+/// - bloom_bucket: The bucket which contains bloom bits for each bucket.
+pub fn refresh_once<D, B, G>(
+    flag: &AtomicBool,
+    bloom_bits: &RwLock<BTreeMap<Bucket, B>>,
+    shared_db: &mut D,
+    get_bloom_bits: &mut G,
+    bloom_bucket: &Bucket,
+) -> Result<RefreshResult, Event>
+where
+    G: FnMut(&mut D, &Bucket) -> Result<Vec<(Bucket, B)>, Event>,
+{
+    let acquired: bool = flag
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    match acquired {
+        false => Ok(RefreshResult::InProgress),
+        true => {
+            let _guard = RefreshGuard { flag };
+            let v: Vec<_> = get_bloom_bits(shared_db, bloom_bucket)?;
+            let mut locked = bloom_bits
+                .write()
+                .map_err(|e| Event::UnexpectedError(format!("Unable to lock bloom bits: {}", e)))?;
+            locked.clear();
+            Ok(RefreshResult::Refreshed(insert_bloom_bits(&mut locked, v)))
+        }
+    }
 }
 
 /// Checks if values may exists or not.
@@ -153,13 +334,39 @@ pub fn bloom_check<B, H, F, C>(
     check: &C,
     b: &Bucket,
 ) -> BloomResult
+where
+    H: Fn(&F) -> B,
+    C: Fn(&B, &B) -> BloomResult,
+{
+    bloom_check_with_default(bloom_bits, hash, filter, check, b, BloomResult::Missing)
+}
+
+/// Checks if values may exists or not; uses the default if the bucket has no bloom bits.
+///
+/// Use [`BloomResult::MayExist`] as the default to scan buckets without bloom bits(fail-open).
+///
+/// # Arguments
+/// - bloom_bits: Contains bloom bits for each bucket.
+/// - hash: Computes the hash to be compared.
+/// - filter: The filter to compute a hash.
+/// - check: Checks if values may exists or not.
+/// - b: The bucket which may contain values.
+/// - default: The result for a bucket without bloom bits.
+pub fn bloom_check_with_default<B, H, F, C>(
+    bloom_bits: &BTreeMap<Bucket, B>,
+    hash: &H,
+    filter: &F,
+    check: &C,
+    b: &Bucket,
+    default: BloomResult,
+) -> BloomResult
 where
     H: Fn(&F) -> B,
     C: Fn(&B, &B) -> BloomResult,
 {
     let bloom_b: Option<&B> = bloom_bits.get(b);
     match bloom_b {
-        None => BloomResult::Missing,
+        None => default,
         Some(found) => {
             let computed: B = hash(filter);
             check(found, &computed)
@@ -167,6 +374,34 @@ where
     }
 }
 
+/// Checks if values may exists or not; values may exist if the hash can not be computed.
+///
+/// # Arguments
+/// - bloom_bits: Contains bloom bits for each bucket.
+/// - hash: Computes the hash to be compared; `None` if the filter lacks the hashed fields.
+/// - filter: The filter to compute a hash.
+/// - check: Checks if values may exists or not.
+/// - b: The bucket which may contain values.
+pub fn bloom_check_opt_hash<B, H, F, C>(
+    bloom_bits: &BTreeMap<Bucket, B>,
+    hash: &H,
+    filter: &F,
+    check: &C,
+    b: &Bucket,
+) -> BloomResult
+where
+    H: Fn(&F) -> Option<B>,
+    C: Fn(&B, &B) -> BloomResult,
+{
+    match hash(filter) {
+        None => BloomResult::MayExist,
+        Some(computed) => bloom_bits
+            .get(b)
+            .map(|found| check(found, &computed))
+            .unwrap_or(BloomResult::Missing),
+    }
+}
+
 /// Creates new checker which uses closures to compute hash / check bloom bits.
 pub fn bloom_check_new<B, H, F, C>(
     hash: H,
@@ -180,3 +415,2712 @@ where
         bloom_check(bits, &hash, filter, &check, b)
     }
 }
+
+/// A hasher whose output is fixed across Rust releases and platforms(FNV-1a with a final mix).
+///
+/// Bloom bits are stored in slow dbs and checked later, possibly by another build of this crate;
+/// [`DefaultHasher`](std::collections::hash_map::DefaultHasher) does not guarantee that. Integers
+/// are fed as little endian bytes and `usize`/`isize` as 64 bit integers.
+#[derive(Clone, Copy, Debug)]
+pub struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x00000100000001b3;
+
+    /// Creates a hasher with the initial state.
+    pub fn new() -> Self {
+        Self {
+            state: Self::OFFSET_BASIS,
+        }
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.state = (self.state ^ u64::from(*b)).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write(&[i])
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_u8(i as u8)
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64)
+    }
+
+    /// Gets the state mixed(the finalizer of MurmurHash3) so that all output bits depend on
+    /// all input bytes.
+    fn finish(&self) -> u64 {
+        let mut h: u64 = self.state;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^= h >> 33;
+        h
+    }
+}
+
+/// Wraps the hash by the width before narrowing it to `usize`(same on 32 and 64 bit targets).
+fn stable_index(hashed: u64, width: usize) -> usize {
+    (hashed % width as u64) as usize
+}
+
+/// Packed bloom bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BloomBits<const N: usize> {
+    packed: [u128; N],
+}
+
+/// Bloom bits which uses 2 words(256 bits).
+pub type BloomBits256 = BloomBits<2>;
+
+impl<const N: usize> Default for BloomBits<N> {
+    fn default() -> Self {
+        Self { packed: [0; N] }
+    }
+}
+
+impl<const N: usize> BloomBits<N> {
+    /// Creates bloom bits from packed words.
+    pub fn new(packed: [u128; N]) -> Self {
+        Self { packed }
+    }
+
+    /// Gets the packed words.
+    pub fn packed(&self) -> &[u128; N] {
+        &self.packed
+    }
+
+    /// Clears all bits in place.
+    pub fn clear(&mut self) {
+        self.packed = [0; N];
+    }
+
+    /// Checks if all bits are zero.
+    pub fn is_empty(&self) -> bool {
+        self.packed.iter().all(|w| 0 == *w)
+    }
+
+    /// Gets the number of bits.
+    pub fn width(&self) -> usize {
+        N * 128
+    }
+
+    /// Gets the number of set bits.
+    pub fn count_ones(&self) -> u32 {
+        self.packed.iter().map(|w| w.count_ones()).sum()
+    }
+
+    /// Sets the bit(`ix` is wrapped by the number of bits).
+    pub fn set_bit(&mut self, ix: usize) {
+        let wrapped: usize = ix % self.width();
+        self.packed[wrapped / 128] |= 1 << (wrapped % 128);
+    }
+
+    /// Computes bloom bits of the item by setting `hashes` bits.
+    pub fn from_hash<I>(item: &I, hashes: u8) -> Self
+    where
+        I: Hash + ?Sized,
+    {
+        let mut bits: Self = Self::default();
+        for seed in 0..hashes {
+            let mut h = StableHasher::new();
+            seed.hash(&mut h);
+            item.hash(&mut h);
+            bits.set_bit(stable_index(h.finish(), bits.width()));
+        }
+        bits
+    }
+
+    /// Computes bitwise or of bloom bits.
+    pub fn or(&self, other: &Self) -> Self {
+        let mut packed: [u128; N] = [0; N];
+        for (i, p) in packed.iter_mut().enumerate() {
+            *p = self.packed[i] | other.packed[i];
+        }
+        Self { packed }
+    }
+
+    /// Computes bitwise and of bloom bits.
+    pub fn and(&self, other: &Self) -> Self {
+        let mut packed: [u128; N] = [0; N];
+        for (i, p) in packed.iter_mut().enumerate() {
+            *p = self.packed[i] & other.packed[i];
+        }
+        Self { packed }
+    }
+
+    /// Checks if all bits of other bits are set in this bits.
+    pub fn contains(&self, other: &Self) -> BloomResult {
+        let and: Self = self.and(other);
+        match and.eq(other) {
+            true => BloomResult::MayExist,
+            false => BloomResult::Missing,
+        }
+    }
+
+    /// Checks if the hamming distance(popcount of xor) is within `max_diff_bits`.
+    pub fn similar(&self, other: &Self, max_diff_bits: u32) -> bool {
+        let diff: u32 = self
+            .packed
+            .iter()
+            .zip(other.packed.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        diff <= max_diff_bits
+    }
+
+    /// Converts to bytes(big endian words, lowest word first).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.packed.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    /// Creates bloom bits from bytes(big endian words, lowest word first).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Event> {
+        let expected: usize = N * 16;
+        match bytes.len() == expected {
+            true => Ok(()),
+            false => Err(Event::UnexpectedError(format!(
+                "Invalid bloom bytes length. expected={}, actual={}",
+                expected,
+                bytes.len(),
+            ))),
+        }?;
+        let mut packed: [u128; N] = [0; N];
+        for (p, chunk) in packed.iter_mut().zip(bytes.chunks_exact(16)) {
+            let mut word: [u8; 16] = [0; 16];
+            word.copy_from_slice(chunk);
+            *p = u128::from_be_bytes(word);
+        }
+        Ok(Self { packed })
+    }
+}
+
+impl BloomBits256 {
+    /// Gets the indices of the bits set in `other` but not in `self`.
+    ///
+    /// An empty vec means `other` is a subset(see [`BloomBits::contains`]).
+    pub fn missing_bits(&self, other: &Self) -> Vec<u8> {
+        self.packed
+            .iter()
+            .zip(other.packed.iter())
+            .enumerate()
+            .flat_map(|(word, (s, o))| {
+                let missing: u128 = o & !s;
+                (0..128u8)
+                    .filter(move |bit| missing & (1 << bit) != 0)
+                    .map(move |bit| (word as u8) * 128 + bit)
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "base64")]
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[cfg(feature = "base64")]
+fn base64_encode(bytes: &[u8]) -> String {
+    bytes
+        .chunks(3)
+        .flat_map(|chunk| {
+            let b0: u32 = chunk[0].into();
+            let b1: u32 = chunk.get(1).copied().unwrap_or_default().into();
+            let b2: u32 = chunk.get(2).copied().unwrap_or_default().into();
+            let joined: u32 = (b0 << 16) | (b1 << 8) | b2;
+            let sextets = [18, 12, 6, 0].map(|shift| (joined >> shift) & 0x3f);
+            sextets
+                .into_iter()
+                .enumerate()
+                .map(move |(i, s)| match i <= chunk.len() {
+                    true => char::from(BASE64_TABLE[s as usize]),
+                    false => '=',
+                })
+        })
+        .collect()
+}
+
+#[cfg(feature = "base64")]
+fn base64_decode(encoded: &str) -> Result<Vec<u8>, Event> {
+    let invalid = || Event::UnexpectedError(format!("Invalid base64 string: {}", encoded));
+    let raw: &[u8] = encoded.as_bytes();
+    match raw.len() % 4 {
+        0 => Ok(()),
+        _ => Err(invalid()),
+    }?;
+    let mut bytes: Vec<u8> = Vec::with_capacity(raw.len() / 4 * 3);
+    let quads = raw.chunks_exact(4);
+    let last: usize = quads.len().saturating_sub(1);
+    for (i, quad) in quads.enumerate() {
+        let pad: usize = quad.iter().rev().take_while(|c| b'='.eq(c)).count();
+        let padding_allowed: bool = i == last && pad <= 2;
+        match pad == 0 || padding_allowed {
+            true => Ok(()),
+            false => Err(invalid()),
+        }?;
+        let mut joined: u32 = 0;
+        for c in &quad[..4 - pad] {
+            let sextet: usize = BASE64_TABLE
+                .iter()
+                .position(|t| t.eq(c))
+                .ok_or_else(invalid)?;
+            joined = (joined << 6) | (sextet as u32);
+        }
+        joined <<= 6 * pad;
+        let decoded: [u8; 4] = joined.to_be_bytes();
+        bytes.extend_from_slice(&decoded[1..4 - pad]);
+    }
+    Ok(bytes)
+}
+
+#[cfg(feature = "base64")]
+impl<const N: usize> BloomBits<N> {
+    /// Creates bloom bits from a base64 string(bytes: see [`BloomBits::from_bytes`]).
+    pub fn from_base64(encoded: &str) -> Result<Self, Event> {
+        let bytes: Vec<u8> = base64_decode(encoded)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Converts to a base64 string(bytes: see [`BloomBits::to_bytes`]).
+    pub fn to_base64(&self) -> String {
+        base64_encode(&self.to_bytes())
+    }
+}
+
+/// Bloom bits container with a generation counter to detect stale reads.
+pub struct VersionedBloomMap<B> {
+    bloom_bits: BTreeMap<Bucket, B>,
+    generation: u64,
+}
+
+impl<B> Default for VersionedBloomMap<B> {
+    fn default() -> Self {
+        Self {
+            bloom_bits: BTreeMap::new(),
+            generation: 0,
+        }
+    }
+}
+
+impl<B> VersionedBloomMap<B> {
+    /// Gets the current generation; incremented on each update.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Gets the bloom bits for each bucket.
+    pub fn bloom_bits(&self) -> &BTreeMap<Bucket, B> {
+        &self.bloom_bits
+    }
+
+    /// Updates the bloom bits(see [`update_bloom_bits`]) and increments the generation.
+    ///
+    /// The generation is incremented even if the update fails(the container is cleared).
+    pub fn update<D, G>(
+        &mut self,
+        shared_db: &mut D,
+        get_bloom_bits: &mut G,
+        bloom_bucket: &Bucket,
+    ) -> Result<u64, Event>
+    where
+        G: FnMut(&mut D, &Bucket) -> Result<Vec<(Bucket, B)>, Event>,
+    {
+        let updated = update_bloom_bits(
+            &mut self.bloom_bits,
+            shared_db,
+            get_bloom_bits,
+            bloom_bucket,
+        );
+        self.generation = self.generation.wrapping_add(1);
+        updated
+    }
+
+    /// Checks if values may exists or not(see [`bloom_check`]).
+    ///
+    /// Returns the result with the generation used for the check.
+    /// Compare it with [`VersionedBloomMap::generation`] to detect a concurrent update.
+    pub fn check<H, F, C>(&self, hash: &H, filter: &F, check: &C, b: &Bucket) -> (BloomResult, u64)
+    where
+        H: Fn(&F) -> B,
+        C: Fn(&B, &B) -> BloomResult,
+    {
+        let result: BloomResult = bloom_check(&self.bloom_bits, hash, filter, check, b);
+        (result, self.generation)
+    }
+}
+
+/// Checks if values may exists or not and returns the stored bloom bits.
+///
+/// # Arguments
+/// - bloom_bits: Contains bloom bits for each bucket.
+/// - b: The bucket which may contain values.
+/// - hash: Computes the hash to be compared.
+/// - filter: The filter to compute a hash.
+/// - check: Checks if values may exists or not.
+pub fn check_and_locate<'a, B, H, F, C>(
+    bloom_bits: &'a BTreeMap<Bucket, B>,
+    b: &Bucket,
+    hash: &H,
+    filter: &F,
+    check: &C,
+) -> (BloomResult, Option<&'a B>)
+where
+    H: Fn(&F) -> B,
+    C: Fn(&B, &B) -> BloomResult,
+{
+    let bloom_b: Option<&B> = bloom_bits.get(b);
+    match bloom_b {
+        None => (BloomResult::Missing, None),
+        Some(found) => {
+            let computed: B = hash(filter);
+            (check(found, &computed), Some(found))
+        }
+    }
+}
+
+/// Checks if values may exists or not for each bucket.
+///
+/// The hash is computed only once and reused for all buckets.
+///
+/// # Arguments
+/// - bloom_bits: Contains bloom bits for each bucket.
+/// - hash: Computes the hash to be compared.
+/// - filter: The filter to compute a hash.
+/// - check: Checks if values may exists or not.
+/// - buckets: The buckets which may contain values.
+pub fn check_many_buckets<B, H, F, C>(
+    bloom_bits: &BTreeMap<Bucket, B>,
+    hash: &H,
+    filter: &F,
+    check: &C,
+    buckets: &[Bucket],
+) -> Vec<(Bucket, BloomResult)>
+where
+    H: Fn(&F) -> B,
+    C: Fn(&B, &B) -> BloomResult,
+{
+    let computed: B = hash(filter);
+    buckets
+        .iter()
+        .map(|b| {
+            let bloom_b: Option<&B> = bloom_bits.get(b);
+            let result: BloomResult = match bloom_b {
+                None => BloomResult::Missing,
+                Some(found) => check(found, &computed),
+            };
+            (b.clone(), result)
+        })
+        .collect()
+}
+
+/// Checks if values may exists or not for each sorted bucket in a single map traversal.
+///
+/// Same as [`check_many_buckets`], but walks the map and the buckets together(merge join).
+/// The buckets must be sorted in ascending order(duplicates allowed);
+/// unsorted buckets are checked individually by [`check_many_buckets`].
+///
+/// # Arguments
+/// - bloom_bits: Contains bloom bits for each bucket.
+/// - hash: Computes the hash to be compared.
+/// - filter: The filter to compute a hash.
+/// - check: Checks if values may exists or not.
+/// - sorted_buckets: The sorted buckets which may contain values.
+pub fn check_sorted_buckets<B, H, F, C>(
+    bloom_bits: &BTreeMap<Bucket, B>,
+    hash: &H,
+    filter: &F,
+    check: &C,
+    sorted_buckets: &[Bucket],
+) -> Vec<(Bucket, BloomResult)>
+where
+    H: Fn(&F) -> B,
+    C: Fn(&B, &B) -> BloomResult,
+{
+    let sorted: bool = sorted_buckets.windows(2).all(|w| w[0] <= w[1]);
+    let first: &Bucket = match (sorted, sorted_buckets.first()) {
+        (true, Some(first)) => first,
+        _ => return check_many_buckets(bloom_bits, hash, filter, check, sorted_buckets),
+    };
+    let computed: B = hash(filter);
+    let mut stored = bloom_bits.range(first.clone()..).peekable();
+    sorted_buckets
+        .iter()
+        .map(|b| {
+            while stored.next_if(|(s, _)| *s < b).is_some() {}
+            let result: BloomResult = match stored.peek() {
+                Some((s, found)) if *s == b => check(found, &computed),
+                _ => BloomResult::Missing,
+            };
+            (b.clone(), result)
+        })
+        .collect()
+}
+
+/// Counts false positives(`MayExist` but no hits) for each bucket.
+///
+/// Record false positives from the scan path and log [`FalsePositiveAggregator::report`] periodically.
+#[derive(Default)]
+pub struct FalsePositiveAggregator {
+    counts: BTreeMap<Bucket, u64>,
+}
+
+impl FalsePositiveAggregator {
+    /// Records a false positive of the bucket.
+    pub fn record(&mut self, bucket: &Bucket) {
+        match self.counts.get_mut(bucket) {
+            Some(cnt) => *cnt = cnt.saturating_add(1),
+            None => {
+                self.counts.insert(bucket.clone(), 1);
+            }
+        }
+    }
+
+    /// Gets the number of false positives for each bucket.
+    pub fn report(&self) -> Vec<(Bucket, u64)> {
+        self.counts
+            .iter()
+            .map(|(b, cnt)| (b.clone(), *cnt))
+            .collect()
+    }
+
+    /// Clears the counts(e.g, after logging a summary).
+    pub fn clear(&mut self) {
+        self.counts.clear()
+    }
+}
+
+/// Scans only the buckets which the bloom filter claims are missing.
+///
+/// This is the inverse of [`get_or_skip_if_missing`] for reconciliation jobs;
+/// any rows found indicate a bug of the bloom filter.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The candidate buckets.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+///
+/// # Returns
+/// The buckets which have rows despite the `Missing` verdict, with the rows.
+pub fn scan_claimed_missing<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<(Bucket, Vec<T>)>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut found: Vec<(Bucket, Vec<T>)> = vec![];
+    for b in buckets {
+        if BloomResult::Missing == bloom(b, filter) {
+            let rows: Vec<T> = getter(shared_db, b, filter)?;
+            if !rows.is_empty() {
+                found.push((b.clone(), rows));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// A scalable bloom filter which chains larger layers as items are added.
+///
+/// Items are added to the small layer(`N` words) until `capacity` items,
+/// then to chained large layers(`M` words) which hold `capacity * M / N` items each.
+/// An item may exist if any layer may contain it.
+pub struct AdaptiveBloom<const N: usize, const M: usize> {
+    small: BloomBits<N>,
+    large: Vec<BloomBits<M>>,
+    hashes: u8,
+    capacity: usize,
+    count: usize,
+}
+
+impl<const N: usize, const M: usize> AdaptiveBloom<N, M> {
+    /// Creates an empty filter.
+    ///
+    /// # Arguments
+    /// - hashes: The number of bits to be set for each item.
+    /// - capacity: The number of items of the small layer.
+    pub fn new(hashes: u8, capacity: usize) -> Self {
+        Self {
+            small: BloomBits::default(),
+            large: vec![],
+            hashes,
+            capacity,
+            count: 0,
+        }
+    }
+
+    fn large_capacity(&self) -> usize {
+        (self.capacity * M / N).max(1)
+    }
+
+    /// Gets the number of layers(including the small layer).
+    pub fn layers(&self) -> usize {
+        1 + self.large.len()
+    }
+
+    /// Gets the number of added items.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Checks if no item has been added.
+    pub fn is_empty(&self) -> bool {
+        0 == self.count
+    }
+
+    /// Adds the item to the current layer; chains a new layer if the current layer is full.
+    pub fn add<I>(&mut self, item: &I)
+    where
+        I: Hash + ?Sized,
+    {
+        match self.count < self.capacity {
+            true => {
+                let computed: BloomBits<N> = BloomBits::from_hash(item, self.hashes);
+                self.small = self.small.or(&computed);
+            }
+            false => {
+                let added: usize = self.count - self.capacity;
+                if added.is_multiple_of(self.large_capacity()) {
+                    self.large.push(BloomBits::default());
+                }
+                let computed: BloomBits<M> = BloomBits::from_hash(item, self.hashes);
+                let last: usize = self.large.len() - 1;
+                self.large[last] = self.large[last].or(&computed);
+            }
+        }
+        self.count += 1;
+    }
+
+    /// Checks if the item may exist in any layer.
+    pub fn may_contain<I>(&self, item: &I) -> BloomResult
+    where
+        I: Hash + ?Sized,
+    {
+        let small: BloomBits<N> = BloomBits::from_hash(item, self.hashes);
+        let large: BloomBits<M> = BloomBits::from_hash(item, self.hashes);
+        let in_small: bool = BloomResult::MayExist == self.small.contains(&small);
+        let in_large = || {
+            self.large
+                .iter()
+                .any(|l| BloomResult::MayExist == l.contains(&large))
+        };
+        match in_small || in_large() {
+            true => BloomResult::MayExist,
+            false => BloomResult::Missing,
+        }
+    }
+}
+
+/// An object safe version of [`Hash`] to hash heterogeneous filter fields.
+pub trait DynHash {
+    /// Feeds this value into the hasher.
+    fn dyn_hash(&self, state: &mut dyn Hasher);
+}
+
+impl<T> DynHash for T
+where
+    T: Hash + ?Sized,
+{
+    fn dyn_hash(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+/// Computes bloom bits of a multi-field filter(e.g, `item_id`, `weight`, `tag`).
+///
+/// All fields are folded into a single hash, so the order of fields matters:
+/// `[item_id, weight]` and `[weight, item_id]` produce different bits.
+/// Use the same order for building and checking filters.
+///
+/// # Arguments
+/// - fields: The field values of a filter.
+/// - hashes: The number of bits to be set.
+pub fn composite_hash(fields: &[&dyn DynHash], hashes: u8) -> BloomBits256 {
+    let mut bits: BloomBits256 = BloomBits::default();
+    for seed in 0..hashes {
+        let mut h = StableHasher::new();
+        seed.hash(&mut h);
+        for f in fields {
+            f.dyn_hash(&mut h);
+        }
+        bits.set_bit(stable_index(h.finish(), bits.width()));
+    }
+    bits
+}
+
+fn accumulate<I, H>(items: &[I], hash: &H) -> BloomBits256
+where
+    H: Fn(&I) -> BloomBits256,
+{
+    items
+        .iter()
+        .fold(BloomBits::default(), |acc: BloomBits256, item| {
+            acc.or(&hash(item))
+        })
+}
+
+fn merge_into(map: &mut BTreeMap<Bucket, BloomBits256>, b: Bucket, bits: BloomBits256) {
+    let merged: BloomBits256 = map.get(&b).map(|prev| prev.or(&bits)).unwrap_or(bits);
+    map.insert(b, merged);
+}
+
+/// Builds bloom bits for each bucket by OR-ing the bits of its items.
+///
+/// Items of the same bucket which appear more than once are merged.
+pub fn build_map<I, H>(
+    items_by_bucket: Vec<(Bucket, Vec<I>)>,
+    hash: &H,
+) -> BTreeMap<Bucket, BloomBits256>
+where
+    H: Fn(&I) -> BloomBits256,
+{
+    let mut map: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+    for (b, items) in items_by_bucket {
+        let bits: BloomBits256 = accumulate(&items, hash);
+        merge_into(&mut map, b, bits);
+    }
+    map
+}
+
+/// Builds bloom bits for each bucket like [`build_map`] using all available cores.
+///
+/// Buckets are split into chunks and hashed on scoped threads.
+pub fn build_map_parallel<I, H>(
+    items_by_bucket: Vec<(Bucket, Vec<I>)>,
+    hash: &H,
+) -> BTreeMap<Bucket, BloomBits256>
+where
+    I: Sync,
+    H: Fn(&I) -> BloomBits256 + Sync,
+{
+    let threads: usize = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let chunk_size: usize = items_by_bucket.len().div_ceil(threads).max(1);
+    let computed: Vec<Vec<(Bucket, BloomBits256)>> = thread::scope(|s| {
+        let handles: Vec<_> = items_by_bucket
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(b, items)| (b.clone(), accumulate(items, hash)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    });
+    let mut map: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+    for (b, bits) in computed.into_iter().flatten() {
+        merge_into(&mut map, b, bits);
+    }
+    map
+}
+
+/// Gets the fill ratio(set bits / 256) of bloom bits for each bucket.
+///
+/// The false positive rate grows as the ratio approaches 1.
+pub fn occupancy(bloom_bits: &BTreeMap<Bucket, BloomBits256>) -> Vec<(Bucket, f64)> {
+    bloom_bits
+        .iter()
+        .map(|(b, bits)| {
+            let ratio: f64 = f64::from(bits.count_ones()) / (bits.width() as f64);
+            (b.clone(), ratio)
+        })
+        .collect()
+}
+
+/// Gets the buckets whose fill ratio(see [`occupancy`]) is greater than or equal to the threshold.
+pub fn saturated_buckets(
+    bloom_bits: &BTreeMap<Bucket, BloomBits256>,
+    threshold: f64,
+) -> Vec<Bucket> {
+    occupancy(bloom_bits)
+        .into_iter()
+        .filter(|(_, ratio)| threshold <= *ratio)
+        .map(|(b, _)| b)
+        .collect()
+}
+
+/// Computes the union(bitwise OR) of the bloom bits of the selected buckets.
+///
+/// An item missing from the union is missing from every selected bucket.
+/// Buckets without bloom bits are ignored.
+///
+/// # Arguments
+/// - bloom_bits: The bloom bits for each bucket.
+/// - buckets: The buckets to be merged.
+pub fn union_of<'a>(
+    bloom_bits: &BTreeMap<Bucket, BloomBits256>,
+    buckets: impl IntoIterator<Item = &'a Bucket>,
+) -> BloomBits256 {
+    buckets
+        .into_iter()
+        .filter_map(|b| bloom_bits.get(b))
+        .fold(BloomBits256::default(), |union, bits| union.or(bits))
+}
+
+/// A bloom filter which checks if any key with a prefix may exist.
+///
+/// Every prefix(up to `max_len` chars) of a key is added on insert,
+/// so a key of `L` chars sets up to `min(L, max_len) * hashes` bits.
+/// Keep `max_len` small or use more words(`N`) to limit the false positive rate.
+pub struct PrefixBloom<const N: usize> {
+    bits: BloomBits<N>,
+    hashes: u8,
+    max_len: usize,
+}
+
+impl<const N: usize> PrefixBloom<N> {
+    /// Creates an empty filter.
+    ///
+    /// # Arguments
+    /// - hashes: The number of bits to be set for each prefix.
+    /// - max_len: The max number of chars of the prefixes to be indexed.
+    pub fn new(hashes: u8, max_len: usize) -> Self {
+        Self {
+            bits: BloomBits::default(),
+            hashes,
+            max_len,
+        }
+    }
+
+    /// Gets the bloom bits of all indexed prefixes.
+    pub fn bits(&self) -> &BloomBits<N> {
+        &self.bits
+    }
+
+    fn prefix(s: &str, max_len: usize) -> &str {
+        match s.char_indices().nth(max_len) {
+            None => s,
+            Some((end, _)) => &s[..end],
+        }
+    }
+
+    /// Adds the key and its prefixes.
+    pub fn insert(&mut self, key: &str) {
+        let indexed: &str = Self::prefix(key, self.max_len);
+        let ends = indexed
+            .char_indices()
+            .skip(1)
+            .map(|(ix, _)| ix)
+            .chain(std::iter::once(indexed.len()))
+            .filter(|end| 0 < *end);
+        for end in ends {
+            let computed: BloomBits<N> = BloomBits::from_hash(&indexed[..end], self.hashes);
+            self.bits = self.bits.or(&computed);
+        }
+    }
+
+    /// Checks if any key with the prefix may exist.
+    ///
+    /// A prefix longer than `max_len` chars is checked by its first `max_len` chars.
+    pub fn may_have_prefix(&self, prefix: &str) -> BloomResult {
+        let indexed: &str = Self::prefix(prefix, self.max_len);
+        match indexed.is_empty() {
+            true => BloomResult::MayExist,
+            false => self
+                .bits
+                .contains(&BloomBits::from_hash(indexed, self.hashes)),
+        }
+    }
+}
+
+/// Bloom bits whose width is selected at runtime.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DynBloom {
+    /// 128 bits.
+    Bits128([u128; 1]),
+
+    /// 256 bits.
+    Bits256([u128; 2]),
+
+    /// 512 bits.
+    Bits512([u128; 4]),
+}
+
+impl DynBloom {
+    /// Gets the number of bits.
+    pub fn width(&self) -> usize {
+        match self {
+            Self::Bits128(_) => 128,
+            Self::Bits256(_) => 256,
+            Self::Bits512(_) => 512,
+        }
+    }
+
+    fn words_mut(&mut self) -> &mut [u128] {
+        match self {
+            Self::Bits128(w) => w,
+            Self::Bits256(w) => w,
+            Self::Bits512(w) => w,
+        }
+    }
+
+    /// Sets the bit(the index wraps around the width).
+    pub fn set_bit(&mut self, ix: usize) {
+        let wrapped: usize = ix % self.width();
+        self.words_mut()[wrapped / 128] |= 1 << (wrapped % 128);
+    }
+
+    /// Computes the bitwise AND; an error if the widths differ.
+    pub fn and(&self, other: &Self) -> Result<Self, Event> {
+        match (self, other) {
+            (Self::Bits128(a), Self::Bits128(b)) => Ok(Self::Bits128(
+                *BloomBits::new(*a).and(&BloomBits::new(*b)).packed(),
+            )),
+            (Self::Bits256(a), Self::Bits256(b)) => Ok(Self::Bits256(
+                *BloomBits::new(*a).and(&BloomBits::new(*b)).packed(),
+            )),
+            (Self::Bits512(a), Self::Bits512(b)) => Ok(Self::Bits512(
+                *BloomBits::new(*a).and(&BloomBits::new(*b)).packed(),
+            )),
+            _ => Err(Event::UnexpectedError(format!(
+                "Bloom width mismatch. left={}, right={}",
+                self.width(),
+                other.width(),
+            ))),
+        }
+    }
+
+    /// Checks if all bits of other are set(see [`BloomBits::contains`]).
+    ///
+    /// Returns [`BloomResult::Missing`] if the widths differ.
+    pub fn contains(&self, other: &Self) -> BloomResult {
+        match (self, other) {
+            (Self::Bits128(a), Self::Bits128(b)) => {
+                BloomBits::new(*a).contains(&BloomBits::new(*b))
+            }
+            (Self::Bits256(a), Self::Bits256(b)) => {
+                BloomBits::new(*a).contains(&BloomBits::new(*b))
+            }
+            (Self::Bits512(a), Self::Bits512(b)) => {
+                BloomBits::new(*a).contains(&BloomBits::new(*b))
+            }
+            _ => BloomResult::Missing,
+        }
+    }
+}
+
+/// Counts the bloom check results of the buckets(e.g, to monitor how many buckets are pruned).
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - buckets: The candidate buckets.
+/// - filter: The filter to check values.
+///
+/// # Returns
+/// The number of buckets which may contain values and the number of buckets without values.
+pub fn verdict_counts<B, F>(bloom: &B, buckets: &[Bucket], filter: &F) -> (u64, u64)
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+{
+    buckets
+        .iter()
+        .fold((0, 0), |(may_exist, missing), b| match bloom(b, filter) {
+            BloomResult::MayExist => (may_exist + 1, missing),
+            BloomResult::Missing => (may_exist, missing + 1),
+        })
+}
+
+/// Checks if a record may be written to the bucket(e.g, partition routing).
+///
+/// Mirrors [`get_or_skip_if_missing`] for writes; skip the write if false.
+///
+/// # Arguments
+/// - bloom: Checks if the bucket may accept the record.
+/// - bucket: The target bucket.
+/// - record_filter: The routing criteria of the record.
+pub fn should_write<B, F>(bloom: &B, bucket: &Bucket, record_filter: &F) -> bool
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+{
+    match bloom(bucket, record_filter) {
+        BloomResult::MayExist => true,
+        BloomResult::Missing => false,
+    }
+}
+
+type DatePartition = (BloomBits256, BTreeMap<Bucket, BloomBits256>);
+
+/// Bloom bits grouped by date to skip whole dates before checking buckets.
+///
+/// The bloom bits of a date are the union of the bloom bits of its buckets.
+#[derive(Default)]
+pub struct HierarchicalBloom {
+    dates: BTreeMap<DatePrefix, DatePartition>,
+}
+
+impl HierarchicalBloom {
+    /// Adds(merges) the bloom bits of the bucket; an error if the bucket has no date.
+    pub fn insert(&mut self, bucket: Bucket, bits: BloomBits256) -> Result<(), Event> {
+        let date: DatePrefix = bucket.date_part().ok_or_else(|| {
+            Event::UnexpectedError(format!("No date in the bucket: {}", bucket.as_str()))
+        })?;
+        let (union, buckets) = self.dates.entry(date).or_default();
+        *union = union.or(&bits);
+        let merged: &mut BloomBits256 = buckets.entry(bucket).or_default();
+        *merged = merged.or(&bits);
+        Ok(())
+    }
+
+    /// Gets the bloom bits of the date(the union of its buckets).
+    pub fn date_bits(&self, date: &DatePrefix) -> Option<&BloomBits256> {
+        self.dates.get(date).map(|(union, _)| union)
+    }
+
+    /// Checks the date first and then the bucket(see [`HierarchicalBloom::check_with`]).
+    pub fn check(&self, bucket: &Bucket, computed: &BloomBits256) -> BloomResult {
+        self.check_with(bucket, computed, &|stored, computed| {
+            stored.contains(computed)
+        })
+    }
+
+    /// Checks the date first and then the bucket.
+    ///
+    /// The bucket is not checked if the date is missing.
+    /// Buckets without a date or without bloom bits are missing.
+    ///
+    /// # Arguments
+    /// - bucket: The bucket which may contain values.
+    /// - computed: The bloom bits of the filter.
+    /// - check: Checks if values may exists or not.
+    pub fn check_with<C>(&self, bucket: &Bucket, computed: &BloomBits256, check: &C) -> BloomResult
+    where
+        C: Fn(&BloomBits256, &BloomBits256) -> BloomResult,
+    {
+        let partition: Option<&DatePartition> =
+            bucket.date_part().and_then(|date| self.dates.get(&date));
+        let (union, buckets) = match partition {
+            None => return BloomResult::Missing,
+            Some(found) => found,
+        };
+        match check(union, computed) {
+            BloomResult::Missing => BloomResult::Missing,
+            BloomResult::MayExist => buckets
+                .get(bucket)
+                .map(|stored| check(stored, computed))
+                .unwrap_or(BloomResult::Missing),
+        }
+    }
+}
+
+/// Estimates the number of distinct items(HyperLogLog).
+///
+/// Keep an estimator for each bucket alongside the bloom bits(e.g, to estimate pushdown costs).
+/// Uses `2^precision` registers; the standard error is about `1.04 / sqrt(2^precision)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CardinalityEstimator {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl CardinalityEstimator {
+    /// Creates an empty estimator; the precision is clamped to `4..=16`.
+    pub fn new(precision: u8) -> Self {
+        let precision: u8 = precision.clamp(4, 16);
+        Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Adds the item.
+    pub fn add<I>(&mut self, item: &I)
+    where
+        I: Hash + ?Sized,
+    {
+        let mut h = StableHasher::new();
+        item.hash(&mut h);
+        let hashed: u64 = h.finish();
+        let ix: usize = (hashed >> (64 - self.precision)) as usize;
+        let rest: u64 = hashed << self.precision;
+        let max_rank: u8 = 64 - self.precision + 1;
+        let rank: u8 = (rest.leading_zeros() as u8 + 1).min(max_rank);
+        self.registers[ix] = self.registers[ix].max(rank);
+    }
+
+    /// Estimates the number of distinct added items.
+    pub fn estimate(&self) -> u64 {
+        let m: f64 = self.registers.len() as f64;
+        let alpha: f64 = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-i32::from(*r)))
+            .sum();
+        let raw: f64 = alpha * m * m / sum;
+        let zeros: usize = self.registers.iter().filter(|r| 0 == **r).count();
+        let small: bool = raw <= 2.5 * m && 0 < zeros;
+        let estimated: f64 = match small {
+            true => m * (m / zeros as f64).ln(),
+            false => raw,
+        };
+        estimated.round() as u64
+    }
+}
+
+/// Bloom bits container which counts checks of each bucket(e.g, to prune unused buckets).
+pub struct TrackedBloomMap<B> {
+    bloom_bits: BTreeMap<Bucket, (B, AtomicU64)>,
+}
+
+impl<B> TrackedBloomMap<B> {
+    /// Creates a container of the bloom bits; no bucket has been checked.
+    pub fn new(bloom_bits: BTreeMap<Bucket, B>) -> Self {
+        Self {
+            bloom_bits: bloom_bits
+                .into_iter()
+                .map(|(b, bits)| (b, (bits, AtomicU64::new(0))))
+                .collect(),
+        }
+    }
+
+    /// Checks if values may exists or not(see [`bloom_check`]) and counts the check.
+    ///
+    /// # Arguments
+    /// - hash: Computes the hash to be compared.
+    /// - filter: The filter to compute a hash.
+    /// - check: Checks if values may exists or not.
+    /// - b: The bucket which may contain values.
+    pub fn check<H, F, C>(&self, hash: &H, filter: &F, check: &C, b: &Bucket) -> BloomResult
+    where
+        H: Fn(&F) -> B,
+        C: Fn(&B, &B) -> BloomResult,
+    {
+        match self.bloom_bits.get(b) {
+            None => BloomResult::Missing,
+            Some((found, checks)) => {
+                checks.fetch_add(1, Ordering::Relaxed);
+                check(found, &hash(filter))
+            }
+        }
+    }
+
+    /// Gets the number of checks of the bucket.
+    pub fn checks(&self, b: &Bucket) -> u64 {
+        self.bloom_bits
+            .get(b)
+            .map(|(_, checks)| checks.load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+
+    /// Gets the buckets which have never been checked.
+    pub fn never_checked(&self) -> Vec<Bucket> {
+        self.bloom_bits
+            .iter()
+            .filter(|(_, (_, checks))| 0 == checks.load(Ordering::Relaxed))
+            .map(|(b, _)| b.clone())
+            .collect()
+    }
+}
+
+/// Marks the bucket as deleted by replacing its bloom bits with empty(all-zero) bloom bits.
+///
+/// Any item(non-empty computed bloom bits) is [`BloomResult::Missing`] for the tombstone;
+/// use [`purge_tombstones`] to remove tombstones later.
+pub fn mark_deleted(bloom_bits: &mut BTreeMap<Bucket, BloomBits256>, bucket: &Bucket) {
+    bloom_bits.insert(bucket.clone(), BloomBits256::default());
+}
+
+/// Removes the tombstones(see [`mark_deleted`]); returns the number of removed buckets.
+pub fn purge_tombstones(bloom_bits: &mut BTreeMap<Bucket, BloomBits256>) -> u64 {
+    let before: usize = bloom_bits.len();
+    bloom_bits.retain(|_, bits| !bits.is_empty());
+    (before - bloom_bits.len()) as u64
+}
+
+fn estimated_entry_bytes(bucket: &Bucket) -> usize {
+    bucket.as_str().len() + std::mem::size_of::<BloomBits256>() + std::mem::size_of::<Bucket>()
+}
+
+/// Estimates the memory footprint of the bloom map.
+///
+/// Each entry counts the name bytes, 32 bytes of bloom bits and the size of the bucket itself.
+pub fn estimated_bytes(bloom_bits: &BTreeMap<Bucket, BloomBits256>) -> usize {
+    bloom_bits.keys().map(estimated_entry_bytes).sum()
+}
+
+/// Drops the lowest priority buckets until [`estimated_bytes`] fits `max_bytes`.
+///
+/// Buckets with the same priority are dropped in bucket order; returns the dropped buckets.
+///
+/// # Arguments
+/// - bloom_bits: The bloom map to shrink.
+/// - max_bytes: The maximum estimated bytes.
+/// - priority: Computes the priority of the bucket(higher is kept longer).
+pub fn evict_to_fit<P>(
+    bloom_bits: &mut BTreeMap<Bucket, BloomBits256>,
+    max_bytes: usize,
+    priority: &P,
+) -> Vec<Bucket>
+where
+    P: Fn(&Bucket) -> i32,
+{
+    let mut total: usize = estimated_bytes(bloom_bits);
+    let mut ordered: Vec<(i32, Bucket)> = bloom_bits
+        .keys()
+        .map(|b| (priority(b), b.clone()))
+        .collect();
+    ordered.sort();
+    let mut dropped: Vec<Bucket> = vec![];
+    for (_, b) in ordered {
+        match total <= max_bytes {
+            true => break,
+            false => {
+                total -= estimated_entry_bytes(&b);
+                bloom_bits.remove(&b);
+                dropped.push(b);
+            }
+        }
+    }
+    dropped
+}
+
+/// Computes bloom bits of a multi-field filter setting a different number of bits per field.
+///
+/// Each field is hashed on its own(salted by its position) and sets `k` bits, so a filter can
+/// be checked by any subset of its fields. Choosing `k` per field:
+/// - Give more bits to discriminating fields(e.g, `item_id`): they are what makes a bucket
+///   `Missing`, and more bits lower their false positive rate.
+/// - Give few bits(or 0 to ignore) to low cardinality fields(e.g, `tag`, `quantity`): their
+///   values appear in almost every bucket, so their bits only saturate the filter.
+/// - Keep the sum of `k` small relative to 256 times the number of items per bucket.
+///
+/// # Arguments
+/// - fields: The field values of a filter and the number of bits for each field.
+pub fn weighted_composite(fields: &[(&dyn DynHash, u8)]) -> BloomBits256 {
+    let mut bits: BloomBits256 = BloomBits::default();
+    for (ix, (field, k)) in fields.iter().enumerate() {
+        for seed in 0..*k {
+            let mut h = StableHasher::new();
+            (ix as u32).hash(&mut h);
+            seed.hash(&mut h);
+            field.dyn_hash(&mut h);
+            bits.set_bit(stable_index(h.finish(), bits.width()));
+        }
+    }
+    bits
+}
+
+/// Computes the fraction of buckets whose bloom bits equal the bits of at least one other bucket.
+///
+/// Returns 0.0 for an empty map.
+pub fn collision_rate(bloom_bits: &BTreeMap<Bucket, BloomBits256>) -> f64 {
+    let mut counts: BTreeMap<[u128; 2], u64> = BTreeMap::new();
+    for bits in bloom_bits.values() {
+        *counts.entry(bits.packed).or_default() += 1;
+    }
+    let colliding: u64 = counts.values().filter(|c| 1 < **c).sum();
+    match bloom_bits.is_empty() {
+        true => 0.0,
+        false => colliding as f64 / bloom_bits.len() as f64,
+    }
+}
+
+/// Fails if the [`collision_rate`] of the bloom map is not below the threshold.
+///
+/// # Arguments
+/// - bloom_bits: The bloom map to be validated.
+/// - threshold: The acceptable collision rate(0.0 - 1.0).
+pub fn assert_collision_rate_below(
+    bloom_bits: &BTreeMap<Bucket, BloomBits256>,
+    threshold: f64,
+) -> Result<(), Event> {
+    let rate: f64 = collision_rate(bloom_bits);
+    match rate < threshold {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError(format!(
+            "Too many bloom collisions: rate={} threshold={}",
+            rate, threshold
+        ))),
+    }
+}
+
+/// Caches bloom check results of (bucket, filter hash) pairs(e.g, within a single request).
+#[derive(Default)]
+pub struct ResultCache {
+    results: BTreeMap<(Bucket, u64), BloomResult>,
+}
+
+impl ResultCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the cached result or computes and caches it.
+    ///
+    /// # Arguments
+    /// - bucket: The checked bucket.
+    /// - filter_hash: The hash of the checked filter.
+    /// - compute: Checks if values may exists or not(called only on a cache miss).
+    pub fn get_or_compute<C>(
+        &mut self,
+        bucket: &Bucket,
+        filter_hash: u64,
+        compute: C,
+    ) -> BloomResult
+    where
+        C: FnOnce() -> BloomResult,
+    {
+        *self
+            .results
+            .entry((bucket.clone(), filter_hash))
+            .or_insert_with(compute)
+    }
+
+    /// Gets the number of cached results.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Checks if no result is cached.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+}
+
+/// A change of a bloom map(e.g, from a change feed).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BloomDelta {
+    /// Inserts or replaces the bloom bits of the bucket.
+    Upsert(Bucket, BloomBits256),
+
+    /// Removes the bloom bits of the bucket.
+    Delete(Bucket),
+}
+
+/// Applies a change to the bloom map.
+pub fn apply_delta(bloom_bits: &mut BTreeMap<Bucket, BloomBits256>, delta: BloomDelta) {
+    match delta {
+        BloomDelta::Upsert(b, bits) => {
+            bloom_bits.insert(b, bits);
+        }
+        BloomDelta::Delete(b) => {
+            bloom_bits.remove(&b);
+        }
+    }
+}
+
+/// Applies changes to the bloom map in order; returns the number of applied changes.
+pub fn apply_deltas<I>(bloom_bits: &mut BTreeMap<Bucket, BloomBits256>, deltas: I) -> u64
+where
+    I: IntoIterator<Item = BloomDelta>,
+{
+    deltas.into_iter().fold(0, |applied, delta| {
+        apply_delta(bloom_bits, delta);
+        applied + 1
+    })
+}
+
+#[cfg(test)]
+mod test_bloom {
+
+    mod bloom_bits {
+
+        use crate::bloom::{BloomBits256, BloomResult};
+
+        #[test]
+        fn test_contains() {
+            let stored = BloomBits256::new([0x333 | 0x599, 0x634 | 0x3776]);
+            let computed = BloomBits256::new([0x599, 0x3776]);
+            assert!(matches!(stored.contains(&computed), BloomResult::MayExist));
+
+            let computed = BloomBits256::new([0x599, 0x3777]);
+            assert!(matches!(stored.contains(&computed), BloomResult::Missing));
+        }
+
+        #[test]
+        fn test_clear() {
+            let mut bits = BloomBits256::new([0x333, 0x634]);
+            assert!(!bits.is_empty());
+            bits.clear();
+            assert!(bits.is_empty());
+            assert_eq!(bits, BloomBits256::default());
+            let filters = [
+                BloomBits256::new([0x333, 0x634]),
+                BloomBits256::new([1, 0]),
+                BloomBits256::new([0, 1 << 127]),
+            ];
+            for f in filters {
+                assert!(matches!(bits.contains(&f), BloomResult::Missing));
+            }
+        }
+
+        #[test]
+        fn test_similar() {
+            let golden = BloomBits256::new([0x333, 0x634]);
+            assert!(golden.similar(&golden, 0));
+            let drift = BloomBits256::new([0x331, 0x634 | 1 << 100]);
+            assert!(!golden.similar(&drift, 0));
+            assert!(!golden.similar(&drift, 1));
+            assert!(golden.similar(&drift, 2));
+            assert!(drift.similar(&golden, 3));
+            let far = BloomBits256::new([!0x333, 0x634]);
+            assert!(!golden.similar(&far, 127));
+            assert!(golden.similar(&far, 128));
+        }
+
+        #[test]
+        fn test_bytes() {
+            let bits = BloomBits256::new([0x0123_4567, 0x89ab_cdef << 96]);
+            let bytes: Vec<u8> = bits.to_bytes();
+            assert_eq!(bytes.len(), 32);
+            assert_eq!(bytes[15], 0x67);
+            assert_eq!(bytes[16], 0x89);
+            assert_eq!(BloomBits256::from_bytes(&bytes).unwrap(), bits);
+            assert!(BloomBits256::from_bytes(&bytes[1..]).is_err());
+        }
+    }
+
+    #[cfg(feature = "base64")]
+    mod base64 {
+
+        use crate::bloom::{BloomBits, BloomBits256};
+        use crate::evt::Event;
+
+        #[test]
+        fn test_round_trip() {
+            let bits = BloomBits256::new([0x333 | 0x599, u128::MAX - 0x634]);
+            let encoded: String = bits.to_base64();
+            assert_eq!(encoded.len(), 44);
+            assert_eq!(BloomBits256::from_base64(&encoded).unwrap(), bits);
+
+            let zero = BloomBits256::default();
+            assert_eq!(
+                zero.to_base64(),
+                "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            );
+            assert_eq!(BloomBits256::from_base64(&zero.to_base64()).unwrap(), zero);
+        }
+
+        #[test]
+        fn test_round_trip_one_word() {
+            let bits = BloomBits::new([0xcafe_f00d_dead_beaf]);
+            let encoded: String = bits.to_base64();
+            assert_eq!(encoded, "AAAAAAAAAADK/vAN3q2+rw==");
+            assert_eq!(BloomBits::<1>::from_base64(&encoded).unwrap(), bits);
+        }
+
+        #[test]
+        fn test_invalid() {
+            let invalid: Vec<&str> = vec![
+                "not base64!",
+                "AAAA=AAA",
+                "AAAAAAAAAADK/vAN3q2+rw=",
+                "AAAAAAAAAADK/vAN3q2+rw==",
+            ];
+            for s in invalid {
+                match BloomBits256::from_base64(s) {
+                    Err(Event::UnexpectedError(_)) => {}
+                    _ => panic!("must be an error: {}", s),
+                }
+            }
+        }
+    }
+
+    mod versioned_bloom_map {
+
+        use crate::bloom::{BloomBits256, BloomResult, VersionedBloomMap};
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+
+        #[test]
+        fn test_generation() {
+            let mut map: VersionedBloomMap<BloomBits256> = VersionedBloomMap::default();
+            let b: Bucket = Bucket::new_checked("pg_database".into());
+            let bloom_bucket: Bucket = Bucket::new_checked("bloom_2022_12_27".into());
+            let hash = |f: &u128| BloomBits256::new([*f, 0]);
+            let check = |a: &BloomBits256, b: &BloomBits256| a.contains(b);
+
+            let (r, g) = map.check(&hash, &0x333, &check, &b);
+            assert!(matches!(r, BloomResult::Missing));
+            assert_eq!(g, 0);
+
+            let mut bits: u128 = 0x333;
+            let mut getter = |bits: &mut u128, _: &Bucket| -> Result<Vec<_>, Event> {
+                Ok(vec![(
+                    Bucket::new_checked("pg_database".into()),
+                    BloomBits256::new([*bits, 0]),
+                )])
+            };
+            assert_eq!(
+                map.update(&mut bits, &mut getter, &bloom_bucket).unwrap(),
+                1
+            );
+            assert_eq!(map.generation(), 1);
+            let (r, g) = map.check(&hash, &0x333, &check, &b);
+            assert!(matches!(r, BloomResult::MayExist));
+            assert_eq!(g, 1);
+
+            bits = 0x599;
+            map.update(&mut bits, &mut getter, &bloom_bucket).unwrap();
+            let (r, g) = map.check(&hash, &0x333, &check, &b);
+            assert!(matches!(r, BloomResult::Missing));
+            assert_eq!(g, 2);
+            assert_eq!(map.generation(), 2);
+
+            let mut failing = |_: &mut u128, _: &Bucket| -> Result<Vec<_>, Event> {
+                Err(Event::UnableToConnect("down".into()))
+            };
+            assert!(map.update(&mut bits, &mut failing, &bloom_bucket).is_err());
+            assert_eq!(map.generation(), 3);
+            assert!(map.bloom_bits().is_empty());
+        }
+    }
+
+    mod check_and_locate {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{check_and_locate, BloomBits256, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_locate() {
+            let mut map: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            map.insert(
+                Bucket::new_checked("pg_database".into()),
+                BloomBits256::new([0x333, 0x634]),
+            );
+            let hash = |f: &u128| BloomBits256::new([*f, 0]);
+            let check = |a: &BloomBits256, b: &BloomBits256| a.contains(b);
+
+            let b: Bucket = Bucket::new_checked("pg_database".into());
+            let (r, found) = check_and_locate(&map, &b, &hash, &0x333, &check);
+            assert!(matches!(r, BloomResult::MayExist));
+            assert!(std::ptr::eq(found.unwrap(), map.get(&b).unwrap()));
+
+            let (r, found) = check_and_locate(&map, &b, &hash, &0x400, &check);
+            assert!(matches!(r, BloomResult::Missing));
+            assert_eq!(found, Some(&BloomBits256::new([0x333, 0x634])));
+
+            let absent: Bucket = Bucket::new_checked("pg_class".into());
+            let (r, found) = check_and_locate(&map, &absent, &hash, &0x333, &check);
+            assert!(matches!(r, BloomResult::Missing));
+            assert_eq!(found, None);
+        }
+    }
+
+    mod refresh_once {
+
+        use std::collections::BTreeMap;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::{Barrier, RwLock};
+        use std::thread;
+
+        use crate::bloom::{refresh_once, BloomBits256, RefreshResult};
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+
+        #[test]
+        fn test_race() {
+            let flag = AtomicBool::new(false);
+            let bloom_bits: RwLock<BTreeMap<Bucket, BloomBits256>> = RwLock::new(BTreeMap::new());
+            let calls = AtomicUsize::new(0);
+            let barrier = Barrier::new(2);
+            let bloom_bucket: Bucket = Bucket::new_checked("bloom_2022_12_27".into());
+
+            let results: Vec<RefreshResult> = thread::scope(|s| {
+                let handles: Vec<_> = (0..2)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let mut dummy: u8 = 0;
+                            let mut getter = |_: &mut u8, _: &Bucket| -> Result<Vec<_>, Event> {
+                                calls.fetch_add(1, Ordering::SeqCst);
+                                barrier.wait();
+                                Ok(vec![(
+                                    Bucket::new_checked("pg_database".into()),
+                                    BloomBits256::new([0x333, 0x634]),
+                                )])
+                            };
+                            let r = refresh_once(
+                                &flag,
+                                &bloom_bits,
+                                &mut dummy,
+                                &mut getter,
+                                &bloom_bucket,
+                            )
+                            .unwrap();
+                            if r == RefreshResult::InProgress {
+                                barrier.wait();
+                            }
+                            r
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert!(results.contains(&RefreshResult::Refreshed(1)));
+            assert!(results.contains(&RefreshResult::InProgress));
+            assert_eq!(bloom_bits.read().unwrap().len(), 1);
+            assert!(!flag.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn test_error_releases_flag() {
+            let flag = AtomicBool::new(false);
+            let bloom_bits: RwLock<BTreeMap<Bucket, BloomBits256>> = RwLock::new(BTreeMap::new());
+            let mut dummy: u8 = 0;
+            let r = refresh_once(
+                &flag,
+                &bloom_bits,
+                &mut dummy,
+                &mut |_: &mut u8, _: &Bucket| -> Result<Vec<(Bucket, BloomBits256)>, Event> {
+                    Err(Event::UnableToConnect("down".into()))
+                },
+                &Bucket::new_checked("bloom_2022_12_27".into()),
+            );
+            assert!(r.is_err());
+            assert!(!flag.load(Ordering::SeqCst));
+        }
+    }
+
+    mod check_many_buckets {
+
+        use std::cell::Cell;
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{check_many_buckets, BloomBits256, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_hash_once() {
+            let mut map: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            map.insert(
+                Bucket::new_checked("b1".into()),
+                BloomBits256::new([0x333, 0]),
+            );
+            map.insert(
+                Bucket::new_checked("b2".into()),
+                BloomBits256::new([0x599, 0]),
+            );
+            let cnt: Cell<u8> = Cell::new(0);
+            let hash = |f: &u128| {
+                cnt.set(cnt.get() + 1);
+                BloomBits256::new([*f, 0])
+            };
+            let check = |a: &BloomBits256, b: &BloomBits256| a.contains(b);
+            let buckets: Vec<Bucket> = vec![
+                Bucket::new_checked("b1".into()),
+                Bucket::new_checked("b2".into()),
+                Bucket::new_checked("b3".into()),
+            ];
+
+            let results: Vec<(Bucket, BloomResult)> =
+                check_many_buckets(&map, &hash, &0x333, &check, &buckets);
+            assert_eq!(cnt.get(), 1);
+            assert_eq!(results.len(), 3);
+            assert_eq!(results[0].0.as_str(), "b1");
+            assert!(matches!(results[0].1, BloomResult::MayExist));
+            assert!(matches!(results[1].1, BloomResult::Missing));
+            assert!(matches!(results[2].1, BloomResult::Missing));
+        }
+    }
+
+    mod false_positive_aggregator {
+
+        use crate::bloom::FalsePositiveAggregator;
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_aggregate() {
+            let mut agg = FalsePositiveAggregator::default();
+            let db: Bucket = Bucket::new_checked("pg_database".into());
+            let class: Bucket = Bucket::new_checked("pg_class".into());
+            agg.record(&db);
+            agg.record(&db);
+            agg.record(&class);
+            agg.record(&db);
+            assert_eq!(agg.report(), vec![(class, 1), (db, 3)]);
+
+            agg.clear();
+            assert_eq!(agg.report(), vec![]);
+        }
+    }
+
+    mod scan_claimed_missing {
+
+        use crate::bloom::{scan_claimed_missing, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_surfaced() {
+            let buckets: Vec<Bucket> = ["b1", "b2", "b3"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "b1" => BloomResult::MayExist,
+                _ => BloomResult::Missing,
+            };
+            let mut scanned: Vec<String> = vec![];
+            let found: Vec<(Bucket, Vec<u8>)> = scan_claimed_missing(
+                &bloom,
+                &mut scanned,
+                &buckets,
+                &mut |scanned: &mut Vec<String>, b: &Bucket, _: &()| {
+                    scanned.push(b.as_str().into());
+                    match b.as_str() {
+                        "b2" => Ok(vec![42]),
+                        _ => Ok(vec![]),
+                    }
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(scanned, vec!["b2", "b3"]);
+            assert_eq!(found, vec![(Bucket::new_checked("b2".into()), vec![42])]);
+        }
+    }
+
+    mod adaptive_bloom {
+
+        use crate::bloom::{AdaptiveBloom, BloomResult};
+
+        #[test]
+        fn test_layers() {
+            let mut bloom: AdaptiveBloom<1, 4> = AdaptiveBloom::new(3, 8);
+            assert!(bloom.is_empty());
+            for i in 0..8 {
+                bloom.add(&format!("item_{}", i));
+            }
+            assert_eq!(bloom.layers(), 1);
+
+            bloom.add("item_8");
+            assert_eq!(bloom.layers(), 2);
+            for i in 9..40 {
+                bloom.add(&format!("item_{}", i));
+            }
+            assert_eq!(bloom.layers(), 2);
+            bloom.add("item_40");
+            assert_eq!(bloom.layers(), 3);
+            assert_eq!(bloom.len(), 41);
+
+            for i in 0..41 {
+                let item: String = format!("item_{}", i);
+                assert_eq!(bloom.may_contain(&item), BloomResult::MayExist);
+            }
+        }
+
+        #[test]
+        fn test_missing() {
+            let mut bloom: AdaptiveBloom<2, 8> = AdaptiveBloom::new(3, 2);
+            bloom.add("item_0");
+            bloom.add("item_1");
+            bloom.add("item_2");
+            assert_eq!(bloom.layers(), 2);
+            assert_eq!(bloom.may_contain("item_0"), BloomResult::MayExist);
+            assert_eq!(bloom.may_contain("item_2"), BloomResult::MayExist);
+            assert_eq!(bloom.may_contain("item_999"), BloomResult::Missing);
+        }
+    }
+
+    mod composite_hash {
+
+        use crate::bloom::{composite_hash, BloomBits256, BloomResult};
+
+        #[test]
+        fn test_fields() {
+            let tags: Vec<&str> = vec!["water", "drink", "pet"];
+            let a: BloomBits256 = composite_hash(&[&"4589506252015", &3_u8, &"500g", &tags], 3);
+            let same: BloomBits256 = composite_hash(&[&"4589506252015", &3_u8, &"500g", &tags], 3);
+            let other: BloomBits256 = composite_hash(&[&"4589506252015", &3_u8, &"501g", &tags], 3);
+            assert_eq!(a, same);
+            assert_ne!(a, other);
+            assert!(!a.is_empty());
+            assert!(matches!(a.contains(&same), BloomResult::MayExist));
+        }
+
+        #[test]
+        fn test_order() {
+            let a: BloomBits256 = composite_hash(&[&"4589506252015", &"500g"], 3);
+            let b: BloomBits256 = composite_hash(&[&"500g", &"4589506252015"], 3);
+            assert_ne!(a, b);
+        }
+    }
+
+    mod build_map_parallel {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{build_map, build_map_parallel, BloomBits, BloomBits256};
+        use crate::bucket::Bucket;
+
+        fn items() -> Vec<(Bucket, Vec<String>)> {
+            let mut v: Vec<(Bucket, Vec<String>)> = (0..64)
+                .map(|i| {
+                    let items: Vec<String> = (0..i).map(|j| format!("item_{}_{}", i, j)).collect();
+                    (Bucket::new_checked(format!("bucket_{:02}", i)), items)
+                })
+                .collect();
+            v.push((
+                Bucket::new_checked("bucket_01".into()),
+                vec!["extra".into()],
+            ));
+            v
+        }
+
+        #[test]
+        fn test_same_as_serial() {
+            let hash = |s: &String| BloomBits::from_hash(s.as_str(), 3);
+            let serial: BTreeMap<Bucket, BloomBits256> = build_map(items(), &hash);
+            let parallel: BTreeMap<Bucket, BloomBits256> = build_map_parallel(items(), &hash);
+            assert_eq!(serial.len(), 64);
+            assert_eq!(serial, parallel);
+            assert!(serial[&Bucket::new_checked("bucket_00".into())].is_empty());
+            let merged: BloomBits256 =
+                BloomBits::from_hash("item_1_0", 3).or(&BloomBits::from_hash("extra", 3));
+            assert_eq!(parallel[&Bucket::new_checked("bucket_01".into())], merged);
+        }
+
+        #[test]
+        fn test_empty() {
+            let hash = |s: &String| BloomBits::from_hash(s.as_str(), 3);
+            assert!(build_map_parallel(vec![], &hash).is_empty());
+        }
+    }
+
+    mod occupancy {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{occupancy, saturated_buckets, BloomBits256};
+        use crate::bucket::Bucket;
+
+        fn map() -> BTreeMap<Bucket, BloomBits256> {
+            let mut m: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            m.insert(
+                Bucket::new_checked("b0_empty".into()),
+                BloomBits256::default(),
+            );
+            m.insert(
+                Bucket::new_checked("b1_half".into()),
+                BloomBits256::new([u128::MAX, 0]),
+            );
+            m.insert(
+                Bucket::new_checked("b2_near_full".into()),
+                BloomBits256::new([u128::MAX, u128::MAX >> 2]),
+            );
+            m
+        }
+
+        #[test]
+        fn test_ratio() {
+            let ratios: Vec<(Bucket, f64)> = occupancy(&map());
+            assert_eq!(ratios.len(), 3);
+            assert_eq!(ratios[0].1, 0.0);
+            assert_eq!(ratios[1].1, 0.5);
+            assert_eq!(ratios[2].1, 254.0 / 256.0);
+        }
+
+        #[test]
+        fn test_saturated() {
+            assert_eq!(
+                saturated_buckets(&map(), 0.9),
+                vec![Bucket::new_checked("b2_near_full".into())]
+            );
+            assert_eq!(saturated_buckets(&map(), 0.5).len(), 2);
+            assert_eq!(saturated_buckets(&map(), 0.0).len(), 3);
+        }
+    }
+
+    mod union_of {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{union_of, BloomBits256, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_union() {
+            let b0 = Bucket::new_checked("items_2022_12_27".into());
+            let b1 = Bucket::new_checked("items_2022_12_28".into());
+            let b2 = Bucket::new_checked("items_2022_12_29".into());
+            let item = BloomBits256::from_hash("apple", 3);
+            let mut m: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            m.insert(b0.clone(), BloomBits256::from_hash("banana", 3));
+            m.insert(b1.clone(), item);
+            m.insert(b2.clone(), BloomBits256::from_hash("cherry", 3));
+
+            let union: BloomBits256 = union_of(&m, [&b0, &b1]);
+            assert_eq!(union.contains(&item), BloomResult::MayExist);
+            assert_eq!(union.contains(&m[&b0]), BloomResult::MayExist);
+
+            let none: BloomBits256 = union_of(&m, []);
+            assert!(none.is_empty());
+        }
+    }
+
+    mod bloom_check_with_default {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{bloom_check, bloom_check_with_default, BloomBits256, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_default() {
+            let present = Bucket::new_checked("items_2022_12_27".into());
+            let absent = Bucket::new_checked("items_2022_12_28".into());
+            let mut m: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            m.insert(present.clone(), BloomBits256::from_hash("apple", 3));
+            let hash = |f: &&str| BloomBits256::from_hash(f, 3);
+            let check = |stored: &BloomBits256, computed: &BloomBits256| stored.contains(computed);
+
+            for default in [BloomResult::MayExist, BloomResult::Missing] {
+                let got: BloomResult =
+                    bloom_check_with_default(&m, &hash, &"apple", &check, &absent, default);
+                assert_eq!(got, default);
+                let got: BloomResult =
+                    bloom_check_with_default(&m, &hash, &"apple", &check, &present, default);
+                assert_eq!(got, BloomResult::MayExist);
+            }
+            assert_eq!(
+                bloom_check(&m, &hash, &"apple", &check, &absent),
+                BloomResult::Missing
+            );
+        }
+    }
+
+    mod missing_bits {
+
+        use crate::bloom::BloomBits256;
+
+        #[test]
+        fn test_subset() {
+            let stored = BloomBits256::new([0b1011, 1 << 5]);
+            let computed = BloomBits256::new([0b0011, 1 << 5]);
+            assert!(stored.missing_bits(&computed).is_empty());
+        }
+
+        #[test]
+        fn test_partial() {
+            let stored = BloomBits256::new([0b0011, 0]);
+            let computed = BloomBits256::new([0b1101, (1 << 127) | 1]);
+            assert_eq!(stored.missing_bits(&computed), vec![2, 3, 128, 255]);
+        }
+
+        #[test]
+        fn test_disjoint() {
+            let stored = BloomBits256::new([0b0101, 0]);
+            let computed = BloomBits256::new([0b1010, 0]);
+            assert_eq!(stored.missing_bits(&computed), vec![1, 3]);
+        }
+    }
+
+    mod prefix_bloom {
+
+        use crate::bloom::{BloomResult, PrefixBloom};
+
+        #[test]
+        fn test_prefixes() {
+            let mut bloom: PrefixBloom<4> = PrefixBloom::new(3, 8);
+            bloom.insert("user_12345");
+            for prefix in ["u", "us", "user", "user_123", "user_1234", "user_12345"] {
+                assert_eq!(
+                    bloom.may_have_prefix(prefix),
+                    BloomResult::MayExist,
+                    "{}",
+                    prefix
+                );
+            }
+            assert_eq!(bloom.may_have_prefix("order"), BloomResult::Missing);
+        }
+
+        #[test]
+        fn test_empty() {
+            let bloom: PrefixBloom<2> = PrefixBloom::new(3, 8);
+            assert!(bloom.bits().is_empty());
+            assert_eq!(bloom.may_have_prefix("user"), BloomResult::Missing);
+        }
+    }
+
+    mod dyn_bloom {
+
+        use crate::bloom::{BloomResult, DynBloom};
+
+        #[test]
+        fn test_same_width() {
+            let mut stored = DynBloom::Bits256([0; 2]);
+            stored.set_bit(3);
+            stored.set_bit(200);
+            stored.set_bit(256 + 7);
+            assert_eq!(stored, DynBloom::Bits256([(1 << 3) | (1 << 7), 1 << 72]));
+            assert_eq!(stored.width(), 256);
+
+            let mut computed = DynBloom::Bits256([0; 2]);
+            computed.set_bit(200);
+            assert_eq!(stored.contains(&computed), BloomResult::MayExist);
+            assert_eq!(stored.and(&computed).unwrap(), computed);
+
+            computed.set_bit(1);
+            assert_eq!(stored.contains(&computed), BloomResult::Missing);
+
+            let mut small = DynBloom::Bits128([0]);
+            small.set_bit(130);
+            assert_eq!(small, DynBloom::Bits128([1 << 2]));
+            assert_eq!(DynBloom::Bits512([0; 4]).width(), 512);
+        }
+
+        #[test]
+        fn test_cross_width() {
+            let a = DynBloom::Bits128([1]);
+            let b = DynBloom::Bits512([1, 0, 0, 0]);
+            assert_eq!(a.contains(&b), BloomResult::Missing);
+            assert_eq!(b.contains(&a), BloomResult::Missing);
+            assert!(a.and(&b).is_err());
+        }
+    }
+
+    mod verdict_counts {
+
+        use crate::bloom::{verdict_counts, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_counts() {
+            let buckets: Vec<Bucket> = (1..=7)
+                .map(|d| Bucket::new_checked(format!("items_2022_12_{:02}", d)))
+                .collect();
+            let bloom = |b: &Bucket, modulo: &u8| match b.date_part().unwrap().2 % modulo {
+                0 => BloomResult::MayExist,
+                _ => BloomResult::Missing,
+            };
+            let (may_exist, missing) = verdict_counts(&bloom, &buckets, &3);
+            assert_eq!(may_exist + missing, buckets.len() as u64);
+            let expected: usize = buckets
+                .iter()
+                .filter(|b| bloom(b, &3) == BloomResult::MayExist)
+                .count();
+            assert_eq!(may_exist, expected as u64);
+            assert_eq!((may_exist, missing), (2, 5));
+            assert_eq!(verdict_counts(&bloom, &[], &3), (0, 0));
+        }
+    }
+
+    mod get_or_skip_opt_filter {
+
+        use crate::bloom::{get_or_skip_opt_filter, BloomResult};
+        use crate::bucket::Bucket;
+
+        fn scan(filter: Option<&u8>) -> Vec<String> {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            get_or_skip_opt_filter(
+                &|_: &Bucket, f: &u8| match *f {
+                    0 => BloomResult::Missing,
+                    _ => BloomResult::MayExist,
+                },
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, f: Option<&u8>| {
+                    Ok(vec![f
+                        .map(|f| f.to_string())
+                        .unwrap_or_else(|| "all".into())])
+                },
+                filter,
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn test_none() {
+            assert_eq!(scan(None), vec!["all"]);
+        }
+
+        #[test]
+        fn test_some() {
+            assert!(scan(Some(&0)).is_empty());
+            assert_eq!(scan(Some(&3)), vec!["3"]);
+        }
+    }
+
+    mod update_bloom_bits_since {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::update_bloom_bits_since;
+        use crate::bucket::{b, Bucket};
+        use crate::evt::Event;
+
+        type Versioned = Vec<(Bucket, u8, u64)>;
+
+        fn get_changed(db: &mut Versioned, since: u64) -> Result<Versioned, Event> {
+            Ok(db.iter().filter(|(_, _, v)| since < *v).cloned().collect())
+        }
+
+        #[test]
+        fn test_changed() {
+            let mut map: BTreeMap<Bucket, u8> =
+                [(b("items_2022_12_27"), 1), (b("items_2022_12_28"), 2)].into();
+            let mut db: Versioned = vec![
+                (b("items_2022_12_27"), 1, 3),
+                (b("items_2022_12_28"), 20, 7),
+                (b("items_2022_12_29"), 30, 6),
+            ];
+            let mut last_version: u64 = 5;
+            let updated: u64 =
+                update_bloom_bits_since(&mut map, &mut db, &mut get_changed, &mut last_version)
+                    .unwrap();
+            assert_eq!(updated, 2);
+            assert_eq!(last_version, 7);
+            assert_eq!(
+                map,
+                [
+                    (b("items_2022_12_27"), 1),
+                    (b("items_2022_12_28"), 20),
+                    (b("items_2022_12_29"), 30),
+                ]
+                .into()
+            );
+
+            let updated: u64 =
+                update_bloom_bits_since(&mut map, &mut db, &mut get_changed, &mut last_version)
+                    .unwrap();
+            assert_eq!(updated, 0);
+            assert_eq!(last_version, 7);
+        }
+    }
+
+    mod empty_policy {
+
+        use crate::bloom::{get_or_skip_if_missing_with_policy, BloomResult, EmptyPolicy};
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+
+        fn get(policy: EmptyPolicy, verdict: BloomResult) -> Result<Option<Vec<u8>>, Event> {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            get_or_skip_if_missing_with_policy(
+                policy,
+                &|_: &Bucket, _: &()| verdict,
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, _: &()| Ok(vec![]),
+                &(),
+            )
+        }
+
+        #[test]
+        fn test_skipped() {
+            assert_eq!(
+                get(EmptyPolicy::EmptyVec, BloomResult::Missing).unwrap(),
+                Some(vec![])
+            );
+            assert!(get(EmptyPolicy::Error, BloomResult::Missing).is_err());
+            assert_eq!(get(EmptyPolicy::None, BloomResult::Missing).unwrap(), None);
+        }
+
+        #[test]
+        fn test_scanned() {
+            for policy in [EmptyPolicy::EmptyVec, EmptyPolicy::Error, EmptyPolicy::None] {
+                assert_eq!(get(policy, BloomResult::MayExist).unwrap(), Some(vec![]));
+            }
+        }
+    }
+
+    mod should_write {
+
+        use crate::bloom::{should_write, BloomResult};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_verdicts() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let by_region = |_: &Bucket, region: &&str| match *region {
+                "jp" => BloomResult::MayExist,
+                _ => BloomResult::Missing,
+            };
+            assert!(should_write(&by_region, &b, &"jp"));
+            assert!(!should_write(&by_region, &b, &"us"));
+        }
+    }
+
+    mod hierarchical_bloom {
+
+        use std::cell::Cell;
+
+        use crate::bloom::{BloomBits256, BloomResult, HierarchicalBloom};
+        use crate::bucket::b;
+
+        fn bloom() -> HierarchicalBloom {
+            let mut h = HierarchicalBloom::default();
+            h.insert(b("items_2022_12_27_a"), BloomBits256::new([0b0011, 0]))
+                .unwrap();
+            h.insert(b("items_2022_12_27_b"), BloomBits256::new([0b0100, 0]))
+                .unwrap();
+            h.insert(b("items_2022_12_28_a"), BloomBits256::new([0, 0b1000]))
+                .unwrap();
+            h
+        }
+
+        #[test]
+        fn test_date_missing() {
+            let h: HierarchicalBloom = bloom();
+            assert_eq!(
+                h.date_bits(&(2022, 12, 27)),
+                Some(&BloomBits256::new([0b0111, 0]))
+            );
+            let checks = Cell::new(0);
+            let check = |stored: &BloomBits256, computed: &BloomBits256| {
+                checks.set(checks.get() + 1);
+                stored.contains(computed)
+            };
+            let computed = BloomBits256::new([0, 0b1000]);
+            for name in ["items_2022_12_27_a", "items_2022_12_27_b"] {
+                assert_eq!(
+                    h.check_with(&b(name), &computed, &check),
+                    BloomResult::Missing
+                );
+            }
+            assert_eq!(checks.get(), 2);
+            assert_eq!(
+                h.check(&b("items_2022_12_28_a"), &computed),
+                BloomResult::MayExist
+            );
+        }
+
+        #[test]
+        fn test_bucket_level() {
+            let h: HierarchicalBloom = bloom();
+            let computed = BloomBits256::new([0b0001, 0]);
+            assert_eq!(
+                h.check(&b("items_2022_12_27_a"), &computed),
+                BloomResult::MayExist
+            );
+            assert_eq!(
+                h.check(&b("items_2022_12_27_b"), &computed),
+                BloomResult::Missing
+            );
+            assert_eq!(
+                h.check(&b("items_2022_12_27_c"), &computed),
+                BloomResult::Missing
+            );
+            assert_eq!(
+                h.check(&b("items_2022_12_29_a"), &computed),
+                BloomResult::Missing
+            );
+        }
+
+        #[test]
+        fn test_no_date() {
+            let mut h = HierarchicalBloom::default();
+            assert!(h.insert(b("pg_database"), BloomBits256::default()).is_err());
+        }
+    }
+
+    mod cardinality_estimator {
+
+        use crate::bloom::CardinalityEstimator;
+
+        fn within(estimated: u64, actual: u64, ratio: f64) -> bool {
+            (estimated as f64 - actual as f64).abs() <= actual as f64 * ratio
+        }
+
+        #[test]
+        fn test_small() {
+            let mut e = CardinalityEstimator::new(10);
+            assert_eq!(e.estimate(), 0);
+            for i in 0..20 {
+                e.add(&i);
+                e.add(&i);
+            }
+            assert!(within(e.estimate(), 20, 0.1), "{}", e.estimate());
+        }
+
+        #[test]
+        fn test_large() {
+            let mut e = CardinalityEstimator::new(10);
+            for i in 0..10_000u32 {
+                e.add(&format!("item-{}", i));
+            }
+            // 3 * standard error(1.04 / 32)
+            assert!(within(e.estimate(), 10_000, 0.1), "{}", e.estimate());
+        }
+    }
+
+    mod check_sorted_buckets {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{check_many_buckets, check_sorted_buckets, BloomBits256, BloomResult};
+        use crate::bucket::{b, Bucket};
+
+        fn map() -> BTreeMap<Bucket, BloomBits256> {
+            [
+                (b("items_2022_12_01"), BloomBits256::from_hash("apple", 3)),
+                (b("items_2022_12_03"), BloomBits256::from_hash("banana", 3)),
+                (b("items_2022_12_04"), BloomBits256::from_hash("apple", 3)),
+                (b("items_2022_12_06"), BloomBits256::from_hash("apple", 3)),
+            ]
+            .into()
+        }
+
+        fn hash(f: &&str) -> BloomBits256 {
+            BloomBits256::from_hash(f, 3)
+        }
+
+        fn check(stored: &BloomBits256, computed: &BloomBits256) -> BloomResult {
+            stored.contains(computed)
+        }
+
+        #[test]
+        fn test_same_as_individual() {
+            let m = map();
+            let buckets: Vec<Bucket> = ["00", "01", "02", "03", "04", "04", "05", "06", "07"]
+                .iter()
+                .map(|d| b(&format!("items_2022_12_{}", d)))
+                .collect();
+            let merged = check_sorted_buckets(&m, &hash, &"apple", &check, &buckets);
+            let individual = check_many_buckets(&m, &hash, &"apple", &check, &buckets);
+            assert_eq!(merged, individual);
+            let may_exist: Vec<&str> = merged
+                .iter()
+                .filter(|(_, r)| BloomResult::MayExist == *r)
+                .map(|(b, _)| b.as_str())
+                .collect();
+            assert_eq!(
+                may_exist,
+                vec![
+                    "items_2022_12_01",
+                    "items_2022_12_04",
+                    "items_2022_12_04",
+                    "items_2022_12_06"
+                ]
+            );
+        }
+
+        #[test]
+        fn test_unsorted() {
+            let m = map();
+            let buckets: Vec<Bucket> = vec![b("items_2022_12_06"), b("items_2022_12_01")];
+            let merged = check_sorted_buckets(&m, &hash, &"apple", &check, &buckets);
+            assert_eq!(
+                merged,
+                check_many_buckets(&m, &hash, &"apple", &check, &buckets)
+            );
+            assert!(check_sorted_buckets(&m, &hash, &"apple", &check, &[]).is_empty());
+        }
+    }
+
+    mod bloom_check_opt_hash {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{bloom_check_opt_hash, BloomBits256, BloomResult};
+        use crate::bucket::Bucket;
+
+        fn hash(item_id: &Option<&str>) -> Option<BloomBits256> {
+            item_id.map(|id| BloomBits256::from_hash(id, 3))
+        }
+
+        fn check(stored: &BloomBits256, computed: &BloomBits256) -> BloomResult {
+            stored.contains(computed)
+        }
+
+        #[test]
+        fn test_none() {
+            let m: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            assert_eq!(
+                bloom_check_opt_hash(&m, &hash, &None, &check, &b),
+                BloomResult::MayExist
+            );
+        }
+
+        #[test]
+        fn test_some() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let m: BTreeMap<Bucket, BloomBits256> =
+                [(b.clone(), BloomBits256::new([0b0011, 0]))].into();
+            let hash_bits = |w: &Option<u128>| w.map(|w| BloomBits256::new([w, 0]));
+            assert_eq!(
+                bloom_check_opt_hash(&m, &hash_bits, &Some(0b0001), &check, &b),
+                BloomResult::MayExist
+            );
+            assert_eq!(
+                bloom_check_opt_hash(&m, &hash_bits, &Some(0b0100), &check, &b),
+                BloomResult::Missing
+            );
+            let absent = Bucket::new_checked("items_2022_12_28".into());
+            assert_eq!(
+                bloom_check_opt_hash(&m, &hash, &Some("apple"), &check, &absent),
+                BloomResult::Missing
+            );
+        }
+    }
+
+    mod tracked_bloom_map {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{BloomBits256, BloomResult, TrackedBloomMap};
+        use crate::bucket::{b, Bucket};
+
+        #[test]
+        fn test_never_checked() {
+            let m: BTreeMap<Bucket, BloomBits256> = (1..=4)
+                .map(|d| {
+                    let name = format!("items_2022_12_{:02}", d);
+                    (b(&name), BloomBits256::from_hash(&name, 3))
+                })
+                .collect();
+            let tracked: TrackedBloomMap<BloomBits256> = TrackedBloomMap::new(m);
+            let hash = |f: &&str| BloomBits256::from_hash(f, 3);
+            let check = |stored: &BloomBits256, computed: &BloomBits256| stored.contains(computed);
+            let bloom = |bucket: &Bucket, f: &&str| tracked.check(&hash, f, &check, bucket);
+
+            assert_eq!(
+                bloom(&b("items_2022_12_01"), &"items_2022_12_01"),
+                BloomResult::MayExist
+            );
+            bloom(&b("items_2022_12_01"), &"x");
+            bloom(&b("items_2022_12_03"), &"x");
+            assert_eq!(bloom(&b("items_2022_12_09"), &"x"), BloomResult::Missing);
+
+            assert_eq!(tracked.checks(&b("items_2022_12_01")), 2);
+            assert_eq!(
+                tracked.never_checked(),
+                vec![b("items_2022_12_02"), b("items_2022_12_04")]
+            );
+        }
+    }
+
+    mod tombstone {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{
+            bloom_check, mark_deleted, purge_tombstones, BloomBits256, BloomResult,
+        };
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_tombstone() {
+            let deleted = Bucket::new_checked("items_2022_12_27".into());
+            let alive = Bucket::new_checked("items_2022_12_28".into());
+            let items: Vec<String> = (0..32).map(|i| format!("item-{}", i)).collect();
+            let all: BloomBits256 = items.iter().fold(BloomBits256::default(), |bits, i| {
+                bits.or(&BloomBits256::from_hash(i, 3))
+            });
+            let mut m: BTreeMap<Bucket, BloomBits256> =
+                [(deleted.clone(), all), (alive.clone(), all)].into();
+            let hash = |f: &String| BloomBits256::from_hash(f, 3);
+            let check = |stored: &BloomBits256, computed: &BloomBits256| stored.contains(computed);
+
+            mark_deleted(&mut m, &deleted);
+            for item in &items {
+                assert_eq!(
+                    bloom_check(&m, &hash, item, &check, &deleted),
+                    BloomResult::Missing
+                );
+                assert_eq!(
+                    bloom_check(&m, &hash, item, &check, &alive),
+                    BloomResult::MayExist
+                );
+            }
+
+            assert_eq!(purge_tombstones(&mut m), 1);
+            assert!(!m.contains_key(&deleted));
+            assert!(m.contains_key(&alive));
+            assert_eq!(purge_tombstones(&mut m), 0);
+        }
+    }
+
+    mod evict_to_fit {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{estimated_bytes, evict_to_fit, BloomBits256};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_priority_and_target() {
+            let names = ["items_2022_12_27", "items_2022_12_28", "items_2022_12_29"];
+            let mut m: BTreeMap<Bucket, BloomBits256> = names
+                .iter()
+                .map(|n| (Bucket::new_checked((*n).into()), BloomBits256::default()))
+                .collect();
+            let entry: usize = 16 + 32 + std::mem::size_of::<Bucket>();
+            assert_eq!(estimated_bytes(&m), 3 * entry);
+            assert_eq!(estimated_bytes(&BTreeMap::new()), 0);
+
+            let priority = |b: &Bucket| match b.as_str() {
+                "items_2022_12_28" => 0,
+                "items_2022_12_27" => 1,
+                _ => 2,
+            };
+            assert!(evict_to_fit(&mut m, 3 * entry, &priority).is_empty());
+
+            let dropped: Vec<Bucket> = evict_to_fit(&mut m, 2 * entry - 1, &priority);
+            assert_eq!(
+                dropped,
+                vec![
+                    Bucket::new_checked("items_2022_12_28".into()),
+                    Bucket::new_checked("items_2022_12_27".into()),
+                ]
+            );
+            assert_eq!(estimated_bytes(&m), entry);
+            assert!(m.contains_key(&Bucket::new_checked("items_2022_12_29".into())));
+
+            assert_eq!(evict_to_fit(&mut m, 0, &priority).len(), 1);
+            assert!(m.is_empty());
+        }
+    }
+
+    mod weighted_composite {
+
+        use crate::bloom::{weighted_composite, BloomBits256, BloomResult};
+
+        #[test]
+        fn test_high_weight_dominates() {
+            let item = |id: &str, tag: &str| -> BloomBits256 {
+                weighted_composite(&[(&id, 16), (&tag, 1)])
+            };
+            let base: BloomBits256 = item("item-1", "fruit");
+            let same_id: BloomBits256 = item("item-1", "vegetable");
+            let same_tag: BloomBits256 = item("item-2", "fruit");
+
+            let id_only: BloomBits256 = weighted_composite(&[(&"item-1", 16), (&"fruit", 0)]);
+            assert!(id_only.count_ones() > 8);
+            assert_eq!(base.contains(&id_only), BloomResult::MayExist);
+            assert_eq!(same_id.contains(&id_only), BloomResult::MayExist);
+
+            let shared_id: u32 = base.and(&same_id).count_ones();
+            let shared_tag: u32 = base.and(&same_tag).count_ones();
+            assert!(shared_id >= id_only.count_ones());
+            assert!(shared_id > shared_tag);
+        }
+
+        #[test]
+        fn test_known_bits() {
+            assert_eq!(
+                weighted_composite(&[(&"item-1", 4), (&7u32, 1)]),
+                BloomBits256::new([
+                    0x8000000000800000000000002008000,
+                    0x80000000000000000000000000
+                ])
+            );
+        }
+
+        #[test]
+        fn test_zero_weight() {
+            assert_eq!(
+                weighted_composite(&[(&"item-1", 0)]),
+                BloomBits256::default()
+            );
+            assert_eq!(weighted_composite(&[]), BloomBits256::default());
+        }
+    }
+
+    mod collision_rate {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{assert_collision_rate_below, collision_rate, BloomBits256};
+        use crate::bucket::Bucket;
+
+        fn map(patterns: &[u128]) -> BTreeMap<Bucket, BloomBits256> {
+            patterns
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    let b = Bucket::new_checked(format!("items_2022_12_{:02}", i + 1));
+                    (b, BloomBits256::new([*p, 0]))
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_no_collision() {
+            let m = map(&[1, 2, 4, 8]);
+            assert_eq!(collision_rate(&m), 0.0);
+            assert!(assert_collision_rate_below(&m, 0.1).is_ok());
+            assert_eq!(collision_rate(&BTreeMap::new()), 0.0);
+        }
+
+        #[test]
+        fn test_partial() {
+            let m = map(&[1, 1, 4, 8]);
+            assert_eq!(collision_rate(&m), 0.5);
+            assert!(assert_collision_rate_below(&m, 0.6).is_ok());
+            assert!(assert_collision_rate_below(&m, 0.5).is_err());
+        }
+
+        #[test]
+        fn test_high() {
+            let m = map(&[3, 3, 3, 5, 5]);
+            assert_eq!(collision_rate(&m), 1.0);
+            assert!(assert_collision_rate_below(&m, 0.9).is_err());
+        }
+    }
+
+    mod result_cache {
+
+        use std::cell::Cell;
+
+        use crate::bloom::{BloomResult, ResultCache};
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_compute_once() {
+            let computed: Cell<u32> = Cell::new(0);
+            let compute = |r: BloomResult| {
+                computed.set(computed.get() + 1);
+                r
+            };
+            let b1 = Bucket::new_checked("items_2022_12_27".into());
+            let b2 = Bucket::new_checked("items_2022_12_28".into());
+            let mut cache = ResultCache::new();
+            assert!(cache.is_empty());
+
+            for _ in 0..3 {
+                let got = cache.get_or_compute(&b1, 42, || compute(BloomResult::Missing));
+                assert_eq!(got, BloomResult::Missing);
+            }
+            assert_eq!(computed.get(), 1);
+
+            let other_filter = cache.get_or_compute(&b1, 43, || compute(BloomResult::MayExist));
+            let other_bucket = cache.get_or_compute(&b2, 42, || compute(BloomResult::MayExist));
+            assert_eq!(other_filter, BloomResult::MayExist);
+            assert_eq!(other_bucket, BloomResult::MayExist);
+            assert_eq!(computed.get(), 3);
+            assert_eq!(cache.len(), 3);
+        }
+    }
+
+    mod apply_deltas {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{apply_delta, apply_deltas, BloomBits256, BloomDelta};
+        use crate::bucket::{b, Bucket};
+
+        #[test]
+        fn test_upserts_and_deletes() {
+            let bits = |lo: u128| BloomBits256::new([lo, 0]);
+            let mut m: BTreeMap<Bucket, BloomBits256> = [(b("items_2022_12_26"), bits(1))].into();
+
+            let deltas = vec![
+                BloomDelta::Upsert(b("items_2022_12_27"), bits(2)),
+                BloomDelta::Upsert(b("items_2022_12_28"), bits(4)),
+                BloomDelta::Delete(b("items_2022_12_26")),
+                BloomDelta::Upsert(b("items_2022_12_27"), bits(8)),
+                BloomDelta::Delete(b("items_2022_12_28")),
+                BloomDelta::Delete(b("items_2022_12_29")),
+            ];
+            assert_eq!(apply_deltas(&mut m, deltas), 6);
+            assert_eq!(m, [(b("items_2022_12_27"), bits(8))].into());
+
+            apply_delta(&mut m, BloomDelta::Upsert(b("items_2022_12_30"), bits(16)));
+            assert_eq!(m.len(), 2);
+        }
+    }
+
+    mod stable_hasher {
+
+        use std::hash::{Hash, Hasher};
+
+        use crate::bloom::{composite_hash, BloomBits256, CardinalityEstimator, StableHasher};
+
+        #[test]
+        fn test_known_hashes() {
+            let mut h = StableHasher::new();
+            h.write(b"apple");
+            assert_eq!(h.finish(), 0x9bd6c11a2c6bf096);
+
+            let mut h = StableHasher::new();
+            42usize.hash(&mut h);
+            let mut h64 = StableHasher::new();
+            42u64.hash(&mut h64);
+            assert_eq!(h.finish(), 0xa6245a5dcf278758);
+            assert_eq!(h.finish(), h64.finish());
+        }
+
+        #[test]
+        fn test_known_bits() {
+            assert_eq!(
+                BloomBits256::from_hash("apple", 3),
+                BloomBits256::new([0x100000000000000000000000100000, 0x8000000000000000000000])
+            );
+            assert_eq!(
+                composite_hash(&[&"item-1", &7u32], 3),
+                BloomBits256::new([0x408000000004000, 0])
+            );
+
+            let mut estimator = CardinalityEstimator::new(10);
+            for i in 0..1000u32 {
+                estimator.add(&i);
+            }
+            assert_eq!(estimator.estimate(), 999);
+        }
+    }
+}
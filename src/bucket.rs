@@ -1,22 +1,759 @@
 //! A key/value pairs container.
 
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Deref;
+use std::str::FromStr;
+
+use crate::evt::Event;
+
 /// An ID of the container which can have many key/value pairs.
-#[derive(Clone, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Debug)]
 pub struct Bucket {
     /// The name of this bucket.
     name: String,
 }
 
+/// A date(year, month, day) which partitions buckets(see [`Bucket::date_part`]).
+pub type DatePrefix = (u16, u8, u8);
+
+/// Describes how the components of bucket names are separated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NameScheme {
+    separator: char,
+}
+
+impl NameScheme {
+    /// The separators of the name components which can be used([`validate_name`] allows them).
+    pub const SEPARATORS: [char; 3] = ['_', '-', '.'];
+
+    /// Creates a scheme; the separator must be one of [`NameScheme::SEPARATORS`].
+    pub fn new(separator: char) -> Result<Self, Event> {
+        match Self::SEPARATORS.contains(&separator) {
+            true => Ok(Self { separator }),
+            false => Err(Event::UnexpectedError(format!(
+                "Invalid separator: {:?}",
+                separator
+            ))),
+        }
+    }
+
+    /// Gets the separator of the name components.
+    pub fn separator(&self) -> char {
+        self.separator
+    }
+}
+
+impl Default for NameScheme {
+    fn default() -> Self {
+        Self { separator: '_' }
+    }
+}
+
 impl Bucket {
     /// Gets the name of this bucket as str.
     pub fn as_str(&self) -> &str {
         self.name.as_str()
     }
 
+    /// Gets the `_` separated segments of the name.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.segments_with(&NameScheme::default())
+    }
+
+    /// Gets the segments of the name separated by the separator of the scheme.
+    pub fn segments_with(&self, scheme: &NameScheme) -> impl Iterator<Item = &str> {
+        self.name.split(scheme.separator)
+    }
+
+    /// Gets the date(`YYYY_MM_DD`) embedded in the name.
+    ///
+    /// e.g, `(2022, 12, 27)` for `bucket_2022_12_27_cafef00ddeadbeafface864299792458`
+    pub fn date_part(&self) -> Option<(u16, u8, u8)> {
+        self.date_part_with(&NameScheme::default())
+    }
+
+    /// Gets the date embedded in the name using the separator of the scheme.
+    ///
+    /// e.g, `(2022, 12, 27)` for `bucket-2022-12-27` with the separator `-`
+    pub fn date_part_with(&self, scheme: &NameScheme) -> Option<(u16, u8, u8)> {
+        let segments: Vec<&str> = self.segments_with(scheme).collect();
+        find_date(&segments).map(|(_, date)| date)
+    }
+
+    /// Checks if the names are equal except for the last segment(e.g, a nonce).
+    ///
+    /// e.g, `items_2022_12_27_cafe` equals `items_2022_12_27_f00d` with the separator `_`.
+    /// Names without the separator are compared as a whole.
+    pub fn eq_ignoring_suffix(&self, other: &Bucket, sep: char) -> bool {
+        let logical = |name: &str| -> usize { name.rfind(sep).unwrap_or(name.len()) };
+        self.name[..logical(&self.name)] == other.name[..logical(&other.name)]
+    }
+
     /// Creates a bucket from a checked string.
     ///
     /// No check will be done by this library.
     pub fn new_checked(checked: String) -> Self {
         Self { name: checked }
     }
+
+    /// Creates a bucket after checking the name(see [`validate_name`]).
+    pub fn new_validated(unchecked: String) -> Result<Self, Event> {
+        validate_name(unchecked.as_str())?;
+        Ok(Self::new_checked(unchecked))
+    }
+}
+
+impl PartialEq<str> for Bucket {
+    fn eq(&self, other: &str) -> bool {
+        self.name == other
+    }
+}
+
+impl PartialEq<&str> for Bucket {
+    fn eq(&self, other: &&str) -> bool {
+        self.name == *other
+    }
+}
+
+impl PartialEq<String> for Bucket {
+    fn eq(&self, other: &String) -> bool {
+        self.name == *other
+    }
+}
+
+/// Parses a bucket name(see [`Bucket::new_validated`]).
+impl FromStr for Bucket {
+    type Err = Event;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(String::from(s))
+    }
+}
+
+/// Converts a bucket name(see [`Bucket::new_validated`]).
+impl TryFrom<&str> for Bucket {
+    type Error = Event;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Self::try_from(String::from(s))
+    }
+}
+
+/// Converts a bucket name(see [`Bucket::new_validated`]).
+impl TryFrom<String> for Bucket {
+    type Error = Event;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new_validated(s)
+    }
+}
+
+fn parse_digits<T: FromStr>(s: &str, len: usize) -> Option<T> {
+    let digits: bool = s.len() == len && s.chars().all(|c| c.is_ascii_digit());
+    digits.then(|| s.parse().ok()).flatten()
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    let leap: bool =
+        (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400);
+    match (month, leap) {
+        (2, true) => 29,
+        (2, false) => 28,
+        (4 | 6 | 9 | 11, _) => 30,
+        _ => 31,
+    }
+}
+
+fn parse_date(y: &str, m: &str, d: &str) -> Option<(u16, u8, u8)> {
+    let year: u16 = parse_digits(y, 4)?;
+    let month: u8 = parse_digits(m, 2)?;
+    let day: u8 = parse_digits(d, 2)?;
+    let valid: bool = (1..=12).contains(&month) && 1 <= day && day <= days_in_month(year, month);
+    valid.then_some((year, month, day))
+}
+
+/// Finds the first date segments; returns (the index of the year segment, the date).
+fn find_date(segments: &[&str]) -> Option<(usize, DatePrefix)> {
+    segments
+        .windows(3)
+        .enumerate()
+        .find_map(|(i, w)| parse_date(w[0], w[1], w[2]).map(|date| (i, date)))
+}
+
+/// Converts the date to the number of days since 1970-01-01.
+pub(crate) fn days_from_civil(date: (u16, u8, u8)) -> i64 {
+    let (year, month, day) = date;
+    let y: i64 = i64::from(year) - i64::from(month <= 2);
+    let era: i64 = y.div_euclid(400);
+    let yoe: i64 = y - era * 400;
+    let m: i64 = i64::from(month);
+    let doy: i64 = (153 * (m + if 2 < m { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe: i64 = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// A bucket with a human readable label for display.
+#[derive(Clone, Debug)]
+pub struct LabeledBucket {
+    /// The labeled bucket.
+    pub bucket: Bucket,
+
+    /// The label to be displayed; the name of the bucket is displayed if `None`.
+    pub label: Option<String>,
+}
+
+impl Deref for LabeledBucket {
+    type Target = Bucket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.bucket
+    }
+}
+
+impl Display for LabeledBucket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => f.write_str(label),
+            None => f.write_str(self.bucket.as_str()),
+        }
+    }
+}
+
+/// Checks if the name can be used as an identifier(e.g, a table name, a column name).
+///
+/// - The first char must be an ascii alphabet or `_`.
+/// - Others must be ascii alphanumerics or `_`.
+/// - Up to 63 bytes.
+pub fn validate_identifier(name: &str) -> Result<(), Event> {
+    let mut chars = name.chars();
+    let head_ok: bool = chars
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false);
+    let tail_ok: bool = chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let len_ok: bool = name.len() <= 63;
+    match head_ok && tail_ok && len_ok {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError(format!(
+            "Invalid identifier: {}",
+            name
+        ))),
+    }
+}
+
+/// Checks if the name can be used as a bucket name(see [`Bucket::new_validated`]).
+///
+/// Same as [`validate_identifier`] except that all [`NameScheme::SEPARATORS`] are allowed after
+/// the first char and the length is not limited.
+pub fn validate_name(name: &str) -> Result<(), Event> {
+    let mut chars = name.chars();
+    let head_ok: bool = chars
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false);
+    let tail_ok: bool =
+        chars.all(|c| c.is_ascii_alphanumeric() || NameScheme::SEPARATORS.contains(&c));
+    match head_ok && tail_ok {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError(format!(
+            "Invalid bucket name: {}",
+            name
+        ))),
+    }
+}
+
+/// Splits the name into (the part before the `_vN_` segment, N).
+///
+/// The whole name and `None` are returned if no version segment exists.
+fn version_key(name: &str) -> (&str, Option<u64>) {
+    let mut offset: usize = 0;
+    for segment in name.split('_') {
+        let digits: Option<&str> = segment.strip_prefix('v');
+        let parsed: Option<u64> = digits
+            .filter(|d| !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|d| d.parse().ok());
+        if let (Some(version), true) = (parsed, 0 < offset) {
+            return (&name[..offset - 1], Some(version));
+        }
+        offset += segment.len() + 1;
+    }
+    (name, None)
+}
+
+/// Compares buckets treating the `_vN_` segment numerically(`v10` sorts after `v2`).
+///
+/// Buckets are ordered by the part before the version segment, the version, and the name.
+/// Buckets without a version segment sort before the versioned buckets with the same prefix.
+///
+/// e.g, `items`, `items_v1_a`, `items_v2_a`, `items_v10_a`
+pub fn by_version(a: &Bucket, b: &Bucket) -> Ordering {
+    let (pa, va) = version_key(a.as_str());
+    let (pb, vb) = version_key(b.as_str());
+    pa.cmp(pb).then(va.cmp(&vb)).then_with(|| a.cmp(b))
+}
+
+/// Formats the date as the `YYYY_MM_DD` segment of bucket names(zero padded).
+///
+/// e.g, `2022_01_05` for `(2022, 1, 5)`
+pub fn date_prefix(year: u16, month: u8, day: u8) -> String {
+    format!("{:04}_{:02}_{:02}", year, month, day)
+}
+
+/// Creates the bucket of the date(`<prefix>_YYYY_MM_DD_<hash>`).
+///
+/// The date can be got back by [`Bucket::date_part`]. No check will be done by this library.
+///
+/// # Arguments
+/// - prefix: The prefix of the bucket name(e.g, `items`).
+/// - year: The year of the timestamp.
+/// - month: The month of the timestamp.
+/// - day: The day of the timestamp.
+/// - hash: The suffix of the bucket name(e.g, a hash of the key).
+pub fn bucket_for_timestamp(prefix: &str, year: u16, month: u8, day: u8, hash: &str) -> Bucket {
+    Bucket::new_checked(format!(
+        "{}_{}_{}",
+        prefix,
+        date_prefix(year, month, day),
+        hash
+    ))
+}
+
+/// Metadata parsed from a bucket name(see [`BucketMeta::parse`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketMeta {
+    /// The date(see [`Bucket::date_part`]).
+    pub date: Option<DatePrefix>,
+
+    /// The hash(the segment just after the date if it has 8 or more hex digits).
+    pub hash: Option<String>,
+
+    /// The shard computed from the first 8 hex digits of the hash; 0 without a hash.
+    pub shard: u32,
+}
+
+impl BucketMeta {
+    /// Parses the `_` separated name of the bucket once(see [`BucketMeta::parse_with`]).
+    ///
+    /// e.g, `bucket_2022_12_27_cafef00ddeadbeafface864299792458`
+    /// - date: `(2022, 12, 27)`
+    /// - hash: `cafef00ddeadbeafface864299792458`
+    /// - shard: `0xcafef00d % num_shards`
+    ///
+    /// # Arguments
+    /// - b: The bucket to be parsed.
+    /// - num_shards: The number of shards; the shard is 0 if no shard.
+    pub fn parse(b: &Bucket, num_shards: u32) -> Self {
+        Self::parse_with(b, num_shards, &NameScheme::default())
+    }
+
+    /// Parses the name of the bucket once to reuse the metadata(e.g, in hot loops).
+    ///
+    /// Names without date segments have no hash(e.g, `items_20221227`).
+    ///
+    /// # Arguments
+    /// - b: The bucket to be parsed.
+    /// - num_shards: The number of shards; the shard is 0 if no shard.
+    /// - scheme: Separates the segments of the name.
+    pub fn parse_with(b: &Bucket, num_shards: u32, scheme: &NameScheme) -> Self {
+        let segments: Vec<&str> = b.segments_with(scheme).collect();
+        let found: Option<(usize, DatePrefix)> = find_date(&segments);
+        let hash: Option<String> = found
+            .and_then(|(ix, _)| segments.get(ix + 3))
+            .filter(|s| 8 <= s.len() && s.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|s| String::from(*s));
+        let shard: u32 = hash
+            .as_deref()
+            .and_then(|h| u32::from_str_radix(&h[..8], 16).ok())
+            .and_then(|prefix| prefix.checked_rem(num_shards))
+            .unwrap_or_default();
+        Self {
+            date: found.map(|(_, date)| date),
+            hash,
+            shard,
+        }
+    }
+}
+
+/// Creates a bucket without checks(test helper).
+#[cfg(test)]
+pub(crate) fn b(name: &str) -> Bucket {
+    Bucket::new_checked(name.into())
+}
+
+/// Creates buckets without checks(test helper).
+#[cfg(test)]
+pub(crate) fn buckets<S: AsRef<str>>(names: impl IntoIterator<Item = S>) -> Vec<Bucket> {
+    names.into_iter().map(|n| b(n.as_ref())).collect()
+}
+
+/// Creates the daily buckets `items_2022_12_DD`(test helper).
+#[cfg(test)]
+pub(crate) fn december(days: std::ops::RangeInclusive<u8>) -> Vec<Bucket> {
+    buckets(days.map(|d| format!("items_2022_12_{:02}", d)))
+}
+
+#[cfg(test)]
+mod test_bucket {
+
+    mod parse {
+
+        use crate::bucket::{Bucket, NameScheme};
+        use crate::evt::Event;
+
+        #[test]
+        fn test_valid() {
+            let b: Bucket = "pg_database".parse().unwrap();
+            assert_eq!(b.as_str(), "pg_database");
+
+            let b: Bucket = Bucket::try_from("bucket_2022_12_27").unwrap();
+            assert_eq!(b.as_str(), "bucket_2022_12_27");
+
+            let b: Bucket = Bucket::try_from(String::from("pg_class")).unwrap();
+            assert_eq!(b.as_str(), "pg_class");
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!("pg_database; DROP TABLE x".parse::<Bucket>().is_err());
+            assert!(Bucket::try_from("").is_err());
+            assert!(Bucket::try_from(String::from("2022_12_27")).is_err());
+            assert!("-items".parse::<Bucket>().is_err());
+            assert!("items/2022".parse::<Bucket>().is_err());
+        }
+
+        #[test]
+        fn test_name_scheme() {
+            let dashed: Bucket = "items-2022-12-27".parse().unwrap();
+            let scheme = NameScheme::new('-').unwrap();
+            assert_eq!(dashed.date_part_with(&scheme), Some((2022, 12, 27)));
+
+            let dotted: Bucket = Bucket::try_from("items.2022.12.27").unwrap();
+            let scheme = NameScheme::new('.').unwrap();
+            assert_eq!(dotted.date_part_with(&scheme), Some((2022, 12, 27)));
+
+            let long: String = format!("items_2022_12_27_{}", "cafe".repeat(16));
+            assert!(long.parse::<Bucket>().is_ok());
+            assert!(Bucket::new_validated(long).is_ok());
+        }
+
+        fn parse_both(a: &str, b: &str) -> Result<(Bucket, Bucket), Event> {
+            let a: Bucket = a.parse()?;
+            let b: Bucket = Bucket::try_from(b)?;
+            Ok((a, b))
+        }
+
+        #[test]
+        fn test_question_mark() {
+            let (a, b) = parse_both("pg_database", "pg_class").unwrap();
+            assert_eq!(a.as_str(), "pg_database");
+            assert_eq!(b.as_str(), "pg_class");
+            match parse_both("pg_database", "pg class") {
+                Err(Event::UnexpectedError(_)) => {}
+                _ => panic!("must be an error"),
+            }
+        }
+    }
+
+    mod by_version {
+
+        use crate::bucket::{by_version, Bucket};
+
+        fn names(v: &[Bucket]) -> Vec<&str> {
+            v.iter().map(|b| b.as_str()).collect()
+        }
+
+        #[test]
+        fn test_numeric() {
+            let mut v: Vec<Bucket> = ["items_v2_cafe", "items_v10_cafe", "items_v1_cafe"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            v.sort_by(by_version);
+            assert_eq!(
+                names(&v),
+                vec!["items_v1_cafe", "items_v2_cafe", "items_v10_cafe"]
+            );
+        }
+
+        #[test]
+        fn test_versionless() {
+            let mut v: Vec<Bucket> = [
+                "orders_v3",
+                "items_v10_cafe",
+                "items",
+                "v2_items",
+                "items_v2_dafe",
+                "items_v2_cafe",
+                "items_vx_cafe",
+            ]
+            .into_iter()
+            .map(|n| Bucket::new_checked(n.into()))
+            .collect();
+            v.sort_by(by_version);
+            let sorted: Vec<&str> = names(&v);
+            assert_eq!(
+                sorted,
+                vec![
+                    "items",
+                    "items_v2_cafe",
+                    "items_v2_dafe",
+                    "items_v10_cafe",
+                    "items_vx_cafe",
+                    "orders_v3",
+                    "v2_items",
+                ]
+            );
+
+            let mut reversed: Vec<Bucket> = v.iter().rev().cloned().collect();
+            reversed.sort_by(by_version);
+            assert_eq!(names(&reversed), sorted);
+        }
+    }
+
+    mod labeled_bucket {
+
+        use crate::bucket::{Bucket, LabeledBucket};
+
+        #[test]
+        fn test_display() {
+            let labeled = LabeledBucket {
+                bucket: Bucket::new_checked(
+                    "bucket_2022_12_27_cafef00ddeadbeafface864299792458".into(),
+                ),
+                label: Some("tenant-a/2022-12-27".into()),
+            };
+            assert_eq!(labeled.to_string(), "tenant-a/2022-12-27");
+
+            let unlabeled = LabeledBucket {
+                label: None,
+                ..labeled
+            };
+            assert_eq!(
+                format!("{}", unlabeled),
+                "bucket_2022_12_27_cafef00ddeadbeafface864299792458"
+            );
+        }
+
+        #[test]
+        fn test_deref() {
+            let labeled = LabeledBucket {
+                bucket: Bucket::new_checked("pg_database".into()),
+                label: Some("databases".into()),
+            };
+            assert_eq!(labeled.as_str(), "pg_database");
+            let b: &Bucket = &labeled;
+            assert_eq!(b, &Bucket::new_checked("pg_database".into()));
+        }
+    }
+
+    mod date_part {
+
+        use crate::bucket::{days_from_civil, Bucket};
+
+        #[test]
+        fn test_date() {
+            let b =
+                Bucket::new_checked("bucket_2022_12_27_cafef00ddeadbeafface864299792458".into());
+            assert_eq!(b.date_part(), Some((2022, 12, 27)));
+
+            let b = Bucket::new_checked("items_v2_2024_02_29".into());
+            assert_eq!(b.date_part(), Some((2024, 2, 29)));
+        }
+
+        #[test]
+        fn test_no_date() {
+            let invalid: Vec<&str> = vec![
+                "pg_database",
+                "bucket_2022_13_27",
+                "bucket_2023_02_29",
+                "bucket_2022_1_5",
+                "bucket_2022_12",
+            ];
+            for name in invalid {
+                assert_eq!(
+                    Bucket::new_checked(name.into()).date_part(),
+                    None,
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        #[test]
+        fn test_days_from_civil() {
+            assert_eq!(days_from_civil((1970, 1, 1)), 0);
+            assert_eq!(
+                days_from_civil((2000, 3, 1)) - days_from_civil((2000, 2, 28)),
+                2
+            );
+            assert_eq!(
+                days_from_civil((2023, 1, 1)) - days_from_civil((2022, 12, 27)),
+                5
+            );
+        }
+    }
+
+    mod name_scheme {
+
+        use crate::bucket::{Bucket, NameScheme};
+
+        #[test]
+        fn test_dash() {
+            let scheme = NameScheme::new('-').unwrap();
+            let b = Bucket::new_checked("bucket-2022-12-27-cafef00d".into());
+            assert_eq!(b.date_part_with(&scheme), Some((2022, 12, 27)));
+            assert_eq!(b.segments_with(&scheme).count(), 5);
+            assert_eq!(b.date_part(), None);
+        }
+
+        #[test]
+        fn test_dot() {
+            let scheme = NameScheme::new('.').unwrap();
+            let b = Bucket::new_checked("items.v2.2024.02.29".into());
+            assert_eq!(b.date_part_with(&scheme), Some((2024, 2, 29)));
+            assert_eq!(b.date_part_with(&NameScheme::default()), None);
+        }
+
+        #[test]
+        fn test_default() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            assert_eq!(NameScheme::default().separator(), '_');
+            assert_eq!(b.date_part_with(&NameScheme::default()), b.date_part());
+        }
+
+        #[test]
+        fn test_invalid_separator() {
+            assert!(NameScheme::new('/').is_err());
+            assert!(NameScheme::new('a').is_err());
+            for sep in NameScheme::SEPARATORS {
+                let name: String = ["items", "2022", "12", "27"].join(&sep.to_string());
+                let b: Bucket = name.parse().unwrap();
+                let scheme = NameScheme::new(sep).unwrap();
+                assert_eq!(b.date_part_with(&scheme), Some((2022, 12, 27)));
+            }
+        }
+    }
+
+    mod eq_ignoring_suffix {
+
+        use crate::bucket::b;
+
+        #[test]
+        fn test_equal() {
+            assert!(b("items_2022_12_27_cafe").eq_ignoring_suffix(&b("items_2022_12_27_f00d"), '_'));
+            assert!(b("items-v2-dead").eq_ignoring_suffix(&b("items-v2-beaf"), '-'));
+            assert!(b("items").eq_ignoring_suffix(&b("items"), '_'));
+        }
+
+        #[test]
+        fn test_unequal() {
+            assert!(
+                !b("items_2022_12_27_cafe").eq_ignoring_suffix(&b("items_2022_12_28_cafe"), '_')
+            );
+            assert!(
+                !b("items_2022_12_27_cafe").eq_ignoring_suffix(&b("items_2022_12_27_f00d"), '-')
+            );
+            assert!(!b("items_v1_cafe").eq_ignoring_suffix(&b("items_v2_cafe"), '_'));
+        }
+    }
+
+    mod bucket_for_timestamp {
+
+        use crate::bucket::{bucket_for_timestamp, date_prefix, Bucket};
+
+        #[test]
+        fn test_padding() {
+            assert_eq!(date_prefix(2022, 1, 5), "2022_01_05");
+            assert_eq!(date_prefix(2022, 12, 27), "2022_12_27");
+            assert_eq!(date_prefix(999, 9, 9), "0999_09_09");
+        }
+
+        #[test]
+        fn test_bucket() {
+            let b: Bucket = bucket_for_timestamp("items", 2022, 1, 5, "cafef00d");
+            assert_eq!(b.as_str(), "items_2022_01_05_cafef00d");
+            assert_eq!(b.date_part(), Some((2022, 1, 5)));
+        }
+    }
+
+    mod eq_str {
+
+        use crate::bucket::Bucket;
+
+        #[test]
+        fn test_eq() {
+            let b = Bucket::new_checked("pg_database".into());
+            assert!(b == *"pg_database");
+            assert!(b == "pg_database");
+            let name: String = "pg_database".into();
+            assert!(b == name);
+        }
+
+        #[test]
+        fn test_ne() {
+            let b = Bucket::new_checked("pg_database".into());
+            assert!(b != *"pg_class");
+            assert!(b != "pg_class");
+            let name: String = "pg_class".into();
+            assert!(b != name);
+        }
+    }
+
+    mod bucket_meta {
+
+        use crate::bucket::{Bucket, BucketMeta, NameScheme};
+
+        #[test]
+        fn test_structured() {
+            let b =
+                Bucket::new_checked("bucket_2022_12_27_cafef00ddeadbeafface864299792458".into());
+            let meta = BucketMeta::parse(&b, 16);
+            assert_eq!(meta.date, Some((2022, 12, 27)));
+            assert_eq!(
+                meta.hash.as_deref(),
+                Some("cafef00ddeadbeafface864299792458")
+            );
+            assert_eq!(meta.shard, 0xcafef00d % 16);
+            assert_eq!(BucketMeta::parse(&b, 0).shard, 0);
+        }
+
+        #[test]
+        fn test_unstructured() {
+            let meta = BucketMeta::parse(&Bucket::new_checked("pg_database".into()), 16);
+            assert_eq!(
+                meta,
+                BucketMeta {
+                    date: None,
+                    hash: None,
+                    shard: 0,
+                }
+            );
+            let meta = BucketMeta::parse(&Bucket::new_checked("items_2022_12_27".into()), 16);
+            assert_eq!(meta.date, Some((2022, 12, 27)));
+            assert_eq!(meta.hash, None);
+        }
+
+        #[test]
+        fn test_unseparated_date() {
+            let meta = BucketMeta::parse(&Bucket::new_checked("items_20221227".into()), 16);
+            assert_eq!(
+                meta,
+                BucketMeta {
+                    date: None,
+                    hash: None,
+                    shard: 0,
+                }
+            );
+        }
+
+        #[test]
+        fn test_scheme() {
+            let b = Bucket::new_checked("items-2022-12-27-cafef00d-v2".into());
+            let meta = BucketMeta::parse_with(&b, 16, &NameScheme::new('-').unwrap());
+            assert_eq!(meta.date, Some((2022, 12, 27)));
+            assert_eq!(meta.hash.as_deref(), Some("cafef00d"));
+            assert_eq!(meta.shard, 0xcafef00d % 16);
+        }
+    }
 }
@@ -1,8 +1,17 @@
 //! Filters buckets using a cache.
 
-use std::collections::BTreeSet;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::BufRead;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::{bucket::Bucket, evt::Event};
+use crate::{
+    bloom::{BloomBits, BloomResult, EmptyPolicy},
+    bucket::{days_from_civil, Bucket},
+    evt::Event,
+    state::{json_string, parse_json_strings},
+};
 
 /// Tries to scan values from a slow db if a bucket is in a cache.
 ///
@@ -78,6 +87,34 @@ where
     }
 }
 
+/// Gets values from a slow db if the bucket exists; reports a skip by the policy.
+///
+/// # Arguments
+/// - policy: How to report a skipped bucket.
+/// - cache: Checks if a bucket exists.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn get_or_skip_if_bucket_missing_with_policy<C, D, G, F, T>(
+    policy: EmptyPolicy,
+    cache: &C,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Option<Vec<T>>, Event>
+where
+    C: Fn(&Bucket) -> bool,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let bucket_exists: bool = cache(bucket);
+    match bucket_exists {
+        true => getter(shared_db, bucket, filter).map(Some),
+        false => policy.skipped(bucket),
+    }
+}
+
 /// Gets list of buckets and updates the cache of buckets.
 ///
 /// # Arguments
@@ -100,3 +137,1080 @@ where
         inserted.then_some(1).map(|cnt| cnt + tot).unwrap_or(tot)
     }))
 }
+
+/// A cache invalidation message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Invalidation {
+    /// The bucket has been created(payload: `+name`).
+    Add(Bucket),
+
+    /// The bucket has been dropped(payload: `-name`).
+    Remove(Bucket),
+}
+
+/// Parses a notification payload(`+name` or `-name`).
+pub fn parse_invalidation(payload: &str) -> Result<Invalidation, Event> {
+    let invalid = || Event::UnexpectedError(format!("Invalid invalidation payload: {}", payload));
+    let mut chars = payload.chars();
+    let op: char = chars.next().ok_or_else(invalid)?;
+    let name: &str = chars.as_str();
+    match name.is_empty() {
+        true => Err(invalid()),
+        false => Ok(()),
+    }?;
+    let bucket: Bucket = Bucket::new_checked(name.into());
+    match op {
+        '+' => Ok(Invalidation::Add(bucket)),
+        '-' => Ok(Invalidation::Remove(bucket)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Applies a notification payload to the shared cache.
+///
+/// Returns true if the cache has been changed.
+pub fn apply_invalidation(cache: &RwLock<BTreeSet<Bucket>>, payload: &str) -> Result<bool, Event> {
+    let inv: Invalidation = parse_invalidation(payload)?;
+    let mut guard = cache
+        .write()
+        .map_err(|e| Event::UnexpectedError(format!("Unable to lock the cache: {}", e)))?;
+    match inv {
+        Invalidation::Add(b) => Ok(guard.insert(b)),
+        Invalidation::Remove(b) => Ok(guard.remove(&b)),
+    }
+}
+
+/// Blocks on notifications and updates the shared cache.
+///
+/// # Arguments
+/// - shared_db: The db which sends notifications(e.g, LISTEN/NOTIFY of postgres).
+/// - channel: The name of the channel to listen.
+/// - next_payload: Waits for a next payload; `None` means the channel has been closed.
+/// - cache: The cache to be updated.
+///
+/// # Threading model
+///
+/// This function blocks until the channel is closed or an error occurs.
+/// Run it on a dedicated thread with its own connection and share the cache with readers.
+/// The write lock is taken only while a single payload is applied,
+/// so readers(e.g, [`get_or_skip_if_bucket_missing`]) are blocked only briefly.
+///
+/// Returns the number of applied payloads.
+pub fn listen_invalidations<D, N>(
+    shared_db: &mut D,
+    channel: &str,
+    next_payload: &mut N,
+    cache: Arc<RwLock<BTreeSet<Bucket>>>,
+) -> Result<u64, Event>
+where
+    N: FnMut(&mut D, &str) -> Result<Option<String>, Event>,
+{
+    let mut cnt: u64 = 0;
+    while let Some(payload) = next_payload(shared_db, channel)? {
+        apply_invalidation(&cache, payload.as_str())?;
+        cnt += 1;
+    }
+    Ok(cnt)
+}
+
+/// Updates the cache of buckets and adds the number of inserted buckets to the running total.
+///
+/// # Arguments
+/// - cache: The cache to be updated.
+/// - shared_db: The db which contains buckets.
+/// - list_buckets: Gets the list of buckets from the shared db.
+/// - running_total: The total number of inserted buckets(saturates at `u64::MAX`).
+pub fn update_cache_btree_accumulate<D, L>(
+    cache: &mut BTreeSet<Bucket>,
+    shared_db: &mut D,
+    list_buckets: &mut L,
+    running_total: &mut u64,
+) -> Result<u64, Event>
+where
+    L: FnMut(&mut D) -> Result<Vec<String>, Event>,
+{
+    let inserted: u64 = update_cache_btree(cache, shared_db, list_buckets)?;
+    *running_total = running_total.saturating_add(inserted);
+    Ok(inserted)
+}
+
+/// A bounded cache of buckets which evicts the oldest-inserted bucket when full.
+pub struct FifoCache {
+    capacity: usize,
+    order: VecDeque<Bucket>,
+    buckets: BTreeSet<Bucket>,
+}
+
+impl FifoCache {
+    /// Creates an empty cache which can hold up to `capacity` buckets.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            buckets: BTreeSet::new(),
+        }
+    }
+
+    /// Inserts the bucket; returns false if the bucket already exists.
+    ///
+    /// Re-inserting an existing bucket does not change its position.
+    pub fn insert(&mut self, b: Bucket) -> bool {
+        let exists: bool = self.buckets.contains(&b);
+        let insert: bool = !exists && 0 < self.capacity;
+        if insert {
+            if self.capacity <= self.order.len() {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            self.order.push_back(b.clone());
+            self.buckets.insert(b);
+        }
+        insert
+    }
+
+    /// Checks if the bucket exists.
+    pub fn contains(&self, b: &Bucket) -> bool {
+        self.buckets.contains(b)
+    }
+
+    /// Gets the number of buckets.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Checks if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Creates a closure which can be used as a cache of [`get_or_skip_if_bucket_missing`].
+    pub fn as_filter(&self) -> impl Fn(&Bucket) -> bool + '_ {
+        move |b: &Bucket| self.contains(b)
+    }
+}
+
+/// An approximate cache of buckets which hashes bucket names into bloom bits.
+///
+/// Uses far less memory than storing names, but answers [`ApproxCache::contains`] probabilistically:
+///
+/// - No false negatives: an inserted bucket is always reported(never skips a scan wrongly).
+/// - False positives: a bucket not inserted may be reported; it only causes an extra scan.
+///
+/// The false positive rate grows as bits are set; use a wider `N` for many buckets.
+pub struct ApproxCache<const N: usize> {
+    bits: BloomBits<N>,
+    hashes: u8,
+}
+
+impl<const N: usize> ApproxCache<N> {
+    /// Creates an empty cache which sets `hashes` bits for each bucket.
+    pub fn new(hashes: u8) -> Self {
+        Self {
+            bits: BloomBits::default(),
+            hashes,
+        }
+    }
+
+    /// Inserts the bucket.
+    pub fn insert(&mut self, b: &Bucket) {
+        let computed: BloomBits<N> = BloomBits::from_hash(b.as_str(), self.hashes);
+        self.bits = self.bits.or(&computed);
+    }
+
+    /// Checks if the bucket may exist.
+    pub fn contains(&self, b: &Bucket) -> bool {
+        let computed: BloomBits<N> = BloomBits::from_hash(b.as_str(), self.hashes);
+        matches!(self.bits.contains(&computed), BloomResult::MayExist)
+    }
+
+    /// Removes all buckets.
+    pub fn clear(&mut self) {
+        self.bits.clear()
+    }
+
+    /// Creates a closure which can be used as a cache of [`get_or_skip_if_bucket_missing`].
+    pub fn as_filter(&self) -> impl Fn(&Bucket) -> bool + '_ {
+        move |b: &Bucket| self.contains(b)
+    }
+}
+
+/// A cache of buckets which expire after a time-to-live.
+#[derive(Default)]
+pub struct TtlCache {
+    inserted: BTreeMap<Bucket, Instant>,
+}
+
+fn expired(inserted: Instant, now: Instant, ttl: Duration) -> bool {
+    ttl <= now.saturating_duration_since(inserted)
+}
+
+impl TtlCache {
+    /// Inserts(or refreshes) the bucket.
+    pub fn insert(&mut self, b: Bucket, now: Instant) {
+        self.inserted.insert(b, now);
+    }
+
+    /// Checks if the bucket exists and is not expired.
+    pub fn contains(&self, b: &Bucket, now: Instant, ttl: Duration) -> bool {
+        self.inserted
+            .get(b)
+            .map(|t| !expired(*t, now, ttl))
+            .unwrap_or(false)
+    }
+
+    /// Gets the number of buckets(including expired buckets not drained yet).
+    pub fn len(&self) -> usize {
+        self.inserted.len()
+    }
+
+    /// Checks if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty()
+    }
+
+    /// Removes the expired buckets and yields them(e.g, to drop the bloom bits of them).
+    pub fn drain_expired(&mut self, now: Instant, ttl: Duration) -> impl Iterator<Item = Bucket> {
+        let mut drained: Vec<Bucket> = vec![];
+        self.inserted.retain(|b, t| {
+            let keep: bool = !expired(*t, now, ttl);
+            if !keep {
+                drained.push(b.clone());
+            }
+            keep
+        });
+        drained.into_iter()
+    }
+
+    /// Creates a closure which can be used as a cache of [`get_or_skip_if_bucket_missing`].
+    pub fn as_filter(&self, now: Instant, ttl: Duration) -> impl Fn(&Bucket) -> bool + '_ {
+        move |b: &Bucket| self.contains(b, now, ttl)
+    }
+}
+
+/// Gets the buckets whose date(see [`Bucket::date_part`]) is within the last `days` days.
+///
+/// The window is `today - (days - 1)` to `today`(inclusive);
+/// buckets without a date or with a future date are excluded.
+///
+/// # Arguments
+/// - cache: The cached buckets.
+/// - today: The last day of the window(year, month, day).
+/// - days: The number of days of the window.
+pub fn recent_buckets(cache: &BTreeSet<Bucket>, today: (u16, u8, u8), days: u32) -> Vec<&Bucket> {
+    let last: i64 = days_from_civil(today);
+    let first: i64 = last - i64::from(days) + 1;
+    cache
+        .iter()
+        .filter(|b| {
+            b.date_part()
+                .map(|d| (first..=last).contains(&days_from_civil(d)))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Reads bucket names(one per line) and updates the cache of buckets.
+///
+/// Lines are trimmed; blank lines and lines starting with `#` are skipped.
+/// The cache is updated only if all names can be parsed as buckets(see `TryFrom` for [`Bucket`]).
+///
+/// # Arguments
+/// - cache: The cache to be updated.
+/// - reader: The reader of the newline-delimited bucket list.
+pub fn update_cache_from_reader<R>(cache: &mut BTreeSet<Bucket>, reader: R) -> Result<u64, Event>
+where
+    R: BufRead,
+{
+    let mut buckets: Vec<Bucket> = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line: String = line
+            .map_err(|e| Event::UnexpectedError(format!("Unable to read line {}: {}", i + 1, e)))?;
+        let name: &str = line.trim();
+        let skip: bool = name.is_empty() || name.starts_with('#');
+        if !skip {
+            let b: Bucket = Bucket::try_from(name).map_err(|e| {
+                Event::UnexpectedError(format!("Invalid bucket at line {}: {:?}", i + 1, e))
+            })?;
+            buckets.push(b);
+        }
+    }
+    cache.clear();
+    Ok(buckets.into_iter().fold(0, |tot, bucket| {
+        let inserted: bool = cache.insert(bucket);
+        inserted.then_some(1).map(|cnt| cnt + tot).unwrap_or(tot)
+    }))
+}
+
+/// Iterates the cached buckets in descending name order(e.g, the most recent date first).
+pub fn iter_desc(cache: &BTreeSet<Bucket>) -> impl Iterator<Item = &Bucket> {
+    cache.iter().rev()
+}
+
+/// An immutable cache of buckets which can be shared between threads without locks.
+///
+/// Clones share the sorted buckets.
+#[derive(Clone)]
+pub struct FrozenCache {
+    sorted: Arc<[Bucket]>,
+}
+
+impl FrozenCache {
+    /// Freezes the buckets.
+    pub fn from_set(cache: BTreeSet<Bucket>) -> Self {
+        let sorted: Vec<Bucket> = cache.into_iter().collect();
+        Self {
+            sorted: sorted.into(),
+        }
+    }
+
+    /// Gets the sorted buckets.
+    pub fn as_slice(&self) -> &[Bucket] {
+        &self.sorted
+    }
+
+    /// Checks if the bucket exists(binary search).
+    pub fn contains(&self, b: &Bucket) -> bool {
+        self.sorted.binary_search(b).is_ok()
+    }
+
+    /// Gets the number of buckets.
+    pub fn len(&self) -> usize {
+        self.sorted.len()
+    }
+
+    /// Checks if there is no bucket.
+    pub fn is_empty(&self) -> bool {
+        self.sorted.is_empty()
+    }
+
+    /// Creates a closure which can be used as a cache of [`get_or_skip_if_bucket_missing`].
+    pub fn as_filter(&self) -> impl Fn(&Bucket) -> bool + '_ {
+        move |b: &Bucket| self.contains(b)
+    }
+}
+
+#[derive(Default)]
+struct LruTtlEntries {
+    inserted: BTreeMap<Bucket, (Instant, u64)>,
+    recency: BTreeMap<u64, Bucket>,
+    tick: u64,
+}
+
+impl LruTtlEntries {
+    fn remove(&mut self, b: &Bucket) {
+        if let Some((_, used)) = self.inserted.remove(b) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn touch(&mut self, b: &Bucket) {
+        self.tick += 1;
+        let tick: u64 = self.tick;
+        if let Some((_, used)) = self.inserted.get_mut(b) {
+            self.recency.remove(used);
+            *used = tick;
+            self.recency.insert(tick, b.clone());
+        }
+    }
+}
+
+/// A bounded cache of buckets which expire after a time-to-live.
+///
+/// Expired buckets are absent(and removed on lookups);
+/// the least recently used bucket is evicted when full.
+pub struct LruTtlCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: RefCell<LruTtlEntries>,
+}
+
+impl LruTtlCache {
+    /// Creates an empty cache which can hold up to `capacity` buckets for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: RefCell::new(LruTtlEntries::default()),
+        }
+    }
+
+    /// Inserts(or refreshes) the bucket at `now`.
+    ///
+    /// Expired buckets are removed before evicting the least recently used bucket.
+    pub fn insert(&mut self, b: Bucket, now: Instant) {
+        let ttl: Duration = self.ttl;
+        let entries: &mut LruTtlEntries = self.entries.get_mut();
+        entries.remove(&b);
+        if 0 == self.capacity {
+            return;
+        }
+        if self.capacity <= entries.inserted.len() {
+            let expired_buckets: Vec<Bucket> = entries
+                .inserted
+                .iter()
+                .filter(|(_, (at, _))| expired(*at, now, ttl))
+                .map(|(b, _)| b.clone())
+                .collect();
+            for e in &expired_buckets {
+                entries.remove(e);
+            }
+        }
+        if self.capacity <= entries.inserted.len() {
+            let lru: Option<Bucket> = entries.recency.values().next().cloned();
+            if let Some(lru) = lru {
+                entries.remove(&lru);
+            }
+        }
+        entries.inserted.insert(b.clone(), (now, 0));
+        entries.touch(&b);
+    }
+
+    /// Checks if the bucket exists and is not expired at `now`; marks the bucket as used.
+    pub fn contains(&self, b: &Bucket, now: Instant) -> bool {
+        let mut entries = self.entries.borrow_mut();
+        let found: Option<bool> = entries
+            .inserted
+            .get(b)
+            .map(|(at, _)| !expired(*at, now, self.ttl));
+        match found {
+            None => false,
+            Some(false) => {
+                entries.remove(b);
+                false
+            }
+            Some(true) => {
+                entries.touch(b);
+                true
+            }
+        }
+    }
+
+    /// Gets the number of buckets(including expired buckets not removed yet).
+    pub fn len(&self) -> usize {
+        self.entries.borrow().inserted.len()
+    }
+
+    /// Checks if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+
+    /// Creates a closure which can be used as a cache of [`get_or_skip_if_bucket_missing`].
+    pub fn as_filter(&self, now: Instant) -> impl Fn(&Bucket) -> bool + '_ {
+        move |b: &Bucket| self.contains(b, now)
+    }
+}
+
+/// Converts the cached buckets to a sorted json array of names(e.g, to debug).
+///
+/// e.g, `["items_2022_12_27","items_2022_12_28"]`
+///
+/// Refuses names [`from_json`] would reject(names which fail [`Bucket::new_validated`]).
+pub fn to_json(cache: &BTreeSet<Bucket>) -> Result<String, Event> {
+    let names: Vec<String> = cache
+        .iter()
+        .map(|b| Bucket::try_from(b.as_str()).map(|valid| json_string(valid.as_str())))
+        .collect::<Result<_, _>>()?;
+    Ok(format!("[{}]", names.join(",")))
+}
+
+/// Parses a json array of bucket names(see [`to_json`]).
+///
+/// Each name is validated by [`Bucket::new_validated`].
+pub fn from_json(json: &str) -> Result<BTreeSet<Bucket>, Event> {
+    parse_json_strings(json)?
+        .into_iter()
+        .map(Bucket::try_from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test_cache {
+
+    mod invalidation {
+
+        use std::collections::BTreeSet;
+        use std::sync::{Arc, RwLock};
+
+        use crate::bucket::Bucket;
+        use crate::cache::{
+            apply_invalidation, listen_invalidations, parse_invalidation, Invalidation,
+        };
+        use crate::evt::Event;
+
+        #[test]
+        fn test_parse() {
+            assert_eq!(
+                parse_invalidation("+pg_database").unwrap(),
+                Invalidation::Add(Bucket::new_checked("pg_database".into())),
+            );
+            assert_eq!(
+                parse_invalidation("-pg_database").unwrap(),
+                Invalidation::Remove(Bucket::new_checked("pg_database".into())),
+            );
+            assert!(parse_invalidation("").is_err());
+            assert!(parse_invalidation("+").is_err());
+            assert!(parse_invalidation("pg_database").is_err());
+        }
+
+        #[test]
+        fn test_apply() {
+            let cache: RwLock<BTreeSet<Bucket>> = RwLock::new(BTreeSet::new());
+            assert!(apply_invalidation(&cache, "+pg_database").unwrap());
+            assert!(!apply_invalidation(&cache, "+pg_database").unwrap());
+            assert!(apply_invalidation(&cache, "+pg_class").unwrap());
+            assert_eq!(cache.read().unwrap().len(), 2);
+
+            assert!(apply_invalidation(&cache, "-pg_database").unwrap());
+            assert!(!apply_invalidation(&cache, "-pg_database").unwrap());
+            let guard = cache.read().unwrap();
+            assert!(guard.contains(&Bucket::new_checked("pg_class".into())));
+            assert!(!guard.contains(&Bucket::new_checked("pg_database".into())));
+        }
+
+        #[test]
+        fn test_listen() {
+            let cache: Arc<RwLock<BTreeSet<Bucket>>> = Arc::new(RwLock::new(BTreeSet::new()));
+            let mut queue: Vec<String> = vec![
+                "-pg_class".into(),
+                "+pg_class".into(),
+                "+pg_database".into(),
+            ];
+            let cnt: u64 = listen_invalidations(
+                &mut queue,
+                "buckets",
+                &mut |q: &mut Vec<String>, ch: &str| -> Result<Option<String>, Event> {
+                    assert_eq!(ch, "buckets");
+                    Ok(q.pop())
+                },
+                cache.clone(),
+            )
+            .unwrap();
+            assert_eq!(cnt, 3);
+            let guard = cache.read().unwrap();
+            assert_eq!(guard.len(), 1);
+            assert!(guard.contains(&Bucket::new_checked("pg_database".into())));
+        }
+    }
+
+    mod update_cache_btree_accumulate {
+
+        use std::collections::BTreeSet;
+
+        use crate::bucket::Bucket;
+        use crate::cache::update_cache_btree_accumulate;
+        use crate::evt::Event;
+
+        #[test]
+        fn test_accumulate() {
+            let mut cache: BTreeSet<Bucket> = BTreeSet::new();
+            let mut names: Vec<String> = vec!["pg_class".into(), "pg_database".into()];
+            let mut lister =
+                |names: &mut Vec<String>| -> Result<Vec<String>, Event> { Ok(names.clone()) };
+            let mut total: u64 = 0;
+
+            let cnt: u64 =
+                update_cache_btree_accumulate(&mut cache, &mut names, &mut lister, &mut total)
+                    .unwrap();
+            assert_eq!(cnt, 2);
+            assert_eq!(total, 2);
+
+            names.push("pg_type".into());
+            let cnt: u64 =
+                update_cache_btree_accumulate(&mut cache, &mut names, &mut lister, &mut total)
+                    .unwrap();
+            assert_eq!(cnt, 3);
+            assert_eq!(total, 5);
+            assert_eq!(cache.len(), 3);
+        }
+
+        #[test]
+        fn test_saturate() {
+            let mut cache: BTreeSet<Bucket> = BTreeSet::new();
+            let mut dummy: u8 = 0;
+            let mut total: u64 = u64::MAX - 1;
+            update_cache_btree_accumulate(
+                &mut cache,
+                &mut dummy,
+                &mut |_: &mut u8| Ok(vec!["pg_class".into(), "pg_database".into()]),
+                &mut total,
+            )
+            .unwrap();
+            assert_eq!(total, u64::MAX);
+        }
+    }
+
+    mod fifo_cache {
+
+        use crate::bucket::{b, Bucket};
+        use crate::cache::{get_or_skip_if_bucket_missing, FifoCache};
+
+        #[test]
+        fn test_evict_oldest() {
+            let mut cache = FifoCache::new(2);
+            assert!(cache.insert(b("b1")));
+            assert!(cache.insert(b("b2")));
+            assert!(cache.insert(b("b3")));
+            assert_eq!(cache.len(), 2);
+            assert!(!cache.contains(&b("b1")));
+            assert!(cache.contains(&b("b2")));
+            assert!(cache.contains(&b("b3")));
+        }
+
+        #[test]
+        fn test_reinsert() {
+            let mut cache = FifoCache::new(2);
+            assert!(cache.insert(b("b1")));
+            assert!(cache.insert(b("b2")));
+            assert!(!cache.insert(b("b1")));
+            assert!(cache.insert(b("b3")));
+            assert!(!cache.contains(&b("b1")));
+            assert!(cache.contains(&b("b2")));
+        }
+
+        #[test]
+        fn test_zero_capacity() {
+            let mut cache = FifoCache::new(0);
+            assert!(!cache.insert(b("b1")));
+            assert!(cache.is_empty());
+        }
+
+        #[test]
+        fn test_filter() {
+            let mut cache = FifoCache::new(2);
+            cache.insert(b("b1"));
+            let f = cache.as_filter();
+            let mut dummy: u8 = 0;
+            let mut getter = |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]);
+            let v: Vec<u8> =
+                get_or_skip_if_bucket_missing(&f, &mut dummy, &b("b1"), &mut getter, &()).unwrap();
+            assert_eq!(v, vec![1]);
+            let v: Vec<u8> =
+                get_or_skip_if_bucket_missing(&f, &mut dummy, &b("b2"), &mut getter, &()).unwrap();
+            assert_eq!(v, vec![]);
+        }
+    }
+
+    mod approx_cache {
+
+        use crate::bucket::Bucket;
+        use crate::cache::{get_or_skip_if_bucket_missing, ApproxCache};
+
+        fn bucket(i: u32) -> Bucket {
+            Bucket::new_checked(format!("bucket_2022_12_27_{:08x}", i))
+        }
+
+        #[test]
+        fn test_no_false_negatives() {
+            let mut cache: ApproxCache<8> = ApproxCache::new(3);
+            for i in 0..100 {
+                cache.insert(&bucket(i));
+            }
+            for i in 0..100 {
+                assert!(cache.contains(&bucket(i)));
+            }
+
+            cache.clear();
+            assert!(!cache.contains(&bucket(0)));
+        }
+
+        #[test]
+        fn test_false_positive_at_saturation() {
+            let mut cache: ApproxCache<1> = ApproxCache::new(3);
+            for i in 0..1000 {
+                cache.insert(&bucket(i));
+            }
+            let false_positives: usize =
+                (1000..2000).filter(|i| cache.contains(&bucket(*i))).count();
+            assert!(0 < false_positives);
+        }
+
+        #[test]
+        fn test_filter() {
+            let mut cache: ApproxCache<8> = ApproxCache::new(3);
+            cache.insert(&bucket(0));
+            let f = cache.as_filter();
+            let mut dummy: u8 = 0;
+            let v: Vec<u8> = get_or_skip_if_bucket_missing(
+                &f,
+                &mut dummy,
+                &bucket(0),
+                &mut |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]),
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![1]);
+        }
+    }
+
+    mod ttl_cache {
+
+        use std::time::{Duration, Instant};
+
+        use crate::bucket::{b, Bucket};
+        use crate::cache::TtlCache;
+
+        #[test]
+        fn test_drain_expired() {
+            let start: Instant = Instant::now();
+            let ttl: Duration = Duration::from_secs(60);
+            let mut cache = TtlCache::default();
+            cache.insert(b("b1"), start);
+            cache.insert(b("b2"), start + Duration::from_secs(30));
+            cache.insert(b("b3"), start + Duration::from_secs(90));
+
+            let now: Instant = start + Duration::from_secs(100);
+            assert!(!cache.contains(&b("b1"), now, ttl));
+            assert!(cache.contains(&b("b3"), now, ttl));
+            {
+                let f = cache.as_filter(now, ttl);
+                assert!(!f(&b("b2")));
+                assert!(f(&b("b3")));
+            }
+
+            let drained: Vec<Bucket> = cache.drain_expired(now, ttl).collect();
+            assert_eq!(drained, vec![b("b1"), b("b2")]);
+            assert_eq!(cache.len(), 1);
+            assert!(cache.contains(&b("b3"), now, ttl));
+
+            assert_eq!(cache.drain_expired(now, ttl).count(), 0);
+            let later: Vec<Bucket> = cache
+                .drain_expired(start + Duration::from_secs(150), ttl)
+                .collect();
+            assert_eq!(later, vec![b("b3")]);
+            assert!(cache.is_empty());
+        }
+    }
+
+    mod recent_buckets {
+
+        use std::collections::BTreeSet;
+
+        use crate::bucket::Bucket;
+        use crate::cache::recent_buckets;
+
+        fn cache() -> BTreeSet<Bucket> {
+            [
+                "bucket_2022_11_30_cafef00d",
+                "bucket_2022_12_01_cafef00d",
+                "bucket_2022_12_27_cafef00d",
+                "bucket_2022_12_31_cafef00d",
+                "bucket_2023_01_01_cafef00d",
+                "bucket_2023_01_02_cafef00d",
+                "bucket_2023_01_03_cafef00d",
+                "pg_database",
+            ]
+            .into_iter()
+            .map(|n| Bucket::new_checked(n.into()))
+            .collect()
+        }
+
+        fn names(v: Vec<&Bucket>) -> Vec<&str> {
+            v.into_iter().map(|b| b.as_str()).collect()
+        }
+
+        #[test]
+        fn test_year_boundary() {
+            let c = cache();
+            assert_eq!(
+                names(recent_buckets(&c, (2023, 1, 2), 3)),
+                vec![
+                    "bucket_2022_12_31_cafef00d",
+                    "bucket_2023_01_01_cafef00d",
+                    "bucket_2023_01_02_cafef00d",
+                ]
+            );
+        }
+
+        #[test]
+        fn test_month_boundary() {
+            let c = cache();
+            assert_eq!(
+                names(recent_buckets(&c, (2022, 12, 1), 2)),
+                vec!["bucket_2022_11_30_cafef00d", "bucket_2022_12_01_cafef00d"]
+            );
+            assert_eq!(
+                names(recent_buckets(&c, (2022, 12, 27), 27)),
+                vec!["bucket_2022_12_01_cafef00d", "bucket_2022_12_27_cafef00d"]
+            );
+        }
+
+        #[test]
+        fn test_empty_window() {
+            let c = cache();
+            assert!(recent_buckets(&c, (2023, 1, 2), 0).is_empty());
+            assert!(recent_buckets(&c, (2021, 1, 1), 30).is_empty());
+        }
+    }
+
+    mod update_cache_from_reader {
+
+        use std::collections::BTreeSet;
+        use std::io::Cursor;
+
+        use crate::bucket::Bucket;
+        use crate::cache::update_cache_from_reader;
+        use crate::evt::Event;
+
+        #[test]
+        fn test_read() {
+            let list: &str = r#"
+# buckets of 2022-12-27
+bucket_2022_12_27_cafef00ddeadbeafface864299792458
+
+  bucket_2022_12_27_dafef00ddeadbeafface864299792458  
+    # duplicated
+bucket_2022_12_27_cafef00ddeadbeafface864299792458
+"#;
+            let mut cache: BTreeSet<Bucket> = BTreeSet::new();
+            cache.insert(Bucket::new_checked("stale".into()));
+            let cnt: u64 = update_cache_from_reader(&mut cache, Cursor::new(list)).unwrap();
+            assert_eq!(cnt, 2);
+            assert_eq!(cache.len(), 2);
+            assert!(cache.contains(&Bucket::new_checked(
+                "bucket_2022_12_27_dafef00ddeadbeafface864299792458".into()
+            )));
+        }
+
+        #[test]
+        fn test_invalid() {
+            let list: &str = "pg_database\npg class\n";
+            let mut cache: BTreeSet<Bucket> = BTreeSet::new();
+            cache.insert(Bucket::new_checked("pg_type".into()));
+            match update_cache_from_reader(&mut cache, Cursor::new(list)) {
+                Err(Event::UnexpectedError(msg)) => assert!(msg.contains("line 2")),
+                _ => panic!("must be an error"),
+            }
+            assert_eq!(cache.len(), 1);
+        }
+
+        #[test]
+        fn test_separators() {
+            let list: &str = "items.2022.12.27\nitems-v2-dead\n";
+            let mut cache: BTreeSet<Bucket> = BTreeSet::new();
+            assert_eq!(
+                update_cache_from_reader(&mut cache, Cursor::new(list)).unwrap(),
+                2
+            );
+            for name in ["items.2022.12.27", "items-v2-dead"] {
+                assert!(cache.contains(&name.parse::<Bucket>().unwrap()));
+            }
+        }
+    }
+
+    mod iter_desc {
+
+        use std::collections::BTreeSet;
+
+        use crate::bucket::Bucket;
+        use crate::cache::iter_desc;
+
+        #[test]
+        fn test_desc() {
+            let cache: BTreeSet<Bucket> =
+                ["items_2022_12_28", "items_2022_12_27", "items_2022_12_29"]
+                    .into_iter()
+                    .map(|n| Bucket::new_checked(n.into()))
+                    .collect();
+            let names: Vec<&str> = iter_desc(&cache).map(|b| b.as_str()).collect();
+            assert_eq!(
+                names,
+                vec!["items_2022_12_29", "items_2022_12_28", "items_2022_12_27"]
+            );
+            assert_eq!(iter_desc(&BTreeSet::new()).count(), 0);
+        }
+    }
+
+    mod frozen_cache {
+
+        use std::collections::BTreeSet;
+        use std::thread;
+
+        use crate::bucket::{b, Bucket};
+        use crate::cache::FrozenCache;
+
+        #[test]
+        fn test_contains() {
+            let set: BTreeSet<Bucket> = [
+                b("items_2022_12_29"),
+                b("items_2022_12_27"),
+                b("items_2022_12_28"),
+            ]
+            .into();
+            let frozen = FrozenCache::from_set(set);
+            assert_eq!(frozen.len(), 3);
+            assert!(frozen.contains(&b("items_2022_12_27")));
+            assert!(frozen.contains(&b("items_2022_12_29")));
+            assert!(!frozen.contains(&b("items_2022_12_26")));
+            assert!(!frozen.contains(&b("items_2022_12_30")));
+            assert!(FrozenCache::from_set(BTreeSet::new()).is_empty());
+        }
+
+        #[test]
+        fn test_clone_shares() {
+            let frozen = FrozenCache::from_set([b("items_2022_12_27")].into());
+            let cloned: FrozenCache = frozen.clone();
+            assert!(std::ptr::eq(frozen.as_slice(), cloned.as_slice()));
+
+            let found: bool = thread::spawn(move || cloned.contains(&b("items_2022_12_27")))
+                .join()
+                .unwrap();
+            assert!(found);
+        }
+    }
+
+    mod get_or_skip_if_bucket_missing_with_policy {
+
+        use crate::bloom::EmptyPolicy;
+        use crate::bucket::Bucket;
+        use crate::cache::get_or_skip_if_bucket_missing_with_policy;
+        use crate::evt::Event;
+
+        fn get(policy: EmptyPolicy, exists: bool) -> Result<Option<Vec<u8>>, Event> {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            get_or_skip_if_bucket_missing_with_policy(
+                policy,
+                &|_: &Bucket| exists,
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, _: &()| Ok(vec![42]),
+                &(),
+            )
+        }
+
+        #[test]
+        fn test_policies() {
+            assert_eq!(get(EmptyPolicy::EmptyVec, false).unwrap(), Some(vec![]));
+            assert!(get(EmptyPolicy::Error, false).is_err());
+            assert_eq!(get(EmptyPolicy::None, false).unwrap(), None);
+            assert_eq!(get(EmptyPolicy::None, true).unwrap(), Some(vec![42]));
+        }
+    }
+
+    mod lru_ttl_cache {
+
+        use std::time::{Duration, Instant};
+
+        use crate::bucket::b;
+        use crate::cache::LruTtlCache;
+
+        #[test]
+        fn test_capacity() {
+            let t0: Instant = Instant::now();
+            let mut cache = LruTtlCache::new(2, Duration::from_secs(60));
+            cache.insert(b("b1"), t0);
+            cache.insert(b("b2"), t0);
+            assert!(cache.contains(&b("b1"), t0));
+            cache.insert(b("b3"), t0);
+            assert_eq!(cache.len(), 2);
+            assert!(cache.contains(&b("b1"), t0));
+            assert!(!cache.contains(&b("b2"), t0));
+            assert!(cache.contains(&b("b3"), t0));
+        }
+
+        #[test]
+        fn test_expiry() {
+            let t0: Instant = Instant::now();
+            let mut cache = LruTtlCache::new(2, Duration::from_secs(60));
+            cache.insert(b("b1"), t0);
+            assert!(cache.contains(&b("b1"), t0 + Duration::from_secs(59)));
+            {
+                let filter = cache.as_filter(t0 + Duration::from_secs(60));
+                assert!(!filter(&b("b1")));
+            }
+            assert!(cache.is_empty());
+        }
+
+        #[test]
+        fn test_expired_frees_slot() {
+            let t0: Instant = Instant::now();
+            let mut cache = LruTtlCache::new(2, Duration::from_secs(60));
+            cache.insert(b("b1"), t0);
+            cache.insert(b("b2"), t0 + Duration::from_secs(30));
+            let t1: Instant = t0 + Duration::from_secs(45);
+            assert!(cache.contains(&b("b1"), t1));
+
+            let t2: Instant = t0 + Duration::from_secs(70);
+            cache.insert(b("b3"), t2);
+            assert_eq!(cache.len(), 2);
+            assert!(cache.contains(&b("b2"), t2));
+            assert!(cache.contains(&b("b3"), t2));
+            assert!(!cache.contains(&b("b1"), t2));
+        }
+    }
+
+    mod json {
+
+        use std::collections::BTreeSet;
+
+        use crate::bucket::Bucket;
+        use crate::cache::{from_json, to_json};
+        use crate::state::parse_json_strings;
+
+        #[test]
+        fn test_round_trip() {
+            let cache: BTreeSet<Bucket> = ["items_2022_12_28", "items_2022_12_27", "pg_database"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let json: String = to_json(&cache).unwrap();
+            assert_eq!(
+                json,
+                r#"["items_2022_12_27","items_2022_12_28","pg_database"]"#
+            );
+            assert_eq!(from_json(&json).unwrap(), cache);
+
+            let separated: BTreeSet<Bucket> = ["items-v2-dead", "items.2022.12.27", "_items"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            assert_eq!(from_json(&to_json(&separated).unwrap()).unwrap(), separated);
+            assert!(from_json(&to_json(&BTreeSet::new()).unwrap())
+                .unwrap()
+                .is_empty());
+        }
+
+        #[test]
+        fn test_unencodable() {
+            for name in ["", "1_items", "items \"x\"", "items\n"] {
+                let cache: BTreeSet<Bucket> = BTreeSet::from([Bucket::new_checked(name.into())]);
+                assert!(to_json(&cache).is_err(), "{:?}", name);
+            }
+        }
+
+        #[test]
+        fn test_whitespace_and_escapes() {
+            let parsed: BTreeSet<Bucket> =
+                from_json(" [ \"items_\\u0032022\" ,\n\"pg\\u005fdatabase\" ] ").unwrap();
+            let names: Vec<&str> = parsed.iter().map(|b| b.as_str()).collect();
+            assert_eq!(names, vec!["items_2022", "pg_database"]);
+
+            let emoji: Vec<String> = parse_json_strings(r#"["\ud83d\ude00"]"#).unwrap();
+            assert_eq!(emoji, vec!["\u{1f600}"]);
+        }
+
+        #[test]
+        fn test_invalid() {
+            let invalid: Vec<&str> = vec![
+                "",
+                "{}",
+                r#"["items_2022_12_27""#,
+                r#"["items_2022_12_27",]"#,
+                r#"["items_2022_12_27"] x"#,
+                r#"[items_2022_12_27]"#,
+                r#"["items\q"]"#,
+                r#"[""]"#,
+                r#"["items\u0000"]"#,
+                r#"["items\n"]"#,
+                r#"["1_items"]"#,
+                r#"["items \"x\""]"#,
+                r#"["items\ud83d"]"#,
+                r#"["items\ud83d\u0041"]"#,
+            ];
+            for json in invalid {
+                assert!(from_json(json).is_err(), "{}", json);
+            }
+        }
+    }
+}
@@ -3,4 +3,71 @@
 pub enum Event {
     UnexpectedError(String),
     UnableToConnect(String),
+    QueryFailed(String),
+    NotFound(String),
+    Timeout(String),
+    FilterFailed(String),
+}
+
+/// A list of stable error codes to classify events without matching messages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    /// Unable to connect to a db.
+    Connection,
+
+    /// A query failed.
+    Query,
+
+    /// A bucket or a value was not found.
+    NotFound,
+
+    /// An operation timed out.
+    Timeout,
+
+    /// A filter failed.
+    Filter,
+
+    /// Other errors.
+    Unexpected,
+}
+
+impl Event {
+    /// Gets the error code of this event.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnexpectedError(_) => ErrorCode::Unexpected,
+            Self::UnableToConnect(_) => ErrorCode::Connection,
+            Self::QueryFailed(_) => ErrorCode::Query,
+            Self::NotFound(_) => ErrorCode::NotFound,
+            Self::Timeout(_) => ErrorCode::Timeout,
+            Self::FilterFailed(_) => ErrorCode::Filter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_evt {
+
+    mod code {
+
+        use crate::evt::{ErrorCode, Event};
+
+        #[test]
+        fn test_variants() {
+            let cases: Vec<(Event, ErrorCode)> = vec![
+                (Event::UnexpectedError("bug".into()), ErrorCode::Unexpected),
+                (
+                    Event::UnableToConnect("postgres".into()),
+                    ErrorCode::Connection,
+                ),
+                (Event::QueryFailed("syntax".into()), ErrorCode::Query),
+                (Event::NotFound("items".into()), ErrorCode::NotFound),
+                (Event::Timeout("statement".into()), ErrorCode::Timeout),
+                (Event::FilterFailed("panicked".into()), ErrorCode::Filter),
+            ];
+            for (e, code) in cases {
+                assert_eq!(e.code(), code);
+            }
+        }
+    }
 }
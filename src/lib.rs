@@ -2,4 +2,10 @@ pub mod bloom;
 pub mod bucket;
 pub mod cache;
 pub mod evt;
+pub mod metrics;
+pub mod rng;
+pub mod state;
 pub mod sub;
+
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -0,0 +1,269 @@
+//! Collects metrics of scans.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Observed scan durations grouped by row count(powers of two).
+///
+/// Row counts are grouped as `0`, `1`, `2..=3`, `4..=7`, `8..=15`, ...
+#[derive(Default)]
+pub struct CostHistogram {
+    observed: BTreeMap<u32, Vec<Duration>>,
+}
+
+fn row_group(rows: u64) -> u32 {
+    u64::BITS - rows.leading_zeros()
+}
+
+impl CostHistogram {
+    /// Records the duration of a scan which returned `rows` rows.
+    pub fn record(&mut self, rows: u64, dur: Duration) {
+        self.observed.entry(row_group(rows)).or_default().push(dur);
+    }
+
+    /// Gets the number of observations in the group of `rows`.
+    pub fn count(&self, rows: u64) -> usize {
+        self.observed
+            .get(&row_group(rows))
+            .map(|v| v.len())
+            .unwrap_or_default()
+    }
+
+    /// Gets the percentile(nearest rank) of durations in the group of `rows`.
+    ///
+    /// Returns `None` if nothing has been recorded in the group.
+    pub fn percentile(&self, rows: u64, p: f64) -> Option<Duration> {
+        let observed: &Vec<Duration> = self.observed.get(&row_group(rows))?;
+        let mut sorted: Vec<Duration> = observed.clone();
+        sorted.sort();
+        let rank: usize = ((p / 100.0) * (sorted.len() as f64)).ceil() as usize;
+        let ix: usize = rank.clamp(1, sorted.len()) - 1;
+        sorted.get(ix).copied()
+    }
+
+    /// Gets the median duration in the group of `rows`.
+    pub fn p50(&self, rows: u64) -> Option<Duration> {
+        self.percentile(rows, 50.0)
+    }
+
+    /// Gets the 95th percentile duration in the group of `rows`.
+    pub fn p95(&self, rows: u64) -> Option<Duration> {
+        self.percentile(rows, 95.0)
+    }
+}
+
+/// Counters of filter decisions which can be shared between scanning threads.
+#[derive(Default)]
+pub struct FilterMetrics {
+    cache_skipped: AtomicU64,
+    bloom_skipped: AtomicU64,
+    scanned: AtomicU64,
+    rows: AtomicU64,
+}
+
+/// The values of [`FilterMetrics`] counters at a point in time(or the difference of two points).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// The number of buckets skipped by the cache.
+    pub cache_skipped: u64,
+
+    /// The number of buckets skipped by the bloom filter.
+    pub bloom_skipped: u64,
+
+    /// The number of scanned buckets.
+    pub scanned: u64,
+
+    /// The number of rows got from the scanned buckets.
+    pub rows: u64,
+}
+
+impl FilterMetrics {
+    /// Counts a bucket skipped by the cache.
+    pub fn record_cache_skip(&self) {
+        self.cache_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a bucket skipped by the bloom filter.
+    pub fn record_bloom_skip(&self) {
+        self.bloom_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a scanned bucket and its rows.
+    pub fn record_scan(&self, rows: u64) {
+        self.scanned.fetch_add(1, Ordering::Relaxed);
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    /// Gets the current values of the counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            cache_skipped: self.cache_skipped.load(Ordering::Relaxed),
+            bloom_skipped: self.bloom_skipped.load(Ordering::Relaxed),
+            scanned: self.scanned.load(Ordering::Relaxed),
+            rows: self.rows.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    ///
+    /// e.g, `bucketfilter_bloom_skipped_total 3` for the prefix `bucketfilter`
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let snapshot: MetricsSnapshot = self.snapshot();
+        let counters: [(&str, &str, u64); 4] = [
+            (
+                "cache_skipped_total",
+                "Buckets skipped by the cache.",
+                snapshot.cache_skipped,
+            ),
+            (
+                "bloom_skipped_total",
+                "Buckets skipped by the bloom filter.",
+                snapshot.bloom_skipped,
+            ),
+            ("scanned_total", "Scanned buckets.", snapshot.scanned),
+            (
+                "rows_total",
+                "Rows got from scanned buckets.",
+                snapshot.rows,
+            ),
+        ];
+        counters
+            .iter()
+            .map(|(name, help, value)| {
+                let metric: String = match prefix.is_empty() {
+                    true => name.to_string(),
+                    false => format!("{}_{}", prefix, name),
+                };
+                format!(
+                    "# HELP {m} {h}\n# TYPE {m} counter\n{m} {v}\n",
+                    m = metric,
+                    h = help,
+                    v = value,
+                )
+            })
+            .collect()
+    }
+}
+
+impl MetricsSnapshot {
+    /// Computes the activity between the earlier snapshot and this snapshot.
+    pub fn delta(&self, earlier: &MetricsSnapshot) -> MetricsSnapshot {
+        MetricsSnapshot {
+            cache_skipped: self.cache_skipped.saturating_sub(earlier.cache_skipped),
+            bloom_skipped: self.bloom_skipped.saturating_sub(earlier.bloom_skipped),
+            scanned: self.scanned.saturating_sub(earlier.scanned),
+            rows: self.rows.saturating_sub(earlier.rows),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+
+    mod cost_histogram {
+
+        use std::time::Duration;
+
+        use crate::metrics::CostHistogram;
+
+        #[test]
+        fn test_percentiles() {
+            let mut h = CostHistogram::default();
+            for ms in 1..=20 {
+                h.record(100, Duration::from_millis(ms));
+            }
+            h.record(0, Duration::from_millis(500));
+
+            assert_eq!(h.count(64), 20);
+            assert_eq!(h.count(127), 20);
+            assert_eq!(h.p50(100), Some(Duration::from_millis(10)));
+            assert_eq!(h.p95(100), Some(Duration::from_millis(19)));
+            assert_eq!(h.percentile(100, 100.0), Some(Duration::from_millis(20)));
+            assert_eq!(h.percentile(100, 0.0), Some(Duration::from_millis(1)));
+
+            assert_eq!(h.p50(0), Some(Duration::from_millis(500)));
+            assert_eq!(h.p95(0), Some(Duration::from_millis(500)));
+        }
+
+        #[test]
+        fn test_groups() {
+            let mut h = CostHistogram::default();
+            h.record(1, Duration::from_millis(1));
+            h.record(2, Duration::from_millis(2));
+            h.record(3, Duration::from_millis(3));
+            assert_eq!(h.count(1), 1);
+            assert_eq!(h.count(2), 2);
+            assert_eq!(h.count(4), 0);
+            assert_eq!(h.p50(4), None);
+            assert_eq!(h.p50(2), Some(Duration::from_millis(2)));
+        }
+    }
+
+    mod filter_metrics {
+
+        use crate::metrics::{FilterMetrics, MetricsSnapshot};
+
+        #[test]
+        fn test_delta() {
+            let m = FilterMetrics::default();
+            m.record_cache_skip();
+            m.record_scan(10);
+            let earlier: MetricsSnapshot = m.snapshot();
+
+            m.record_cache_skip();
+            m.record_bloom_skip();
+            m.record_bloom_skip();
+            m.record_scan(3);
+            m.record_scan(4);
+            let later: MetricsSnapshot = m.snapshot();
+
+            assert_eq!(
+                later.delta(&earlier),
+                MetricsSnapshot {
+                    cache_skipped: 1,
+                    bloom_skipped: 2,
+                    scanned: 2,
+                    rows: 7,
+                }
+            );
+            assert_eq!(later.delta(&later), MetricsSnapshot::default());
+            assert_eq!(later.rows, 17);
+        }
+    }
+
+    mod to_prometheus {
+
+        use crate::metrics::FilterMetrics;
+
+        #[test]
+        fn test_lines() {
+            let m = FilterMetrics::default();
+            m.record_cache_skip();
+            m.record_bloom_skip();
+            m.record_bloom_skip();
+            m.record_scan(42);
+            let rendered: String = m.to_prometheus("bucketfilter");
+            let lines: Vec<&str> = rendered.lines().collect();
+            assert_eq!(
+                lines,
+                vec![
+                    "# HELP bucketfilter_cache_skipped_total Buckets skipped by the cache.",
+                    "# TYPE bucketfilter_cache_skipped_total counter",
+                    "bucketfilter_cache_skipped_total 1",
+                    "# HELP bucketfilter_bloom_skipped_total Buckets skipped by the bloom filter.",
+                    "# TYPE bucketfilter_bloom_skipped_total counter",
+                    "bucketfilter_bloom_skipped_total 2",
+                    "# HELP bucketfilter_scanned_total Scanned buckets.",
+                    "# TYPE bucketfilter_scanned_total counter",
+                    "bucketfilter_scanned_total 1",
+                    "# HELP bucketfilter_rows_total Rows got from scanned buckets.",
+                    "# TYPE bucketfilter_rows_total counter",
+                    "bucketfilter_rows_total 42",
+                ]
+            );
+            assert!(rendered.ends_with('\n'));
+            assert!(m.to_prometheus("").contains("\nscanned_total 1\n"));
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Sources of random numbers for sampling.
+
+/// Generates numbers used for sampling decisions.
+pub trait SampleSource {
+    /// Gets the next number in `[0.0, 1.0)`.
+    fn next_f64(&mut self) -> f64;
+}
+
+impl<F> SampleSource for F
+where
+    F: FnMut() -> f64,
+{
+    fn next_f64(&mut self) -> f64 {
+        self()
+    }
+}
+
+/// Returns the preset numbers in order(repeated from the first after the last).
+///
+/// Use this to get deterministic sampling decisions in tests.
+pub struct FixedSequence {
+    values: Vec<f64>,
+    next: usize,
+}
+
+impl FixedSequence {
+    /// Creates a sequence of the values; an empty sequence always returns 0.0.
+    pub fn new(values: Vec<f64>) -> Self {
+        Self { values, next: 0 }
+    }
+}
+
+impl SampleSource for FixedSequence {
+    fn next_f64(&mut self) -> f64 {
+        match self.values.get(self.next) {
+            None => 0.0,
+            Some(v) => {
+                self.next = (self.next + 1) % self.values.len();
+                *v
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_rng {
+
+    mod fixed_sequence {
+
+        use crate::rng::{FixedSequence, SampleSource};
+
+        #[test]
+        fn test_repeat() {
+            let mut seq = FixedSequence::new(vec![0.25, 0.75]);
+            let got: Vec<f64> = (0..5).map(|_| seq.next_f64()).collect();
+            assert_eq!(got, vec![0.25, 0.75, 0.25, 0.75, 0.25]);
+
+            let mut empty = FixedSequence::new(vec![]);
+            assert_eq!(empty.next_f64(), 0.0);
+        }
+    }
+}
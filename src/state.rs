@@ -0,0 +1,620 @@
+//! Records the state of the filters.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    bloom::{BloomBits256, BloomResult},
+    bucket::Bucket,
+    evt::Event,
+};
+
+/// The decisions made for a bucket during a scan.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// The checked bucket.
+    pub bucket: Bucket,
+
+    /// The cache verdict; `None` if not checked.
+    pub cache: Option<bool>,
+
+    /// The bloom verdict; `None` if not checked.
+    pub bloom: Option<BloomResult>,
+
+    /// The pushdown decision; `None` if not scanned.
+    pub pushdown: Option<bool>,
+
+    /// The number of rows got from the bucket.
+    pub rows: u64,
+}
+
+/// Records the decisions made for each bucket during a scan.
+#[derive(Default)]
+pub struct ScanTrace {
+    entries: Vec<TraceEntry>,
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut escaped: String = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn invalid_json(reason: &str) -> Event {
+    Event::UnexpectedError(format!("Invalid json: {}", reason))
+}
+
+fn skip_json_ws(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn parse_json_hex4(chars: &mut Peekable<Chars>) -> Result<u32, Event> {
+    let hex: String = chars.by_ref().take(4).collect();
+    match 4 == hex.len() {
+        true => u32::from_str_radix(&hex, 16).map_err(|_| invalid_json("invalid \\u escape")),
+        false => Err(invalid_json("incomplete \\u escape")),
+    }
+}
+
+fn parse_json_unicode(chars: &mut Peekable<Chars>) -> Result<char, Event> {
+    let high: u32 = parse_json_hex4(chars)?;
+    let code: u32 = match (0xd800..0xdc00).contains(&high) {
+        false => high,
+        true => {
+            let pair: bool = chars.next() == Some('\\') && chars.next() == Some('u');
+            let low: u32 = match pair {
+                true => parse_json_hex4(chars)?,
+                false => return Err(invalid_json("unpaired surrogate")),
+            };
+            match (0xdc00..0xe000).contains(&low) {
+                true => Ok(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)),
+                false => Err(invalid_json("unpaired surrogate")),
+            }?
+        }
+    };
+    char::from_u32(code).ok_or_else(|| invalid_json("invalid code point"))
+}
+
+fn parse_json_string(chars: &mut Peekable<Chars>) -> Result<String, Event> {
+    match chars.next() {
+        Some('"') => Ok(()),
+        _ => Err(invalid_json("string expected")),
+    }?;
+    let mut parsed: String = String::new();
+    loop {
+        let c: char = chars
+            .next()
+            .ok_or_else(|| invalid_json("unterminated string"))?;
+        match c {
+            '"' => return Ok(parsed),
+            '\\' => {
+                let escaped: char = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => parse_json_unicode(chars)?,
+                    _ => return Err(invalid_json("invalid escape")),
+                };
+                parsed.push(escaped);
+            }
+            c if c.is_control() => return Err(invalid_json("control character in string")),
+            c => parsed.push(c),
+        }
+    }
+}
+
+/// Parses a json array of strings(e.g, `["a", "b"]`).
+pub(crate) fn parse_json_strings(json: &str) -> Result<Vec<String>, Event> {
+    let mut chars: Peekable<Chars> = json.chars().peekable();
+    skip_json_ws(&mut chars);
+    match chars.next() {
+        Some('[') => Ok(()),
+        _ => Err(invalid_json("array expected")),
+    }?;
+    let mut strings: Vec<String> = vec![];
+    skip_json_ws(&mut chars);
+    if chars.next_if_eq(&']').is_none() {
+        loop {
+            skip_json_ws(&mut chars);
+            strings.push(parse_json_string(&mut chars)?);
+            skip_json_ws(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some(']') => break,
+                _ => return Err(invalid_json("',' or ']' expected")),
+            }
+        }
+    }
+    skip_json_ws(&mut chars);
+    match chars.next() {
+        None => Ok(strings),
+        Some(_) => Err(invalid_json("trailing characters")),
+    }
+}
+
+fn bloom_name(b: BloomResult) -> &'static str {
+    match b {
+        BloomResult::MayExist => "may_exist",
+        BloomResult::Missing => "missing",
+    }
+}
+
+fn json_opt<T>(o: Option<T>, f: fn(T) -> String) -> String {
+    o.map(f).unwrap_or_else(|| "null".into())
+}
+
+impl ScanTrace {
+    /// Records the entry.
+    pub fn record(&mut self, entry: TraceEntry) {
+        self.entries.push(entry)
+    }
+
+    /// Gets the recorded entries.
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    /// Converts the recorded entries to a json string.
+    ///
+    /// e.g, `{"entries":[{"bucket":"b1","cache":true,"bloom":"may_exist","pushdown":false,"rows":3}]}`
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|e| {
+                format!(
+                    r#"{{"bucket":{},"cache":{},"bloom":{},"pushdown":{},"rows":{}}}"#,
+                    json_string(e.bucket.as_str()),
+                    json_opt(e.cache, |c| c.to_string()),
+                    json_opt(e.bloom, |b| json_string(bloom_name(b))),
+                    json_opt(e.pushdown, |p| p.to_string()),
+                    e.rows,
+                )
+            })
+            .collect();
+        format!(r#"{{"entries":[{}]}}"#, entries.join(","))
+    }
+}
+
+/// Gets values from a slow db if both the cache and the bloom filter accept the bucket,
+/// and records the decisions.
+///
+/// # Arguments
+/// - trace: Records the decisions.
+/// - cache: Checks if a bucket exists.
+/// - bloom: Checks if values may exists or not.
+/// - pushdown: Checks if a remote filter must be used or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket(with the pushdown decision).
+/// - filter: The filter to get values.
+#[allow(clippy::too_many_arguments)]
+pub fn get_or_skip_traced<C, B, P, D, G, F, T>(
+    trace: &mut ScanTrace,
+    cache: &C,
+    bloom: &B,
+    pushdown: &P,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    C: Fn(&Bucket) -> bool,
+    B: Fn(&Bucket, &F) -> BloomResult,
+    P: Fn(&F) -> bool,
+    G: FnMut(&mut D, &Bucket, &F, bool) -> Result<Vec<T>, Event>,
+{
+    let mut entry = TraceEntry {
+        bucket: bucket.clone(),
+        cache: Some(cache(bucket)),
+        bloom: None,
+        pushdown: None,
+        rows: 0,
+    };
+    if entry.cache == Some(true) {
+        entry.bloom = Some(bloom(bucket, filter));
+    }
+    let found: Result<Vec<T>, Event> = match entry.bloom {
+        Some(BloomResult::MayExist) => {
+            let remote: bool = pushdown(filter);
+            entry.pushdown = Some(remote);
+            getter(shared_db, bucket, filter, remote)
+        }
+        _ => Ok(vec![]),
+    };
+    entry.rows = found.as_ref().map(|v| v.len() as u64).unwrap_or_default();
+    trace.record(entry);
+    found
+}
+
+/// The buckets known to only one of the cache and the bloom map.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CrossCheck {
+    /// The buckets which have bloom bits but are not cached.
+    pub bloom_only: Vec<Bucket>,
+
+    /// The cached buckets which have no bloom bits.
+    pub cache_only: Vec<Bucket>,
+}
+
+impl CrossCheck {
+    /// Checks if the cache and the bloom map agree on the buckets.
+    pub fn is_consistent(&self) -> bool {
+        self.bloom_only.is_empty() && self.cache_only.is_empty()
+    }
+}
+
+/// Compares the buckets of the cache and the bloom map(e.g, to detect refresh skew).
+///
+/// # Arguments
+/// - cache: The cached buckets.
+/// - bloom: The bloom bits for each bucket.
+pub fn cross_check(cache: &BTreeSet<Bucket>, bloom: &BTreeMap<Bucket, BloomBits256>) -> CrossCheck {
+    let bloom_only: Vec<Bucket> = bloom
+        .keys()
+        .filter(|b| !cache.contains(b))
+        .cloned()
+        .collect();
+    let cache_only: Vec<Bucket> = cache
+        .iter()
+        .filter(|b| !bloom.contains_key(b))
+        .cloned()
+        .collect();
+    CrossCheck {
+        bloom_only,
+        cache_only,
+    }
+}
+
+/// The buckets of the cache and the bloom bits of each bucket.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterState {
+    /// The cached buckets.
+    pub cache: BTreeSet<Bucket>,
+
+    /// The bloom bits for each bucket.
+    pub bloom: BTreeMap<Bucket, BloomBits256>,
+}
+
+/// The operations to transform a [`FilterState`] into another.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationPlan {
+    /// The buckets to be added to the cache.
+    pub cache_add: Vec<Bucket>,
+
+    /// The buckets to be removed from the cache.
+    pub cache_remove: Vec<Bucket>,
+
+    /// The bloom bits to be added.
+    pub bloom_add: Vec<(Bucket, BloomBits256)>,
+
+    /// The buckets whose bloom bits are to be removed.
+    pub bloom_remove: Vec<Bucket>,
+
+    /// The bloom bits to be replaced.
+    pub bloom_update: Vec<(Bucket, BloomBits256)>,
+}
+
+impl MigrationPlan {
+    /// Checks if there is nothing to do.
+    pub fn is_empty(&self) -> bool {
+        self.cache_add.is_empty()
+            && self.cache_remove.is_empty()
+            && self.bloom_add.is_empty()
+            && self.bloom_remove.is_empty()
+            && self.bloom_update.is_empty()
+    }
+}
+
+/// Computes the minimal operations to transform the old state into the new state.
+///
+/// # Arguments
+/// - old: The current state.
+/// - new: The desired state.
+pub fn plan_migration(old: &FilterState, new: &FilterState) -> MigrationPlan {
+    let cache_add: Vec<Bucket> = new.cache.difference(&old.cache).cloned().collect();
+    let cache_remove: Vec<Bucket> = old.cache.difference(&new.cache).cloned().collect();
+    let bloom_remove: Vec<Bucket> = old
+        .bloom
+        .keys()
+        .filter(|b| !new.bloom.contains_key(b))
+        .cloned()
+        .collect();
+    let mut bloom_add: Vec<(Bucket, BloomBits256)> = vec![];
+    let mut bloom_update: Vec<(Bucket, BloomBits256)> = vec![];
+    for (b, bits) in &new.bloom {
+        match old.bloom.get(b) {
+            None => bloom_add.push((b.clone(), *bits)),
+            Some(current) if current != bits => bloom_update.push((b.clone(), *bits)),
+            Some(_) => {}
+        }
+    }
+    MigrationPlan {
+        cache_add,
+        cache_remove,
+        bloom_add,
+        bloom_remove,
+        bloom_update,
+    }
+}
+
+/// A record of a scan decision to be stored(e.g, inserted into an audit table by the caller).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlanAuditRecord {
+    /// The checked bucket.
+    pub bucket: Bucket,
+
+    /// `cache_missing`, `may_exist`, `missing` or `unchecked`.
+    pub verdict: String,
+
+    /// True if a remote filter has been used.
+    pub pushdown: bool,
+
+    /// The unix time(seconds) of the decision.
+    pub timestamp: u64,
+}
+
+impl PlanAuditRecord {
+    /// Converts to a row(bucket, verdict, pushdown, timestamp).
+    pub fn to_row(&self) -> (String, String, bool, u64) {
+        (
+            self.bucket.as_str().into(),
+            self.verdict.clone(),
+            self.pushdown,
+            self.timestamp,
+        )
+    }
+}
+
+/// Creates an audit record of the decisions(see [`record_plan`]) made at the timestamp.
+pub fn record_plan_at(plan: &TraceEntry, timestamp: u64) -> PlanAuditRecord {
+    let verdict: &str = match (plan.cache, plan.bloom) {
+        (Some(false), _) => "cache_missing",
+        (_, Some(b)) => bloom_name(b),
+        (_, None) => "unchecked",
+    };
+    PlanAuditRecord {
+        bucket: plan.bucket.clone(),
+        verdict: verdict.into(),
+        pushdown: plan.pushdown.unwrap_or(false),
+        timestamp,
+    }
+}
+
+/// Creates an audit record of the decisions made for a bucket(timestamp: now).
+pub fn record_plan(plan: &TraceEntry) -> PlanAuditRecord {
+    let now: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    record_plan_at(plan, now)
+}
+
+#[cfg(test)]
+mod test_state {
+
+    mod scan_trace {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::state::{get_or_skip_traced, json_string, ScanTrace};
+
+        #[test]
+        fn test_json_string() {
+            assert_eq!(json_string("pg_database"), r#""pg_database""#);
+            assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+        }
+
+        #[test]
+        fn test_trace() {
+            let mut trace = ScanTrace::default();
+            let cache = |b: &Bucket| b.as_str() != "b3";
+            let bloom = |b: &Bucket, _: &u8| match b.as_str() {
+                "b2" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let pushdown = |f: &u8| 10 < *f;
+            let mut dummy: u8 = 0;
+            let mut getter = |_: &mut u8, _: &Bucket, _: &u8, remote: bool| match remote {
+                true => Ok(vec![1]),
+                false => Ok(vec![1, 2, 3]),
+            };
+            for name in ["b1", "b2", "b3"] {
+                get_or_skip_traced(
+                    &mut trace,
+                    &cache,
+                    &bloom,
+                    &pushdown,
+                    &mut dummy,
+                    &Bucket::new_checked(name.into()),
+                    &mut getter,
+                    &3,
+                )
+                .unwrap();
+            }
+            let v: Vec<u8> = get_or_skip_traced(
+                &mut trace,
+                &cache,
+                &bloom,
+                &pushdown,
+                &mut dummy,
+                &Bucket::new_checked("b4".into()),
+                &mut getter,
+                &42,
+            )
+            .unwrap();
+            assert_eq!(v, vec![1]);
+            assert_eq!(trace.entries().len(), 4);
+
+            let json: String = trace.to_json();
+            assert_eq!(
+                json,
+                concat!(
+                    r#"{"entries":["#,
+                    r#"{"bucket":"b1","cache":true,"bloom":"may_exist","pushdown":false,"rows":3},"#,
+                    r#"{"bucket":"b2","cache":true,"bloom":"missing","pushdown":null,"rows":0},"#,
+                    r#"{"bucket":"b3","cache":false,"bloom":null,"pushdown":null,"rows":0},"#,
+                    r#"{"bucket":"b4","cache":true,"bloom":"may_exist","pushdown":true,"rows":1}"#,
+                    r#"]}"#,
+                ),
+            );
+        }
+    }
+
+    mod cross_check {
+
+        use std::collections::{BTreeMap, BTreeSet};
+
+        use crate::bloom::BloomBits256;
+        use crate::bucket::{b, Bucket};
+        use crate::state::{cross_check, CrossCheck};
+
+        #[test]
+        fn test_mismatch() {
+            let cache: BTreeSet<Bucket> = [b("items_2022_12_26"), b("items_2022_12_27")].into();
+            let bloom: BTreeMap<Bucket, BloomBits256> = [
+                (b("items_2022_12_27"), BloomBits256::default()),
+                (b("items_2022_12_28"), BloomBits256::default()),
+                (b("items_2022_12_29"), BloomBits256::default()),
+            ]
+            .into();
+            let checked: CrossCheck = cross_check(&cache, &bloom);
+            assert_eq!(
+                checked.bloom_only,
+                vec![b("items_2022_12_28"), b("items_2022_12_29")]
+            );
+            assert_eq!(checked.cache_only, vec![b("items_2022_12_26")]);
+            assert!(!checked.is_consistent());
+        }
+
+        #[test]
+        fn test_consistent() {
+            let cache: BTreeSet<Bucket> = [b("items_2022_12_27")].into();
+            let bloom: BTreeMap<Bucket, BloomBits256> =
+                [(b("items_2022_12_27"), BloomBits256::default())].into();
+            assert!(cross_check(&cache, &bloom).is_consistent());
+        }
+    }
+
+    mod plan_migration {
+
+        use crate::bloom::BloomBits256;
+        use crate::bucket::b;
+        use crate::state::{plan_migration, FilterState, MigrationPlan};
+
+        fn bits(w: u128) -> BloomBits256 {
+            BloomBits256::new([w, 0])
+        }
+
+        #[test]
+        fn test_plan() {
+            let old = FilterState {
+                cache: [b("items_2022_12_26"), b("items_2022_12_27")].into(),
+                bloom: [
+                    (b("items_2022_12_26"), bits(1)),
+                    (b("items_2022_12_27"), bits(2)),
+                ]
+                .into(),
+            };
+            let new = FilterState {
+                cache: [b("items_2022_12_27"), b("items_2022_12_28")].into(),
+                bloom: [
+                    (b("items_2022_12_27"), bits(3)),
+                    (b("items_2022_12_28"), bits(4)),
+                ]
+                .into(),
+            };
+            let plan: MigrationPlan = plan_migration(&old, &new);
+            assert_eq!(
+                plan,
+                MigrationPlan {
+                    cache_add: vec![b("items_2022_12_28")],
+                    cache_remove: vec![b("items_2022_12_26")],
+                    bloom_add: vec![(b("items_2022_12_28"), bits(4))],
+                    bloom_remove: vec![b("items_2022_12_26")],
+                    bloom_update: vec![(b("items_2022_12_27"), bits(3))],
+                }
+            );
+            assert!(!plan.is_empty());
+        }
+
+        #[test]
+        fn test_same() {
+            let state = FilterState {
+                cache: [b("items_2022_12_27")].into(),
+                bloom: [(b("items_2022_12_27"), bits(2))].into(),
+            };
+            assert!(plan_migration(&state, &state.clone()).is_empty());
+            assert!(plan_migration(&FilterState::default(), &FilterState::default()).is_empty());
+        }
+    }
+
+    mod plan_audit_record {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::state::{record_plan, record_plan_at, PlanAuditRecord, TraceEntry};
+
+        fn entry(
+            cache: Option<bool>,
+            bloom: Option<BloomResult>,
+            pushdown: Option<bool>,
+        ) -> TraceEntry {
+            TraceEntry {
+                bucket: Bucket::new_checked("items_2022_12_27".into()),
+                cache,
+                bloom,
+                pushdown,
+                rows: 3,
+            }
+        }
+
+        #[test]
+        fn test_row() {
+            let plan = entry(Some(true), Some(BloomResult::MayExist), Some(true));
+            let record: PlanAuditRecord = record_plan_at(&plan, 1672099200);
+            assert_eq!(
+                record.to_row(),
+                (
+                    "items_2022_12_27".into(),
+                    "may_exist".into(),
+                    true,
+                    1672099200
+                )
+            );
+        }
+
+        #[test]
+        fn test_verdicts() {
+            let verdict = |plan: TraceEntry| record_plan_at(&plan, 0).verdict;
+            assert_eq!(verdict(entry(Some(false), None, None)), "cache_missing");
+            assert_eq!(
+                verdict(entry(None, Some(BloomResult::Missing), None)),
+                "missing"
+            );
+            assert_eq!(verdict(entry(None, None, Some(false))), "unchecked");
+            assert!(!record_plan_at(&entry(None, None, None), 0).pushdown);
+            assert!(0 < record_plan(&entry(None, None, None)).timestamp);
+        }
+    }
+}
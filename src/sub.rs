@@ -1,4 +1,28 @@
-use crate::{bucket::Bucket, evt::Event};
+//! Scans buckets and their sub buckets.
+//!
+//! Scan helpers which return a flat `Vec` concatenate values strictly in the order of the input
+//! buckets(and of the values got from each bucket); see [`scan_ordered`].
+//! The exception is [`scan_prioritized`], which concatenates them in the order of priority.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Display;
+use std::hash::Hash;
+#[cfg(feature = "csv")]
+use std::io::ErrorKind;
+use std::panic::{catch_unwind, RefUnwindSafe};
+#[cfg(feature = "csv")]
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    bloom::{get_or_skip_if_missing, BloomResult},
+    bucket::{validate_identifier, Bucket},
+    evt::Event,
+    rng::SampleSource,
+};
 
 /// Gets sub buckets from a db and gets some of them.
 ///
@@ -76,6 +100,8 @@ where
 
 /// Creates a closure which checks if a remote filter must be used or not.
 ///
+/// The inputs are not validated; use [`pushdown_by_storage_checked_new`] for untrusted estimates.
+///
 /// # Arguments
 /// - estimate_ix_scan: Gets number of index scans.
 /// - estimate_sq_scan: Gets number of sequential scans.
@@ -100,244 +126,3912 @@ where
     }
 }
 
-#[cfg(test)]
-mod test_sub {
-
-    mod get_sub_buckets {
+/// Creates a closure which checks if a remote filter must be used or not; validates the costs.
+///
+/// Same as [`pushdown_by_storage_new`] but returns `default` if any cost or estimate is
+/// `NaN`/negative or a computed cost is not finite(e.g, overflowed to `inf`).
+/// Use `false`(no remote filter; full scan) as the default to fail toward full scans.
+///
+/// # Arguments
+/// - estimate_ix_scan: Gets number of index scans.
+/// - estimate_sq_scan: Gets number of sequential scans.
+/// - ix_scan_cost: The cost to get a row(random scan).
+/// - sq_scan_cost: The cost to get a row(sequential scan).
+/// - default: The decision used for invalid inputs.
+pub fn pushdown_by_storage_checked_new<C, I, R>(
+    estimate_ix_scan: I,
+    estimate_sq_scan: R,
+    ix_scan_cost: f32,
+    sq_scan_cost: f32,
+    default: bool,
+) -> impl Fn(&C) -> bool
+where
+    I: Fn(&C) -> f32,
+    R: Fn(&C) -> f32,
+{
+    let valid_input = |v: f32| !v.is_nan() && 0.0 <= v;
+    move |filter_cfg: &C| {
+        let ix_estimate: f32 = estimate_ix_scan(filter_cfg);
+        let sq_estimate: f32 = estimate_sq_scan(filter_cfg);
+        let valid_inputs: bool = [ix_scan_cost, sq_scan_cost, ix_estimate, sq_estimate]
+            .into_iter()
+            .all(valid_input);
+        let ix_cost: f32 = ix_scan_cost * ix_estimate;
+        let sq_cost: f32 = sq_scan_cost * sq_estimate;
+        let valid: bool = valid_inputs && ix_cost.is_finite() && sq_cost.is_finite();
+        match valid {
+            true => ix_cost <= sq_cost,
+            false => default,
+        }
+    }
+}
 
-        use crate::bucket::Bucket;
-        use crate::sub::get_sub_buckets;
+/// Scans buckets in order and stops once the deadline passes.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+/// - deadline: No more buckets will be scanned after this instant.
+///
+/// # Returns
+/// The values gathered so far and whether all buckets were scanned.
+pub fn scan_until_deadline<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+    deadline: Instant,
+) -> Result<(Vec<T>, bool), Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut expired = || deadline < Instant::now();
+    scan_until_expired(bloom, shared_db, buckets, getter, filter, &mut expired)
+}
 
-        #[derive(PartialEq, Eq, Debug)]
-        struct SubBucket {
-            id: u16,
-            data: Vec<u8>,
+/// Scans buckets in order and stops once `expired` says so(see [`scan_until_deadline`]).
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+/// - expired: Checked before each bucket; no more buckets will be scanned once it returns true.
+///
+/// # Returns
+/// The values gathered so far and whether all buckets were scanned.
+pub fn scan_until_expired<B, D, G, F, T, E>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+    expired: &mut E,
+) -> Result<(Vec<T>, bool), Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    E: FnMut() -> bool,
+{
+    let mut gathered: Vec<T> = vec![];
+    for b in buckets {
+        if expired() {
+            return Ok((gathered, false));
         }
+        let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+        gathered.extend(found);
+    }
+    Ok((gathered, true))
+}
 
-        #[derive(Default)]
-        struct Filter {
-            id_lbi: u16,
-            id_ubi: u16,
-        }
+/// Creates a closure which uses a remote filter only if both predicates say so.
+///
+/// # Arguments
+/// - a: Checks if a remote filter must be used or not.
+/// - b: Checks if a remote filter must be used or not.
+pub fn pushdown_and<C, A, B>(a: A, b: B) -> impl Fn(&C) -> bool
+where
+    A: Fn(&C) -> bool,
+    B: Fn(&C) -> bool,
+{
+    move |filter_cfg: &C| a(filter_cfg) && b(filter_cfg)
+}
 
-        #[test]
-        fn test_empty() {
-            let mut dummy: u8 = 0;
-            let b: Bucket =
-                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+/// Creates a closure which uses a remote filter if any of predicates say so.
+///
+/// # Arguments
+/// - a: Checks if a remote filter must be used or not.
+/// - b: Checks if a remote filter must be used or not.
+pub fn pushdown_or<C, A, B>(a: A, b: B) -> impl Fn(&C) -> bool
+where
+    A: Fn(&C) -> bool,
+    B: Fn(&C) -> bool,
+{
+    move |filter_cfg: &C| a(filter_cfg) || b(filter_cfg)
+}
 
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
-                &mut |v: Vec<SubBucket>, _: &Filter| v,
-                &Filter::default(),
-                false,
-                false,
-            )
-            .unwrap();
-            assert_eq!(v, vec![]);
+/// Creates a closure which inverts the pushdown decision.
+///
+/// # Arguments
+/// - a: Checks if a remote filter must be used or not.
+pub fn pushdown_not<C, A>(a: A) -> impl Fn(&C) -> bool
+where
+    A: Fn(&C) -> bool,
+{
+    move |filter_cfg: &C| !a(filter_cfg)
+}
 
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
-                &mut |v: Vec<SubBucket>, _: &Filter| v,
-                &Filter::default(),
-                false,
-                true,
-            )
-            .unwrap();
-            assert_eq!(v, vec![]);
+/// Buckets which must be scanned regardless of bloom/cache verdicts.
+#[derive(Default)]
+pub struct PinnedBuckets {
+    pinned: BTreeSet<Bucket>,
+}
 
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
-                &mut |v: Vec<SubBucket>, _: &Filter| v,
-                &Filter::default(),
-                true,
-                false,
-            )
-            .unwrap();
-            assert_eq!(v, vec![]);
+impl PinnedBuckets {
+    /// Pins the bucket; returns false if the bucket was already pinned.
+    pub fn pin(&mut self, b: Bucket) -> bool {
+        self.pinned.insert(b)
+    }
 
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
-                &mut |v: Vec<SubBucket>, _: &Filter| v,
-                &Filter::default(),
-                true,
-                true,
-            )
-            .unwrap();
-            assert_eq!(v, vec![]);
-        }
+    /// Unpins the bucket; returns false if the bucket was not pinned.
+    pub fn unpin(&mut self, b: &Bucket) -> bool {
+        self.pinned.remove(b)
+    }
 
-        #[test]
-        fn test_local_check() {
-            let mut dummy: u8 = 0;
-            let b: Bucket =
-                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+    /// Checks if the bucket is pinned.
+    pub fn is_pinned(&self, b: &Bucket) -> bool {
+        self.pinned.contains(b)
+    }
+}
 
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
-                    Ok(vec![
-                        SubBucket {
-                            id: 0x0042,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0043,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0044,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0045,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0046,
-                            data: vec![],
-                        },
-                    ])
-                },
-                &mut |v: Vec<SubBucket>, f: &Filter| {
-                    v.into_iter()
-                        .filter(|s| {
-                            let lbi: u16 = f.id_lbi;
-                            let ubi: u16 = f.id_ubi;
-                            let id: u16 = s.id;
-                            lbi <= id && id <= ubi
-                        })
-                        .collect()
-                },
-                &Filter {
-                    id_lbi: 0x0043,
-                    id_ubi: 0x0045,
-                },
-                false,
-                false,
-            )
-            .unwrap();
+/// Gets values from a slow db if the bucket is pinned or the values may exists.
+///
+/// # Arguments
+/// - pins: The pinned buckets which are always scanned.
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn get_or_skip_with_pins<B, D, G, F, T>(
+    pins: &PinnedBuckets,
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    match pins.is_pinned(bucket) {
+        true => getter(shared_db, bucket, filter),
+        false => get_or_skip_if_missing(bloom, shared_db, bucket, getter, filter),
+    }
+}
 
-            assert_eq!(v.len(), 3);
+/// Gets raw rows from a bucket and maps them to typed values.
+///
+/// The mapper can be a deserializer, e.g, `serde_json::from_value` for json rows.
+///
+/// # Arguments
+/// - shared_db: The db which may contain rows.
+/// - bucket: The bucket which may contain rows.
+/// - getter: Gets raw rows from a bucket.
+/// - mapper: Converts a raw row to a typed value.
+/// - filter: The filter to get rows.
+///
+/// # Errors
+/// All mapping errors are collected into a single [`Event::UnexpectedError`].
+pub fn get_mapped<D, F, G, M, R, T, E>(
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    mapper: &M,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<R>, Event>,
+    M: Fn(R) -> Result<T, E>,
+    E: Display,
+{
+    let raws: Vec<R> = getter(shared_db, bucket, filter)?;
+    let mut mapped: Vec<T> = Vec::with_capacity(raws.len());
+    let mut errors: Vec<String> = vec![];
+    for (i, raw) in raws.into_iter().enumerate() {
+        match mapper(raw) {
+            Ok(t) => mapped.push(t),
+            Err(e) => errors.push(format!("row {}: {}", i, e)),
         }
+    }
+    match errors.is_empty() {
+        true => Ok(mapped),
+        false => Err(Event::UnexpectedError(format!(
+            "Unable to map rows from {}: {}",
+            bucket.as_str(),
+            errors.join(", "),
+        ))),
+    }
+}
 
-        #[test]
-        fn test_pushdown_only() {
-            let mut dummy: u8 = 0;
-            let b: Bucket =
-                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
-
-            let v: Vec<SubBucket> = get_sub_buckets(
-                &mut dummy,
-                &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
-                    Ok(vec![
-                        SubBucket {
-                            id: 0x0042,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0043,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0044,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0045,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0046,
-                            data: vec![],
-                        },
-                    ])
-                },
-                &mut |v: Vec<SubBucket>, f: &Filter| {
-                    v.into_iter()
-                        .filter(|s| {
-                            let lbi: u16 = f.id_lbi;
-                            let ubi: u16 = f.id_ubi;
-                            let id: u16 = s.id;
-                            lbi <= id && id <= ubi
-                        })
-                        .collect()
-                },
-                &Filter {
-                    id_lbi: 0x0043,
-                    id_ubi: 0x0045,
-                },
-                true,
-                false,
-            )
-            .unwrap();
+/// A validated list of column names to be selected.
+pub struct ColumnSpec {
+    columns: Vec<String>,
+}
 
-            assert_eq!(v.len(), 5);
+impl ColumnSpec {
+    /// Creates a column spec; each column name must be an identifier.
+    pub fn new(columns: Vec<String>) -> Result<Self, Event> {
+        match columns.is_empty() {
+            true => Err(Event::UnexpectedError("No columns specified".into())),
+            false => Ok(()),
+        }?;
+        for c in &columns {
+            validate_identifier(c.as_str())?;
         }
+        Ok(Self { columns })
+    }
 
-        #[test]
-        fn test_double_check() {
-            let mut dummy: u8 = 0;
-            let b: Bucket =
-                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+    /// Gets the column names.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
 
-            let v: Vec<SubBucket> = get_sub_buckets(
+    /// Builds the quoted comma-separated column list for a `SELECT`.
+    ///
+    /// e.g, `"datcollate", "datname"`
+    pub fn build_projection(&self) -> String {
+        let quoted: Vec<String> = self.columns.iter().map(|c| format!("\"{}\"", c)).collect();
+        quoted.join(", ")
+    }
+}
+
+/// Scans many buckets using a single batch query(e.g, `WHERE bucket = ANY($1)`).
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The candidate buckets.
+/// - getter_batch: Gets values from the approved buckets at once.
+/// - filter: The filter to get values.
+///
+/// # Returns
+/// The values for each approved bucket; the batch getter is not called if no bucket is approved.
+/// A row of a bucket which was not approved(skipped or not requested) is an error.
+pub fn coalesced_scan<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter_batch: &mut G,
+    filter: &F,
+) -> Result<BTreeMap<Bucket, Vec<T>>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &[Bucket], &F) -> Result<Vec<(Bucket, T)>, Event>,
+{
+    let approved: Vec<Bucket> = buckets
+        .iter()
+        .filter(|b| matches!(bloom(b, filter), BloomResult::MayExist))
+        .cloned()
+        .collect();
+    let mut grouped: BTreeMap<Bucket, Vec<T>> =
+        approved.iter().map(|b| (b.clone(), vec![])).collect();
+    match approved.is_empty() {
+        true => Ok(grouped),
+        false => {
+            let rows: Vec<(Bucket, T)> = getter_batch(shared_db, &approved, filter)?;
+            for (b, t) in rows {
+                let values: &mut Vec<T> = grouped.get_mut(&b).ok_or_else(|| {
+                    Event::UnexpectedError(format!(
+                        "The batch getter returned a row of an unapproved bucket: {}",
+                        b.as_str()
+                    ))
+                })?;
+                values.push(t);
+            }
+            Ok(grouped)
+        }
+    }
+}
+
+/// List of orders to check a cache and a bloom filter.
+#[derive(Clone, Copy)]
+pub enum CheckOrder {
+    /// Checks the cache first; the bloom filter is skipped if the cache rejects.
+    CacheThenBloom,
+
+    /// Checks the bloom filter first; the cache is skipped if the bloom filter rejects.
+    BloomThenCache,
+}
+
+/// Gets values from a slow db only if both the cache and the bloom filter accept the bucket.
+///
+/// # Arguments
+/// - order: The order of checks; the cheaper check should run first.
+/// - cache: Checks if a bucket exists.
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn get_or_skip_ordered<C, B, D, G, F, T>(
+    order: CheckOrder,
+    cache: &C,
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    C: Fn(&Bucket) -> bool,
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let cache_ok = || cache(bucket);
+    let bloom_ok = || matches!(bloom(bucket, filter), BloomResult::MayExist);
+    let scan: bool = match order {
+        CheckOrder::CacheThenBloom => cache_ok() && bloom_ok(),
+        CheckOrder::BloomThenCache => bloom_ok() && cache_ok(),
+    };
+    match scan {
+        true => getter(shared_db, bucket, filter),
+        false => Ok(vec![]),
+    }
+}
+
+/// A query template which contains `{bucket}` placeholders.
+///
+/// e.g, `SELECT datcollate FROM {bucket} WHERE datname = $1::TEXT`
+pub struct QueryTemplate {
+    template: String,
+}
+
+impl QueryTemplate {
+    /// Creates a template.
+    pub fn new(template: String) -> Self {
+        Self { template }
+    }
+
+    /// Gets the template string.
+    pub fn as_str(&self) -> &str {
+        self.template.as_str()
+    }
+
+    /// Replaces all `{bucket}` placeholders with the name of the bucket.
+    pub fn render(&self, b: &Bucket) -> String {
+        self.template.replace("{bucket}", b.as_str())
+    }
+}
+
+fn find_placeholder(rendered: &str) -> Option<&str> {
+    let mut rest: &str = rendered;
+    while let Some(start) = rest.find('{') {
+        let after: &str = &rest[start + 1..];
+        let end: Option<usize> = after.find('}');
+        let found: Option<&str> = end
+            .map(|e| &after[..e])
+            .filter(|name| validate_identifier(name).is_ok());
+        match found {
+            Some(name) => return Some(name),
+            None => rest = after,
+        }
+    }
+    None
+}
+
+/// Renders the template and checks the query before sending it to a db.
+///
+/// # Checks
+/// - Quotes(`'` and `"`) are balanced.
+/// - The name of the bucket is present.
+/// - No placeholder(e.g, `{bucket}`, `{schema}`) remains.
+pub fn validate_query(template: &QueryTemplate, bucket: &Bucket) -> Result<String, Event> {
+    let rendered: String = template.render(bucket);
+    let invalid = |reason: &str| {
+        Event::UnexpectedError(format!("Invalid query({}): {}", reason, template.as_str()))
+    };
+    for quote in ['\'', '"'] {
+        let cnt: usize = rendered.chars().filter(|c| quote.eq(c)).count();
+        match cnt % 2 {
+            0 => Ok(()),
+            _ => Err(invalid("unbalanced quotes")),
+        }?;
+    }
+    match rendered.contains(bucket.as_str()) {
+        true => Ok(()),
+        false => Err(invalid("no bucket")),
+    }?;
+    match find_placeholder(rendered.as_str()) {
+        None => Ok(rendered),
+        Some(_) => Err(invalid("placeholder remains")),
+    }
+}
+
+/// Limits the number of concurrent scans(a counting semaphore).
+pub struct ScanLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A permit to scan; released when dropped.
+pub struct ScanPermit<'a> {
+    limiter: &'a ScanLimiter,
+}
+
+impl Drop for ScanPermit<'_> {
+    fn drop(&mut self) {
+        let mut available = self
+            .limiter
+            .available
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *available += 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+impl ScanLimiter {
+    /// Creates a limiter which allows up to `limit` concurrent scans.
+    ///
+    /// A zero limit is an error(no scan could ever acquire a permit).
+    pub fn new(limit: usize) -> Result<Self, Event> {
+        match 0 < limit {
+            true => Ok(()),
+            false => Err(Event::UnexpectedError(String::from(
+                "The scan limit must be positive",
+            ))),
+        }?;
+        Ok(Self {
+            available: Mutex::new(limit),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Blocks until a permit is available.
+    pub fn acquire(&self) -> Result<ScanPermit<'_>, Event> {
+        let locked = self
+            .available
+            .lock()
+            .map_err(|e| Event::UnexpectedError(format!("Unable to lock the limiter: {}", e)))?;
+        let mut available = self
+            .released
+            .wait_while(locked, |a| 0 == *a)
+            .map_err(|e| Event::UnexpectedError(format!("Unable to wait a permit: {}", e)))?;
+        *available -= 1;
+        Ok(ScanPermit { limiter: self })
+    }
+}
+
+/// Gets values from a bucket while holding a permit of the limiter.
+///
+/// # Arguments
+/// - limiter: Limits the number of concurrent scans.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Gets values from a bucket.
+/// - filter: The filter to get values.
+pub fn scan_limited<D, G, F, T>(
+    limiter: &ScanLimiter,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let _permit: ScanPermit = limiter.acquire()?;
+    getter(shared_db, bucket, filter)
+}
+
+/// Creates a getter which queries the physical bucket rewritten from the logical bucket.
+///
+/// Pass the created getter to the skip functions(e.g, [`get_or_skip_if_missing`]);
+/// bloom/cache lookups use the logical bucket while the query uses the rewritten one.
+///
+/// # Arguments
+/// - name_rewriter: Rewrites a logical bucket to a physical bucket(e.g, adds a schema prefix).
+/// - getter: Gets values from a physical bucket.
+pub fn rewritten_getter_new<D, F, T, R, G>(
+    name_rewriter: R,
+    mut getter: G,
+) -> impl FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>
+where
+    R: Fn(&Bucket) -> Bucket,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    move |shared: &mut D, logical: &Bucket, filter: &F| {
+        let physical: Bucket = name_rewriter(logical);
+        getter(shared, &physical, filter)
+    }
+}
+
+/// Creates a getter which retries via the secondary getter if the primary getter fails.
+///
+/// # Arguments
+/// - primary: Gets values from a bucket(e.g, a new backend).
+/// - secondary: Gets values from a bucket(e.g, an old backend).
+/// - should_fallback: Checks if the error of the primary getter must be retried via the secondary.
+pub fn getter_with_fallback_if<D, F, T, P, S, E>(
+    mut primary: P,
+    mut secondary: S,
+    should_fallback: E,
+) -> impl FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>
+where
+    P: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    S: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    E: Fn(&Event) -> bool,
+{
+    move |shared: &mut D, b: &Bucket, filter: &F| match primary(shared, b, filter) {
+        Err(e) if should_fallback(&e) => secondary(shared, b, filter),
+        other => other,
+    }
+}
+
+/// Creates a getter which retries via the secondary getter on [`Event::UnableToConnect`].
+///
+/// See [`getter_with_fallback_if`].
+pub fn getter_with_fallback<D, F, T, P, S>(
+    primary: P,
+    secondary: S,
+) -> impl FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>
+where
+    P: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    S: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    getter_with_fallback_if(primary, secondary, |e: &Event| {
+        matches!(e, Event::UnableToConnect(_))
+    })
+}
+
+/// Scans buckets using a filter derived for each bucket.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - config_for: Creates the filter for a bucket(e.g, date-specific thresholds).
+/// - getter: Tries to get values from a bucket.
+pub fn scan_with_per_bucket_config<B, D, G, F, T, C>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    config_for: &C,
+    getter: &mut G,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    C: Fn(&Bucket) -> F,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut gathered: Vec<T> = vec![];
+    for b in buckets {
+        let filter: F = config_for(b);
+        let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, &filter)?;
+        gathered.extend(found);
+    }
+    Ok(gathered)
+}
+
+/// Scans buckets and groups the rows by the bucket they came from.
+///
+/// Buckets skipped by the bloom check are absent from the map.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned.
+/// - getter: Tries to get values from a bucket.
+/// - filter: Filters values.
+pub fn scan_grouped<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<BTreeMap<Bucket, Vec<T>>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut grouped: BTreeMap<Bucket, Vec<T>> = BTreeMap::new();
+    for b in buckets {
+        match bloom(b, filter) {
+            BloomResult::Missing => {}
+            BloomResult::MayExist => {
+                let rows: Vec<T> = getter(shared_db, b, filter)?;
+                grouped.entry(b.clone()).or_default().extend(rows);
+            }
+        }
+    }
+    Ok(grouped)
+}
+
+/// Creates a getter which caches the values for each (bucket, filter) pair.
+///
+/// The cached values live as long as the created getter(e.g, a single request).
+/// Errors are not cached.
+///
+/// # Arguments
+/// - inner: Gets values from a bucket.
+pub fn memoized_getter<D, F, T, G>(
+    mut inner: G,
+) -> impl FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>
+where
+    F: Hash + Eq + Clone,
+    T: Clone,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut memo: HashMap<(Bucket, F), Vec<T>> = HashMap::new();
+    move |shared: &mut D, b: &Bucket, filter: &F| {
+        let key: (Bucket, F) = (b.clone(), filter.clone());
+        match memo.get(&key) {
+            Some(cached) => Ok(cached.clone()),
+            None => {
+                let found: Vec<T> = inner(shared, b, filter)?;
+                memo.insert(key, found.clone());
+                Ok(found)
+            }
+        }
+    }
+}
+
+/// Scans buckets in order and sends each value to the channel as it is scanned.
+///
+/// The channel is closed(the sender dropped) once this function returns.
+/// A getter error is sent to the channel and stops the scan.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+/// - sender: Sends the scanned values to a consumer.
+///
+/// # Returns
+/// The number of values sent; an error if the receiver has been dropped.
+pub fn scan_to_channel<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+    sender: Sender<Result<T, Event>>,
+) -> Result<u64, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    T: Send,
+{
+    let disconnected = || Event::UnexpectedError("The receiver has been dropped".into());
+    let mut sent: u64 = 0;
+    for b in buckets {
+        match get_or_skip_if_missing(bloom, shared_db, b, getter, filter) {
+            Ok(found) => {
+                for item in found {
+                    sender.send(Ok(item)).map_err(|_| disconnected())?;
+                    sent += 1;
+                }
+            }
+            Err(e) => {
+                sender.send(Err(e)).map_err(|_| disconnected())?;
+                return Ok(sent);
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Scans a random sample of buckets(e.g, to estimate aggregates over huge bucket sets).
+///
+/// Each bucket is sampled if the next number of `rng` is less than `sample_fraction`.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be sampled.
+/// - sample_fraction: The probability to sample a bucket(0.0 - 1.0; an error otherwise).
+/// - rng: Generates random numbers in `[0.0, 1.0)`(see [`SampleSource`]).
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+///
+/// # Returns
+/// The values from the sampled buckets and the fraction of the buckets actually sampled.
+#[allow(clippy::too_many_arguments)]
+pub fn scan_sample<B, D, G, F, T, R>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    sample_fraction: f64,
+    rng: &mut R,
+    getter: &mut G,
+    filter: &F,
+) -> Result<(Vec<T>, f64), Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    R: SampleSource,
+{
+    match (0.0..=1.0).contains(&sample_fraction) {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError(format!(
+            "Sample fraction must be in 0.0 - 1.0: {}",
+            sample_fraction
+        ))),
+    }?;
+    let mut gathered: Vec<T> = vec![];
+    let mut sampled: usize = 0;
+    for b in buckets {
+        let sample: bool = rng.next_f64() < sample_fraction;
+        if sample {
+            sampled += 1;
+            let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+            gathered.extend(found);
+        }
+    }
+    let effective: f64 = match buckets.is_empty() {
+        true => 0.0,
+        false => sampled as f64 / buckets.len() as f64,
+    };
+    Ok((gathered, effective))
+}
+
+/// Checks if a bucket has any matching values without getting them.
+///
+/// Returns false without probing if the cache or the bloom filter rejects the bucket.
+///
+/// # Arguments
+/// - cache: Checks if a bucket exists.
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - probe: Checks if the bucket has values(e.g, `SELECT 1 FROM ... LIMIT 1`).
+/// - filter: The filter to check values.
+pub fn exists<C, B, D, P, F>(
+    cache: &C,
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    probe: &mut P,
+    filter: &F,
+) -> Result<bool, Event>
+where
+    C: Fn(&Bucket) -> bool,
+    B: Fn(&Bucket, &F) -> BloomResult,
+    P: FnMut(&mut D, &Bucket, &F) -> Result<bool, Event>,
+{
+    let may_exist: bool = cache(bucket) && matches!(bloom(bucket, filter), BloomResult::MayExist);
+    match may_exist {
+        true => probe(shared_db, bucket, filter),
+        false => Ok(false),
+    }
+}
+
+/// Scans buckets in chunks and passes the values of each chunk to the callback.
+///
+/// Only the values of a single chunk are held at a time.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - chunk_size: The number of buckets of each chunk(must be positive).
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+/// - on_chunk: Gets the chunk index and the values of the chunk.
+///
+/// # Returns
+/// The number of chunks.
+#[allow(clippy::too_many_arguments)]
+pub fn scan_in_chunks<B, D, G, F, T, O>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    chunk_size: usize,
+    getter: &mut G,
+    filter: &F,
+    on_chunk: &mut O,
+) -> Result<usize, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    O: FnMut(usize, Vec<T>),
+{
+    match 0 < chunk_size {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError("Chunk size must be positive".into())),
+    }?;
+    let mut chunks: usize = 0;
+    for (ix, chunk) in buckets.chunks(chunk_size).enumerate() {
+        let mut gathered: Vec<T> = vec![];
+        for b in chunk {
+            let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+            gathered.extend(found);
+        }
+        on_chunk(ix, gathered);
+        chunks += 1;
+    }
+    Ok(chunks)
+}
+
+/// Scans buckets in order and returns the first bucket which has values.
+///
+/// Buckets after the first hit are not scanned.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn first_hit<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<Option<(Bucket, Vec<T>)>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    for b in buckets {
+        let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+        if !found.is_empty() {
+            return Ok(Some((b.clone(), found)));
+        }
+    }
+    Ok(None)
+}
+
+/// The index of a backend of [`RoutedScan`].
+pub type BackendId = usize;
+
+/// Scans buckets against the backends(e.g, shards) assigned to them.
+pub struct RoutedScan<'a, D> {
+    backends: Vec<&'a mut D>,
+}
+
+impl<'a, D> RoutedScan<'a, D> {
+    /// Creates a scanner; the index of a backend is its [`BackendId`].
+    pub fn new(backends: Vec<&'a mut D>) -> Self {
+        Self { backends }
+    }
+
+    /// Gets the number of backends.
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// Checks if there is no backend.
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    /// Scans buckets in order; each bucket is scanned against its routed backend.
+    ///
+    /// # Arguments
+    /// - route: Gets the backend of a bucket.
+    /// - bloom: Checks if values may exists or not.
+    /// - buckets: The buckets to be scanned in order.
+    /// - getter: Tries to get values from a bucket.
+    /// - filter: The filter to get values.
+    pub fn scan<R, B, G, F, T>(
+        &mut self,
+        route: &R,
+        bloom: &B,
+        buckets: &[Bucket],
+        getter: &mut G,
+        filter: &F,
+    ) -> Result<Vec<T>, Event>
+    where
+        R: Fn(&Bucket) -> BackendId,
+        B: Fn(&Bucket, &F) -> BloomResult,
+        G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    {
+        let mut gathered: Vec<T> = vec![];
+        for b in buckets {
+            let id: BackendId = route(b);
+            let backends: usize = self.backends.len();
+            let backend: &mut D = self.backends.get_mut(id).ok_or_else(|| {
+                Event::UnexpectedError(format!(
+                    "No backend for the bucket. bucket={}, backend={}, backends={}",
+                    b.as_str(),
+                    id,
+                    backends,
+                ))
+            })?;
+            let found: Vec<T> = get_or_skip_if_missing(bloom, backend, b, getter, filter)?;
+            gathered.extend(found);
+        }
+        Ok(gathered)
+    }
+}
+
+/// Scans buckets and concatenates values in the order of the buckets.
+///
+/// Values of a bucket keep the order returned by the getter
+/// and precede the values of the later buckets.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn scan_ordered<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut gathered: Vec<T> = vec![];
+    for b in buckets {
+        let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+        gathered.extend(found);
+    }
+    Ok(gathered)
+}
+
+/// The verdict of a stage of [`FilterChain`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StageVerdict {
+    /// The bucket must be scanned.
+    Accept,
+
+    /// The bucket must be skipped.
+    Reject,
+
+    /// The next stage decides.
+    Defer,
+}
+
+type Stage<'a, F> = Box<dyn Fn(&Bucket, &F) -> StageVerdict + 'a>;
+
+/// Filter stages(e.g, cache, negative cache, bloom) evaluated in order.
+pub struct FilterChain<'a, F> {
+    stages: Vec<Stage<'a, F>>,
+}
+
+impl<F> Default for FilterChain<'_, F> {
+    fn default() -> Self {
+        Self { stages: vec![] }
+    }
+}
+
+impl<'a, F> FilterChain<'a, F> {
+    /// Adds the stage to the end of the chain.
+    pub fn add_stage<S>(&mut self, stage: S)
+    where
+        S: Fn(&Bucket, &F) -> StageVerdict + 'a,
+    {
+        self.stages.push(Box::new(stage))
+    }
+
+    /// Checks if the bucket must be scanned; the first verdict other than `Defer` wins.
+    ///
+    /// The bucket is scanned if all stages defer.
+    pub fn decide(&self, bucket: &Bucket, filter: &F) -> bool {
+        self.stages
+            .iter()
+            .map(|stage| stage(bucket, filter))
+            .find(|v| StageVerdict::Defer != *v)
+            .map(|v| StageVerdict::Accept == v)
+            .unwrap_or(true)
+    }
+}
+
+/// Stops scanning buckets which failed repeatedly until a cooldown elapses.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: BTreeMap<Bucket, (u32, Option<Instant>)>,
+}
+
+impl CircuitBreaker {
+    /// Creates a breaker.
+    ///
+    /// # Arguments
+    /// - threshold: The number of consecutive failures to open the circuit of a bucket.
+    /// - cooldown: The duration to keep the circuit open.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Checks if the circuit of the bucket is open at `now`.
+    pub fn is_open(&self, bucket: &Bucket, now: Instant) -> bool {
+        self.failures
+            .get(bucket)
+            .and_then(|(_, opened)| *opened)
+            .map(|opened| now.saturating_duration_since(opened) < self.cooldown)
+            .unwrap_or(false)
+    }
+
+    /// Resets the failures of the bucket.
+    pub fn record_success(&mut self, bucket: &Bucket) {
+        self.failures.remove(bucket);
+    }
+
+    /// Counts a failure of the bucket; opens the circuit after `threshold` failures.
+    pub fn record_failure(&mut self, bucket: &Bucket, now: Instant) {
+        let threshold: u32 = self.threshold;
+        let (count, opened) = self.failures.entry(bucket.clone()).or_insert((0, None));
+        *count += 1;
+        if threshold <= *count {
+            *opened = Some(now);
+        }
+    }
+}
+
+/// Gets values from a bucket unless its circuit is open; records the result to the breaker.
+///
+/// An open circuit is [`Event::UnableToConnect`](e.g, to fall back to another backend).
+///
+/// # Arguments
+/// - breaker: Tracks failures of buckets.
+/// - now: The current instant.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn scan_with_breaker<D, G, F, T>(
+    breaker: &mut CircuitBreaker,
+    now: Instant,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    match breaker.is_open(bucket, now) {
+        true => Err(Event::UnableToConnect(format!(
+            "circuit open: {}",
+            bucket.as_str()
+        ))),
+        false => Ok(()),
+    }?;
+    let got: Result<Vec<T>, Event> = getter(shared_db, bucket, filter);
+    match got.is_ok() {
+        true => breaker.record_success(bucket),
+        false => breaker.record_failure(bucket, now),
+    }
+    got
+}
+
+/// Chooses the cheaper scan for each bucket and checks the total cost against the budget.
+///
+/// A bucket is pushed down(index scan with a remote filter) if it is not more expensive
+/// than a sequential scan(same as [`pushdown_by_storage_new`]).
+///
+/// # Arguments
+/// - estimates: The number of index scans and sequential scans for each bucket.
+/// - ix_cost: The cost to get a row(random scan).
+/// - sq_cost: The cost to get a row(sequential scan).
+/// - budget: The max total cost.
+///
+/// # Returns
+/// The pushdown decision for each bucket and whether the total cost exceeds the budget.
+pub fn optimal_pushdown(
+    estimates: &[(Bucket, f32, f32)],
+    ix_cost: f32,
+    sq_cost: f32,
+    budget: f32,
+) -> (Vec<(Bucket, bool)>, bool) {
+    let mut total: f32 = 0.0;
+    let decisions: Vec<(Bucket, bool)> = estimates
+        .iter()
+        .map(|(b, ix, sq)| {
+            let ix_total: f32 = ix_cost * ix;
+            let sq_total: f32 = sq_cost * sq;
+            let pushdown: bool = ix_total <= sq_total;
+            total += match pushdown {
+                true => ix_total,
+                false => sq_total,
+            };
+            (b.clone(), pushdown)
+        })
+        .collect();
+    let over_budget: bool = budget < total;
+    (decisions, over_budget)
+}
+
+/// Creates a closure which converts a panic of the filter closure into [`Event::FilterFailed`].
+///
+/// The filter closure and the filter must be [`RefUnwindSafe`];
+/// wrap them with [`std::panic::AssertUnwindSafe`] only if a panic can not leave them broken.
+/// The panic hook still runs(e.g, prints the panic message).
+///
+/// # Arguments
+/// - filter: Checks a bucket(e.g, a bloom check or a user-supplied hash).
+pub fn catch_filter_panics<F, R, C>(filter: C) -> impl Fn(&Bucket, &F) -> Result<R, Event>
+where
+    C: Fn(&Bucket, &F) -> R + RefUnwindSafe,
+    F: RefUnwindSafe,
+{
+    move |b: &Bucket, f: &F| {
+        catch_unwind(|| filter(b, f))
+            .map_err(|_| Event::FilterFailed(format!("filter panicked: {}", b.as_str())))
+    }
+}
+
+/// Gets values from a bucket; [`Event::QueryFailed`] if the bucket returned more than `max_rows`
+/// values.
+///
+/// # Arguments
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+/// - max_rows: The max number of values(e.g, to detect a missing filter).
+pub fn scan_capped<D, G, F, T>(
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+    max_rows: usize,
+) -> Result<Vec<T>, Event>
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let found: Vec<T> = getter(shared_db, bucket, filter)?;
+    match found.len() <= max_rows {
+        true => Ok(found),
+        false => Err(Event::QueryFailed(format!(
+            "result too large. bucket={}, rows={}, max={}",
+            bucket.as_str(),
+            found.len(),
+            max_rows,
+        ))),
+    }
+}
+
+/// The number of rows examined and returned by a scan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanStats {
+    /// The number of rows returned.
+    pub returned: u64,
+
+    /// The number of rows examined.
+    pub scanned: u64,
+
+    /// `returned / scanned`; 0.0 if no row was examined.
+    pub selectivity: f64,
+}
+
+/// Gets values from a bucket with the number of examined rows.
+///
+/// # Arguments
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Gets values and the number of examined rows from a bucket.
+/// - filter: The filter to get values.
+pub fn get_with_scan_stats<D, G, F, T>(
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<(Vec<T>, ScanStats), Event>
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<(Vec<T>, u64), Event>,
+{
+    let (found, scanned) = getter(shared_db, bucket, filter)?;
+    let returned: u64 = found.len() as u64;
+    let selectivity: f64 = match 0 < scanned {
+        true => returned as f64 / scanned as f64,
+        false => 0.0,
+    };
+    Ok((
+        found,
+        ScanStats {
+            returned,
+            scanned,
+            selectivity,
+        },
+    ))
+}
+
+/// The decision and timing of a bucket of [`ScanReport`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportEntry {
+    /// The scanned(or skipped) bucket.
+    pub bucket: Bucket,
+
+    /// The bloom verdict.
+    pub verdict: BloomResult,
+
+    /// True if the bucket has been skipped.
+    pub skipped: bool,
+
+    /// The number of values got from the bucket.
+    pub rows: u64,
+
+    /// The time spent for the bucket(including the bloom check).
+    pub duration: Duration,
+}
+
+/// The decisions and timings of a scan.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanReport {
+    /// The entries in the order of the buckets.
+    pub entries: Vec<ReportEntry>,
+
+    /// The number of skipped buckets.
+    pub skipped: u64,
+
+    /// The number of scanned buckets.
+    pub scanned: u64,
+
+    /// The total number of values.
+    pub rows: u64,
+
+    /// The total time spent.
+    pub duration: Duration,
+}
+
+impl ScanReport {
+    fn record(&mut self, entry: ReportEntry) {
+        match entry.skipped {
+            true => self.skipped += 1,
+            false => self.scanned += 1,
+        }
+        self.rows += entry.rows;
+        self.duration += entry.duration;
+        self.entries.push(entry);
+    }
+}
+
+/// Scans buckets in order and reports the decision and timing of each bucket.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn scan_with_report<B, D, G, F, T>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<(Vec<T>, ScanReport), Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    let mut gathered: Vec<T> = vec![];
+    let mut report: ScanReport = ScanReport::default();
+    for b in buckets {
+        let started: Instant = Instant::now();
+        let verdict: BloomResult = bloom(b, filter);
+        let found: Vec<T> = match verdict {
+            BloomResult::Missing => vec![],
+            BloomResult::MayExist => getter(shared_db, b, filter)?,
+        };
+        report.record(ReportEntry {
+            bucket: b.clone(),
+            verdict,
+            skipped: BloomResult::Missing == verdict,
+            rows: found.len() as u64,
+            duration: started.elapsed(),
+        });
+        gathered.extend(found);
+    }
+    Ok((gathered, report))
+}
+
+/// Scans buckets in order; the scans are distributed to the backends in round-robin.
+///
+/// Skipped buckets do not take a turn.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - backends: The dbs(e.g, replicas) which may contain values.
+/// - buckets: The buckets to be scanned in order.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn round_robin_scan<B, D, G, F, T>(
+    bloom: &B,
+    backends: &mut [D],
+    buckets: &[Bucket],
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    match backends.is_empty() {
+        true => Err(Event::UnexpectedError("No backends to scan".into())),
+        false => Ok(()),
+    }?;
+    let mut gathered: Vec<T> = vec![];
+    let mut turn: usize = 0;
+    for b in buckets {
+        match bloom(b, filter) {
+            BloomResult::Missing => {}
+            BloomResult::MayExist => {
+                let backend: &mut D = &mut backends[turn % backends.len()];
+                gathered.extend(getter(backend, b, filter)?);
+                turn += 1;
+            }
+        }
+    }
+    Ok(gathered)
+}
+
+/// Scans buckets in descending order of priority(e.g, recent dates first).
+///
+/// Unlike other scan helpers, the values are not in the input order(see the module docs);
+/// buckets with the same priority keep the input order.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - buckets: The buckets to be scanned.
+/// - priority: Gets the priority of a bucket.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn scan_prioritized<B, D, G, F, T, P>(
+    bloom: &B,
+    shared_db: &mut D,
+    buckets: &[Bucket],
+    priority: &P,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+    P: Fn(&Bucket) -> i32,
+{
+    let mut prioritized: Vec<(i32, &Bucket)> = buckets.iter().map(|b| (priority(b), b)).collect();
+    prioritized.sort_by_key(|(p, _)| std::cmp::Reverse(*p));
+    let mut gathered: Vec<T> = vec![];
+    for (_, b) in prioritized {
+        let found: Vec<T> = get_or_skip_if_missing(bloom, shared_db, b, getter, filter)?;
+        gathered.extend(found);
+    }
+    Ok(gathered)
+}
+
+/// Creates a getter which keeps the good values and collects the errors of bad values.
+///
+/// An error of the inner getter itself(e.g, a query error) is still returned.
+///
+/// # Arguments
+/// - inner: Gets values(or errors of each value, e.g, deserialization errors) from a bucket.
+/// - errors: Collects the errors of bad values.
+pub fn lenient_getter<'a, D, F, T, G>(
+    mut inner: G,
+    errors: &'a mut Vec<Event>,
+) -> impl FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event> + 'a
+where
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<Result<T, Event>>, Event> + 'a,
+{
+    move |shared: &mut D, b: &Bucket, filter: &F| {
+        let rows: Vec<Result<T, Event>> = inner(shared, b, filter)?;
+        let mut good: Vec<T> = Vec::with_capacity(rows.len());
+        for row in rows {
+            match row {
+                Ok(t) => good.push(t),
+                Err(e) => errors.push(e),
+            }
+        }
+        Ok(good)
+    }
+}
+
+#[cfg(feature = "csv")]
+fn parse_csv(content: &str) -> Result<Vec<Vec<String>>, Event> {
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut row: Vec<String> = vec![];
+    let mut field = String::new();
+    let mut quoted: bool = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') => match chars.next_if_eq(&'"') {
+                Some(_) => field.push('"'),
+                None => match chars.peek() {
+                    None | Some(',') | Some('\r') | Some('\n') => quoted = false,
+                    Some(next) => {
+                        return Err(Event::UnexpectedError(format!(
+                            "Unexpected char after quoted csv field: {:?}",
+                            next
+                        )))
+                    }
+                },
+            },
+            (true, _) => field.push(c),
+            (false, '"') => match field.is_empty() {
+                true => quoted = true,
+                false => {
+                    return Err(Event::UnexpectedError(format!(
+                        "Unexpected quote in csv field: {}",
+                        field
+                    )))
+                }
+            },
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, _) => field.push(c),
+        }
+    }
+    match quoted {
+        true => Err(Event::UnexpectedError(String::from(
+            "Unterminated quoted csv field",
+        ))),
+        false => Ok(()),
+    }?;
+    match field.is_empty() && row.is_empty() {
+        true => {}
+        false => {
+            row.push(field);
+            rows.push(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Creates a getter which reads rows from `{dir}/{bucket}.csv`(no header row).
+///
+/// A missing file means no rows; the filter is ignored. Names which could point outside of the
+/// directory(e.g, containing `..` or a path separator) are errors.
+///
+/// # Arguments
+/// - dir: The directory of csv files.
+#[cfg(feature = "csv")]
+pub fn csv_getter<F>(
+    dir: PathBuf,
+) -> impl FnMut(&mut (), &Bucket, &F) -> Result<Vec<Vec<String>>, Event> {
+    move |_: &mut (), b: &Bucket, _: &F| {
+        let name: &str = b.as_str();
+        let unsafe_name: bool = name.is_empty()
+            || name.contains("..")
+            || name.chars().any(|c| matches!(c, '/' | '\\' | ':' | '\0'));
+        match unsafe_name {
+            true => Err(Event::UnexpectedError(format!(
+                "Invalid bucket name for a csv file: {}",
+                name
+            ))),
+            false => Ok(()),
+        }?;
+        let path: PathBuf = dir.join(format!("{}.csv", name));
+        match std::fs::read_to_string(&path) {
+            Ok(content) => parse_csv(&content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(Event::UnexpectedError(format!(
+                "Unable to read {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+}
+
+/// Scans buckets concurrently and yields the values of each bucket as soon as it completes.
+///
+/// Buckets which may contain values are scanned by up to `workers` threads; buckets missing in
+/// the bloom filter are not yielded. The results are in completion order, not in bucket order.
+/// A panic of the getter is yielded as an error of the bucket.
+///
+/// Dropping the iterator stops the workers after their in-flight scans; the rest of the buckets
+/// are not scanned. The in-flight scans are not interrupted and their results are discarded.
+///
+/// # Arguments
+/// - bloom: Checks if values may exists or not.
+/// - buckets: The buckets to be scanned.
+/// - workers: The max number of concurrent scans(must be positive).
+/// - getter: Gets values from the bucket(must be usable from many threads).
+/// - filter: Filters values.
+pub fn scan_unordered<B, G, F, T>(
+    bloom: B,
+    buckets: Vec<Bucket>,
+    workers: usize,
+    getter: G,
+    filter: F,
+) -> Result<impl Iterator<Item = Result<(Bucket, Vec<T>), Event>>, Event>
+where
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: Fn(&Bucket, &F) -> Result<Vec<T>, Event> + RefUnwindSafe + Send + Sync + 'static,
+    F: RefUnwindSafe + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    match 0 < workers {
+        true => Ok(()),
+        false => Err(Event::UnexpectedError(String::from(
+            "The number of workers must be positive",
+        ))),
+    }?;
+    let approved: VecDeque<Bucket> = buckets
+        .into_iter()
+        .filter(|b| BloomResult::MayExist == bloom(b, &filter))
+        .collect();
+    let workers: usize = workers.min(approved.len());
+    let pending: Arc<Mutex<VecDeque<Bucket>>> = Arc::new(Mutex::new(approved));
+    let getter: Arc<G> = Arc::new(getter);
+    let filter: Arc<F> = Arc::new(filter);
+    let (sender, receiver) = channel();
+    for _ in 0..workers {
+        let sender: Sender<Result<(Bucket, Vec<T>), Event>> = sender.clone();
+        let pending: Arc<Mutex<VecDeque<Bucket>>> = pending.clone();
+        let getter: Arc<G> = getter.clone();
+        let filter: Arc<F> = filter.clone();
+        thread::spawn(move || loop {
+            let next: Option<Bucket> = pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front();
+            let b: Bucket = match next {
+                Some(b) => b,
+                None => break,
+            };
+            let got: Result<Vec<T>, Event> =
+                catch_unwind(|| getter(&b, &filter)).unwrap_or_else(|_| {
+                    Err(Event::UnexpectedError(format!(
+                        "getter panicked: {}",
+                        b.as_str()
+                    )))
+                });
+            // the receiver has been dropped: stop scanning
+            if sender.send(got.map(|values| (b, values))).is_err() {
+                break;
+            }
+        });
+    }
+    Ok(receiver.into_iter())
+}
+
+/// Gets values from a bucket only if the prefilter accepts it(e.g, to exclude other regions).
+///
+/// Rejected buckets are skipped before any bloom work.
+///
+/// # Arguments
+/// - prefilter: Checks the bucket(metadata) itself.
+/// - bloom: Checks if values may exists or not.
+/// - shared_db: The db which may contain values.
+/// - bucket: The bucket which may contain values.
+/// - getter: Tries to get values from a bucket.
+/// - filter: The filter to get values.
+pub fn get_or_skip_with_prefilter<P, B, D, G, F, T>(
+    prefilter: &P,
+    bloom: &B,
+    shared_db: &mut D,
+    bucket: &Bucket,
+    getter: &mut G,
+    filter: &F,
+) -> Result<Vec<T>, Event>
+where
+    P: Fn(&Bucket) -> bool,
+    B: Fn(&Bucket, &F) -> BloomResult,
+    G: FnMut(&mut D, &Bucket, &F) -> Result<Vec<T>, Event>,
+{
+    match prefilter(bucket) {
+        true => get_or_skip_if_missing(bloom, shared_db, bucket, getter, filter),
+        false => Ok(vec![]),
+    }
+}
+
+/// A type erased filter config(e.g, to scan buckets which need differently typed configs).
+pub trait ErasedFilterConfig<S> {
+    /// Checks if a remote filter must be used or not.
+    fn push_down(&self) -> bool;
+
+    /// Checks if the sub bucket must be kept by the local filter.
+    fn keep(&self, sub: &S) -> bool;
+}
+
+/// Gets sub buckets of buckets, each filtered by its own config(see [`get_sub_buckets`]).
+///
+/// # Arguments
+/// - shared_db: The db which may contain sub buckets.
+/// - scans: The buckets and their configs.
+/// - get_sub: Gets sub buckets(remote filtered if a config is given).
+/// - double_check: Use local filtering even if a remote filter is used.
+pub fn get_sub_buckets_erased<D, S, P>(
+    shared_db: &mut D,
+    scans: &[(Bucket, Box<dyn ErasedFilterConfig<S>>)],
+    get_sub: &mut P,
+    double_check: bool,
+) -> Result<Vec<S>, Event>
+where
+    P: FnMut(&mut D, &Bucket, Option<&dyn ErasedFilterConfig<S>>) -> Result<Vec<S>, Event>,
+{
+    let mut gathered: Vec<S> = vec![];
+    for (b, cfg) in scans {
+        let push_down: bool = cfg.push_down();
+        let sub_buckets: Vec<S> = match push_down {
+            true => get_sub(shared_db, b, Some(cfg.as_ref()))?,
+            false => get_sub(shared_db, b, None)?,
+        };
+        let local_filter_required: bool = !push_down || double_check;
+        match local_filter_required {
+            true => gathered.extend(sub_buckets.into_iter().filter(|s| cfg.keep(s))),
+            false => gathered.extend(sub_buckets),
+        }
+    }
+    Ok(gathered)
+}
+
+/// The outcome of a call of an [`InstrumentedSubGetter`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SubGetterStats {
+    /// The bucket got.
+    pub bucket: Bucket,
+
+    /// True if a remote filter was used.
+    pub pushdown: bool,
+
+    /// The number of sub buckets got(`None` on error).
+    pub rows: Option<usize>,
+}
+
+/// Gets sub buckets like the closure from [`get_sub_buckets_new`] and records each call.
+pub struct InstrumentedSubGetter<G, F, P> {
+    get_sub: G,
+    filter: F,
+    pushdown: P,
+    stats: Vec<SubGetterStats>,
+}
+
+impl<G, F, P> InstrumentedSubGetter<G, F, P> {
+    /// Gets sub buckets(see [`get_sub_buckets`]) and records the outcome.
+    ///
+    /// # Arguments
+    /// - shared: The db which may contain sub buckets.
+    /// - b: The bucket which may contain sub buckets.
+    /// - cfg: The config to filter sub buckets.
+    /// - double_check: Use local filtering even if a remote filter is used.
+    pub fn get<D, C, S>(
+        &mut self,
+        shared: &mut D,
+        b: &Bucket,
+        cfg: &C,
+        double_check: bool,
+    ) -> Result<Vec<S>, Event>
+    where
+        G: FnMut(&mut D, &Bucket, Option<&C>) -> Result<Vec<S>, Event>,
+        F: Fn(Vec<S>, &C) -> Vec<S>,
+        P: Fn(&C) -> bool,
+    {
+        let remote_check: bool = (self.pushdown)(cfg);
+        let got: Result<Vec<S>, Event> = get_sub_buckets(
+            shared,
+            b,
+            &mut self.get_sub,
+            &self.filter,
+            cfg,
+            remote_check,
+            double_check,
+        );
+        self.stats.push(SubGetterStats {
+            bucket: b.clone(),
+            pushdown: remote_check,
+            rows: got.as_ref().ok().map(|subs| subs.len()),
+        });
+        got
+    }
+
+    /// Gets the outcomes of the calls in order.
+    pub fn stats(&self) -> &[SubGetterStats] {
+        &self.stats
+    }
+}
+
+/// Creates a getter like [`get_sub_buckets_new`] which records row counts and pushdown decisions.
+///
+/// # Arguments
+/// - get_sub: Gets sub buckets.
+/// - filter: Gets filtered sub buckets.
+/// - pushdown: Checks if a remote filter must be used or not.
+pub fn instrumented_sub_getter<G, F, P>(
+    get_sub: G,
+    filter: F,
+    pushdown: P,
+) -> InstrumentedSubGetter<G, F, P> {
+    InstrumentedSubGetter {
+        get_sub,
+        filter,
+        pushdown,
+        stats: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test_sub {
+
+    #[allow(clippy::unnecessary_mut_passed)]
+    mod get_sub_buckets {
+
+        use crate::bucket::Bucket;
+        use crate::sub::get_sub_buckets;
+
+        #[derive(PartialEq, Eq, Debug)]
+        struct SubBucket {
+            id: u16,
+            data: Vec<u8>,
+        }
+
+        #[derive(Default)]
+        struct Filter {
+            id_lbi: u16,
+            id_ubi: u16,
+        }
+
+        #[test]
+        fn test_empty() {
+            let mut dummy: u8 = 0;
+            let b: Bucket =
+                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
+                &mut |v: Vec<SubBucket>, _: &Filter| v,
+                &Filter::default(),
+                false,
+                false,
+            )
+            .unwrap();
+            assert_eq!(v, vec![]);
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
+                &mut |v: Vec<SubBucket>, _: &Filter| v,
+                &Filter::default(),
+                false,
+                true,
+            )
+            .unwrap();
+            assert_eq!(v, vec![]);
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
+                &mut |v: Vec<SubBucket>, _: &Filter| v,
+                &Filter::default(),
+                true,
+                false,
+            )
+            .unwrap();
+            assert_eq!(v, vec![]);
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| Ok(vec![]),
+                &mut |v: Vec<SubBucket>, _: &Filter| v,
+                &Filter::default(),
+                true,
+                true,
+            )
+            .unwrap();
+            assert_eq!(v, vec![]);
+        }
+
+        #[test]
+        fn test_local_check() {
+            let mut dummy: u8 = 0;
+            let b: Bucket =
+                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
+                    Ok(vec![
+                        SubBucket {
+                            id: 0x0042,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0043,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0044,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0045,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0046,
+                            data: vec![],
+                        },
+                    ])
+                },
+                &mut |v: Vec<SubBucket>, f: &Filter| {
+                    v.into_iter()
+                        .filter(|s| {
+                            let lbi: u16 = f.id_lbi;
+                            let ubi: u16 = f.id_ubi;
+                            let id: u16 = s.id;
+                            lbi <= id && id <= ubi
+                        })
+                        .collect()
+                },
+                &Filter {
+                    id_lbi: 0x0043,
+                    id_ubi: 0x0045,
+                },
+                false,
+                false,
+            )
+            .unwrap();
+
+            assert_eq!(v.len(), 3);
+        }
+
+        #[test]
+        fn test_pushdown_only() {
+            let mut dummy: u8 = 0;
+            let b: Bucket =
+                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
+                    Ok(vec![
+                        SubBucket {
+                            id: 0x0042,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0043,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0044,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0045,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0046,
+                            data: vec![],
+                        },
+                    ])
+                },
+                &mut |v: Vec<SubBucket>, f: &Filter| {
+                    v.into_iter()
+                        .filter(|s| {
+                            let lbi: u16 = f.id_lbi;
+                            let ubi: u16 = f.id_ubi;
+                            let id: u16 = s.id;
+                            lbi <= id && id <= ubi
+                        })
+                        .collect()
+                },
+                &Filter {
+                    id_lbi: 0x0043,
+                    id_ubi: 0x0045,
+                },
+                true,
+                false,
+            )
+            .unwrap();
+
+            assert_eq!(v.len(), 5);
+        }
+
+        #[test]
+        fn test_double_check() {
+            let mut dummy: u8 = 0;
+            let b: Bucket =
+                Bucket::new_checked("items_2023_01_01_cafef00ddeadbeafface864299792458".into());
+
+            let v: Vec<SubBucket> = get_sub_buckets(
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
+                    Ok(vec![
+                        SubBucket {
+                            id: 0x0042,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0043,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0044,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0045,
+                            data: vec![],
+                        },
+                        SubBucket {
+                            id: 0x0046,
+                            data: vec![],
+                        },
+                    ])
+                },
+                &mut |v: Vec<SubBucket>, f: &Filter| {
+                    v.into_iter()
+                        .filter(|s| {
+                            let lbi: u16 = f.id_lbi;
+                            let ubi: u16 = f.id_ubi;
+                            let id: u16 = s.id;
+                            lbi <= id && id <= ubi
+                        })
+                        .collect()
+                },
+                &Filter {
+                    id_lbi: 0x0043,
+                    id_ubi: 0x0045,
+                },
+                true,
+                true,
+            )
+            .unwrap();
+
+            assert_eq!(v.len(), 3);
+        }
+    }
+
+    mod scan_until_deadline {
+
+        use std::time::{Duration, Instant};
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{december, Bucket};
+        use crate::evt::Event;
+        use crate::sub::{scan_until_deadline, scan_until_expired};
+
+        #[test]
+        fn test_completed() {
+            let mut dummy: u8 = 0;
+            let (v, completed): (Vec<u8>, bool) = scan_until_deadline(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut dummy,
+                &december(1..=5),
+                &mut |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]),
+                &(),
+                Instant::now() + Duration::from_secs(60),
+            )
+            .unwrap();
+            assert_eq!(v.len(), 5);
+            assert!(completed);
+        }
+
+        #[test]
+        fn test_partial() {
+            let mut dummy: u8 = 0;
+            let mut checks: u8 = 0;
+            let (v, completed): (Vec<u8>, bool) = scan_until_expired(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut dummy,
+                &december(1..=5),
+                &mut |_: &mut u8, _: &Bucket, _: &()| -> Result<Vec<u8>, Event> { Ok(vec![1]) },
+                &(),
+                &mut || {
+                    checks += 1;
+                    3 < checks
+                },
+            )
+            .unwrap();
+            assert_eq!(v.len(), 3);
+            assert!(!completed);
+        }
+
+        #[test]
+        fn test_expired_before_first() {
+            let mut dummy: u8 = 0;
+            let (v, completed): (Vec<u8>, bool) = scan_until_expired(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut dummy,
+                &december(1..=5),
+                &mut |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]),
+                &(),
+                &mut || true,
+            )
+            .unwrap();
+            assert!(v.is_empty());
+            assert!(!completed);
+        }
+    }
+
+    mod pushdown_combinator {
+
+        use crate::sub::{pushdown_and, pushdown_not, pushdown_or};
+
+        #[test]
+        fn test_truth_table() {
+            let cases: Vec<(bool, bool)> =
+                vec![(false, false), (false, true), (true, false), (true, true)];
+            for (l, r) in cases {
+                let and = pushdown_and(|c: &(bool, bool)| c.0, |c: &(bool, bool)| c.1);
+                let or = pushdown_or(|c: &(bool, bool)| c.0, |c: &(bool, bool)| c.1);
+                let not = pushdown_not(|c: &(bool, bool)| c.0);
+                assert_eq!(and(&(l, r)), l && r);
+                assert_eq!(or(&(l, r)), l || r);
+                assert_eq!(not(&(l, r)), !l);
+            }
+        }
+
+        #[test]
+        fn test_de_morgan() {
+            let cases: Vec<(bool, bool)> =
+                vec![(false, false), (false, true), (true, false), (true, true)];
+            for c in cases {
+                let lhs =
+                    pushdown_not(pushdown_and(|c: &(bool, bool)| c.0, |c: &(bool, bool)| c.1));
+                let rhs = pushdown_or(
+                    pushdown_not(|c: &(bool, bool)| c.0),
+                    pushdown_not(|c: &(bool, bool)| c.1),
+                );
+                assert_eq!(lhs(&c), rhs(&c));
+            }
+        }
+
+        #[test]
+        fn test_nested() {
+            let size = |c: &(u32, u32)| 100 < c.0;
+            let selective = |c: &(u32, u32)| c.1 < 10;
+            let fixed = |_: &(u32, u32)| false;
+            let pushdown = pushdown_or(pushdown_and(size, selective), fixed);
+            assert!(pushdown(&(1000, 1)));
+            assert!(!pushdown(&(1000, 100)));
+            assert!(!pushdown(&(10, 1)));
+        }
+    }
+
+    mod get_or_skip_with_pins {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::{get_or_skip_with_pins, PinnedBuckets};
+
+        #[test]
+        fn test_pinned() {
+            let mut pins = PinnedBuckets::default();
+            let critical: Bucket = Bucket::new_checked("items_2023_01_01_critical".into());
+            let normal: Bucket = Bucket::new_checked("items_2023_01_01_normal".into());
+            assert!(pins.pin(critical.clone()));
+            assert!(!pins.pin(critical.clone()));
+
+            let mut scan_count: u8 = 0;
+            let bloom = |_: &Bucket, _: &()| BloomResult::Missing;
+            let mut getter = |cnt: &mut u8, _: &Bucket, _: &()| {
+                *cnt += 1;
+                Ok(vec![42])
+            };
+
+            let v: Vec<u8> =
+                get_or_skip_with_pins(&pins, &bloom, &mut scan_count, &critical, &mut getter, &())
+                    .unwrap();
+            assert_eq!(v, vec![42]);
+            assert_eq!(scan_count, 1);
+
+            let v: Vec<u8> =
+                get_or_skip_with_pins(&pins, &bloom, &mut scan_count, &normal, &mut getter, &())
+                    .unwrap();
+            assert_eq!(v, vec![]);
+            assert_eq!(scan_count, 1);
+
+            assert!(pins.unpin(&critical));
+            assert!(!pins.unpin(&critical));
+            let v: Vec<u8> =
+                get_or_skip_with_pins(&pins, &bloom, &mut scan_count, &critical, &mut getter, &())
+                    .unwrap();
+            assert_eq!(v, vec![]);
+            assert_eq!(scan_count, 1);
+        }
+    }
+
+    mod get_mapped {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::get_mapped;
+
+        #[derive(PartialEq, Eq, Debug)]
+        struct Item {
+            item_id: String,
+            quantity: u8,
+        }
+
+        fn parse_item(raw: String) -> Result<Item, String> {
+            let (item_id, quantity) = raw.split_once(',').ok_or("missing comma")?;
+            let quantity: u8 = quantity.parse().map_err(|_| "invalid quantity")?;
+            Ok(Item {
+                item_id: item_id.into(),
+                quantity,
+            })
+        }
+
+        #[test]
+        fn test_mapped() {
+            let mut rows: Vec<String> = vec!["4589506252015,3".into(), "4589506252016,5".into()];
+            let v: Vec<Item> = get_mapped(
+                &mut rows,
+                &Bucket::new_checked("items_2023_01_01".into()),
+                &mut |rows: &mut Vec<String>, _: &Bucket, _: &()| Ok(rows.clone()),
+                &parse_item,
+                &(),
+            )
+            .unwrap();
+            assert_eq!(
+                v,
+                vec![
+                    Item {
+                        item_id: "4589506252015".into(),
+                        quantity: 3,
+                    },
+                    Item {
+                        item_id: "4589506252016".into(),
+                        quantity: 5,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_malformed() {
+            let mut rows: Vec<String> = vec!["4589506252015,3".into(), "4589506252016".into()];
+            let r: Result<Vec<Item>, Event> = get_mapped(
+                &mut rows,
+                &Bucket::new_checked("items_2023_01_01".into()),
+                &mut |rows: &mut Vec<String>, _: &Bucket, _: &()| Ok(rows.clone()),
+                &parse_item,
+                &(),
+            );
+            match r {
+                Err(Event::UnexpectedError(msg)) => assert!(msg.contains("row 1")),
+                _ => panic!("must be an error"),
+            }
+        }
+    }
+
+    mod column_spec {
+
+        use crate::sub::ColumnSpec;
+
+        #[test]
+        fn test_single() {
+            let spec = ColumnSpec::new(vec!["datcollate".into()]).unwrap();
+            assert_eq!(spec.build_projection(), r#""datcollate""#);
+        }
+
+        #[test]
+        fn test_many() {
+            let spec = ColumnSpec::new(vec![
+                "datcollate".into(),
+                "datname".into(),
+                "_item_id2".into(),
+            ])
+            .unwrap();
+            assert_eq!(spec.columns().len(), 3);
+            assert_eq!(
+                spec.build_projection(),
+                r#""datcollate", "datname", "_item_id2""#
+            );
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!(ColumnSpec::new(vec![]).is_err());
+            assert!(ColumnSpec::new(vec!["datname".into(), "1st".into()]).is_err());
+            assert!(ColumnSpec::new(vec![r#"x"; DROP TABLE y; --"#.into()]).is_err());
+            assert!(ColumnSpec::new(vec!["".into()]).is_err());
+            assert!(ColumnSpec::new(vec!["a".repeat(64)]).is_err());
+        }
+    }
+
+    mod coalesced_scan {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::coalesced_scan;
+
+        #[test]
+        fn test_single_call() {
+            let buckets: Vec<Bucket> = ["b1", "b2", "b3", "b4"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "b2" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let mut calls: Vec<Vec<String>> = vec![];
+            let grouped: BTreeMap<Bucket, Vec<u8>> = coalesced_scan(
+                &bloom,
+                &mut calls,
+                &buckets,
+                &mut |calls: &mut Vec<Vec<String>>,
+                      approved: &[Bucket],
+                      _: &()|
+                 -> Result<Vec<(Bucket, u8)>, Event> {
+                    calls.push(approved.iter().map(|b| b.as_str().into()).collect());
+                    Ok(vec![
+                        (Bucket::new_checked("b1".into()), 1),
+                        (Bucket::new_checked("b3".into()), 3),
+                        (Bucket::new_checked("b1".into()), 11),
+                    ])
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(calls, vec![vec!["b1", "b3", "b4"]]);
+            assert_eq!(grouped.len(), 3);
+            assert_eq!(grouped[&Bucket::new_checked("b1".into())], vec![1, 11]);
+            assert_eq!(grouped[&Bucket::new_checked("b3".into())], vec![3]);
+            assert_eq!(grouped[&Bucket::new_checked("b4".into())], vec![]);
+            assert!(!grouped.contains_key(&Bucket::new_checked("b2".into())));
+        }
+
+        #[test]
+        fn test_nothing_approved() {
+            let mut dummy: u8 = 0;
+            let grouped: BTreeMap<Bucket, Vec<u8>> = coalesced_scan(
+                &|_: &Bucket, _: &()| BloomResult::Missing,
+                &mut dummy,
+                &[Bucket::new_checked("b1".into())],
+                &mut |_: &mut u8, _: &[Bucket], _: &()| panic!("must not be called"),
+                &(),
+            )
+            .unwrap();
+            assert!(grouped.is_empty());
+        }
+
+        #[test]
+        fn test_unapproved_row() {
+            let buckets: Vec<Bucket> = ["b1", "b2"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "b2" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            for unapproved in ["b2", "b9"] {
+                let got: Result<BTreeMap<Bucket, Vec<u8>>, Event> = coalesced_scan(
+                    &bloom,
+                    &mut (),
+                    &buckets,
+                    &mut |_: &mut (), _: &[Bucket], _: &()| {
+                        Ok(vec![
+                            (Bucket::new_checked("b1".into()), 1),
+                            (Bucket::new_checked(unapproved.into()), 2),
+                        ])
+                    },
+                    &(),
+                );
+                assert!(got.is_err());
+            }
+        }
+    }
+
+    mod get_or_skip_ordered {
+
+        use std::cell::Cell;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::{get_or_skip_ordered, CheckOrder};
+
+        fn run(order: CheckOrder, cached: bool, verdict: bool) -> (Vec<u8>, u8, u8) {
+            let cache_calls: Cell<u8> = Cell::new(0);
+            let bloom_calls: Cell<u8> = Cell::new(0);
+            let cache = |_: &Bucket| {
+                cache_calls.set(cache_calls.get() + 1);
+                cached
+            };
+            let bloom = |_: &Bucket, _: &()| {
+                bloom_calls.set(bloom_calls.get() + 1);
+                match verdict {
+                    true => BloomResult::MayExist,
+                    false => BloomResult::Missing,
+                }
+            };
+            let mut dummy: u8 = 0;
+            let v: Vec<u8> = get_or_skip_ordered(
+                order,
+                &cache,
+                &bloom,
+                &mut dummy,
+                &Bucket::new_checked("pg_database".into()),
+                &mut |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]),
+                &(),
+            )
+            .unwrap();
+            (v, cache_calls.get(), bloom_calls.get())
+        }
+
+        #[test]
+        fn test_cache_then_bloom() {
+            assert_eq!(run(CheckOrder::CacheThenBloom, false, true), (vec![], 1, 0));
+            assert_eq!(run(CheckOrder::CacheThenBloom, true, false), (vec![], 1, 1));
+            assert_eq!(run(CheckOrder::CacheThenBloom, true, true), (vec![1], 1, 1));
+        }
+
+        #[test]
+        fn test_bloom_then_cache() {
+            assert_eq!(run(CheckOrder::BloomThenCache, true, false), (vec![], 0, 1));
+            assert_eq!(run(CheckOrder::BloomThenCache, false, true), (vec![], 1, 1));
+            assert_eq!(run(CheckOrder::BloomThenCache, true, true), (vec![1], 1, 1));
+        }
+    }
+
+    mod validate_query {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{validate_query, QueryTemplate};
+
+        fn validate(template: &str) -> Result<String, Event> {
+            validate_query(
+                &QueryTemplate::new(template.into()),
+                &Bucket::new_checked("pg_database".into()),
+            )
+        }
+
+        #[test]
+        fn test_valid() {
+            let sql: String =
+                validate("SELECT datcollate FROM {bucket} WHERE datname = $1::TEXT").unwrap();
+            assert_eq!(
+                sql,
+                "SELECT datcollate FROM pg_database WHERE datname = $1::TEXT"
+            );
+            let sql: String = validate(r#"SELECT '{}'::JSON FROM {bucket}"#).unwrap();
+            assert_eq!(sql, r#"SELECT '{}'::JSON FROM pg_database"#);
+        }
+
+        #[test]
+        fn test_unbalanced_quotes() {
+            assert!(validate("SELECT datcollate FROM {bucket} WHERE datname = 'x").is_err());
+            assert!(validate(r#"SELECT "datcollate FROM {bucket}"#).is_err());
+        }
+
+        #[test]
+        fn test_no_bucket() {
+            assert!(validate("SELECT datcollate FROM pg_class").is_err());
+        }
+
+        #[test]
+        fn test_leftover_placeholder() {
+            assert!(validate("SELECT datcollate FROM {schema}.{bucket}").is_err());
+            assert!(validate("SELECT * FROM {bucket} JOIN {bucket_2}").is_err());
+        }
+    }
+
+    mod scan_limited {
+
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{scan_limited, ScanLimiter};
+
+        #[test]
+        fn test_limit() {
+            let limiter = ScanLimiter::new(2).unwrap();
+            let running = AtomicUsize::new(0);
+            let peak = AtomicUsize::new(0);
+            let total: usize = thread::scope(|s| {
+                let handles: Vec<_> = (0..8)
+                    .map(|i| {
+                        let limiter = &limiter;
+                        let running = &running;
+                        let peak = &peak;
+                        s.spawn(move || {
+                            let mut dummy: u8 = 0;
+                            let v: Vec<usize> = scan_limited(
+                                limiter,
+                                &mut dummy,
+                                &Bucket::new_checked(format!("b{}", i)),
+                                &mut |_: &mut u8, _: &Bucket, _: &()| -> Result<_, Event> {
+                                    let now: usize = running.fetch_add(1, Ordering::SeqCst) + 1;
+                                    peak.fetch_max(now, Ordering::SeqCst);
+                                    thread::sleep(Duration::from_millis(10));
+                                    running.fetch_sub(1, Ordering::SeqCst);
+                                    Ok(vec![i])
+                                },
+                                &(),
+                            )
+                            .unwrap();
+                            v.len()
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).sum()
+            });
+            assert_eq!(total, 8);
+            assert!(peak.load(Ordering::SeqCst) <= 2);
+            assert!(0 < peak.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn test_released_on_error() {
+            let limiter = ScanLimiter::new(1).unwrap();
+            let mut dummy: u8 = 0;
+            let b: Bucket = Bucket::new_checked("b1".into());
+            let r: Result<Vec<u8>, Event> = scan_limited(
+                &limiter,
                 &mut dummy,
                 &b,
-                &mut |_: &mut u8, _: &Bucket, _: Option<&Filter>| {
-                    Ok(vec![
-                        SubBucket {
-                            id: 0x0042,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0043,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0044,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0045,
-                            data: vec![],
-                        },
-                        SubBucket {
-                            id: 0x0046,
-                            data: vec![],
-                        },
-                    ])
+                &mut |_: &mut u8, _: &Bucket, _: &()| Err(Event::UnexpectedError("e".into())),
+                &(),
+            );
+            assert!(r.is_err());
+            let v: Vec<u8> = scan_limited(
+                &limiter,
+                &mut dummy,
+                &b,
+                &mut |_: &mut u8, _: &Bucket, _: &()| Ok(vec![1]),
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![1]);
+        }
+
+        #[test]
+        fn test_zero_limit() {
+            assert!(ScanLimiter::new(0).is_err());
+        }
+    }
+
+    mod rewritten_getter_new {
+
+        use std::cell::RefCell;
+
+        use crate::bloom::{get_or_skip_if_missing, BloomResult};
+        use crate::bucket::Bucket;
+        use crate::sub::rewritten_getter_new;
+
+        #[test]
+        fn test_schema_prefix() {
+            let checked: RefCell<Vec<String>> = RefCell::new(vec![]);
+            let bloom = |b: &Bucket, _: &()| {
+                checked.borrow_mut().push(b.as_str().into());
+                BloomResult::MayExist
+            };
+            let mut getter = rewritten_getter_new(
+                |b: &Bucket| Bucket::new_checked(format!("tenant_a.{}", b.as_str())),
+                |queried: &mut Vec<String>, b: &Bucket, _: &()| {
+                    queried.push(b.as_str().into());
+                    Ok(vec![1])
+                },
+            );
+            let mut queried: Vec<String> = vec![];
+            let v: Vec<u8> = get_or_skip_if_missing(
+                &bloom,
+                &mut queried,
+                &Bucket::new_checked("pg_database".into()),
+                &mut getter,
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![1]);
+            assert_eq!(checked.into_inner(), vec!["pg_database"]);
+            assert_eq!(queried, vec!["tenant_a.pg_database"]);
+        }
+    }
+
+    mod getter_with_fallback {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{getter_with_fallback, getter_with_fallback_if};
+
+        #[derive(Default)]
+        struct Calls {
+            primary: u8,
+            secondary: u8,
+        }
+
+        #[test]
+        fn test_fail_over() {
+            let mut getter = getter_with_fallback(
+                |c: &mut Calls, _: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                    c.primary += 1;
+                    Err(Event::UnableToConnect("new backend is down".into()))
+                },
+                |c: &mut Calls, _: &Bucket, _: &()| {
+                    c.secondary += 1;
+                    Ok(vec![2])
+                },
+            );
+            let mut calls = Calls::default();
+            let b: Bucket = Bucket::new_checked("pg_database".into());
+            assert_eq!(getter(&mut calls, &b, &()).unwrap(), vec![2]);
+            assert_eq!((calls.primary, calls.secondary), (1, 1));
+        }
+
+        #[test]
+        fn test_primary_ok() {
+            let mut getter = getter_with_fallback(
+                |c: &mut Calls, _: &Bucket, _: &()| {
+                    c.primary += 1;
+                    Ok(vec![1])
+                },
+                |c: &mut Calls, _: &Bucket, _: &()| {
+                    c.secondary += 1;
+                    Ok(vec![2])
+                },
+            );
+            let mut calls = Calls::default();
+            let b: Bucket = Bucket::new_checked("pg_database".into());
+            assert_eq!(getter(&mut calls, &b, &()).unwrap(), vec![1]);
+            assert_eq!((calls.primary, calls.secondary), (1, 0));
+        }
+
+        #[test]
+        fn test_predicate() {
+            let mut getter = getter_with_fallback(
+                |_: &mut Calls, _: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                    Err(Event::UnexpectedError("syntax error".into()))
+                },
+                |c: &mut Calls, _: &Bucket, _: &()| {
+                    c.secondary += 1;
+                    Ok(vec![2])
+                },
+            );
+            let mut calls = Calls::default();
+            let b: Bucket = Bucket::new_checked("pg_database".into());
+            assert!(getter(&mut calls, &b, &()).is_err());
+            assert_eq!(calls.secondary, 0);
+
+            let mut getter = getter_with_fallback_if(
+                |_: &mut Calls, _: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                    Err(Event::UnexpectedError("syntax error".into()))
+                },
+                |_: &mut Calls, _: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                    Err(Event::UnexpectedError("old backend error".into()))
+                },
+                |_: &Event| true,
+            );
+            match getter(&mut calls, &b, &()) {
+                Err(Event::UnexpectedError(msg)) => assert_eq!(msg, "old backend error"),
+                _ => panic!("must be the secondary error"),
+            }
+        }
+    }
+
+    mod scan_with_per_bucket_config {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::scan_with_per_bucket_config;
+
+        #[test]
+        fn test_configs() {
+            let buckets: Vec<Bucket> = ["items_2022_12_27", "items_2022_12_28", "items_2022_12_29"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let config_for = |b: &Bucket| -> u32 {
+                match b.as_str() {
+                    "items_2022_12_27" => 10,
+                    "items_2022_12_28" => 20,
+                    _ => 30,
+                }
+            };
+            let bloom = |_: &Bucket, threshold: &u32| match *threshold < 25 {
+                true => BloomResult::MayExist,
+                false => BloomResult::Missing,
+            };
+            let mut used: Vec<(String, u32)> = vec![];
+            let v: Vec<u32> = scan_with_per_bucket_config(
+                &bloom,
+                &mut used,
+                &buckets,
+                &config_for,
+                &mut |used: &mut Vec<(String, u32)>, b: &Bucket, threshold: &u32| {
+                    used.push((b.as_str().into(), *threshold));
+                    Ok(vec![*threshold])
+                },
+            )
+            .unwrap();
+            assert_eq!(v, vec![10, 20]);
+            assert_eq!(
+                used,
+                vec![
+                    ("items_2022_12_27".into(), 10),
+                    ("items_2022_12_28".into(), 20),
+                ]
+            );
+        }
+    }
+
+    mod scan_grouped {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::scan_grouped;
+
+        #[test]
+        fn test_grouped() {
+            let buckets: Vec<Bucket> = ["items_2022_12_27", "items_2022_12_28", "items_2022_12_29"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "items_2022_12_28" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let grouped: BTreeMap<Bucket, Vec<String>> = scan_grouped(
+                &bloom,
+                &mut (),
+                &buckets,
+                &mut |_: &mut (), b: &Bucket, _: &()| {
+                    Ok(vec![
+                        format!("{}-a", b.as_str()),
+                        format!("{}-b", b.as_str()),
+                    ])
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(grouped.len(), 2);
+            assert!(!grouped.contains_key(&buckets[1]));
+            assert_eq!(
+                grouped[&buckets[0]],
+                vec!["items_2022_12_27-a", "items_2022_12_27-b"]
+            );
+            assert_eq!(
+                grouped[&buckets[2]],
+                vec!["items_2022_12_29-a", "items_2022_12_29-b"]
+            );
+        }
+    }
+
+    mod memoized_getter {
+
+        use crate::bucket::Bucket;
+        use crate::sub::memoized_getter;
+
+        #[test]
+        fn test_once() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut getter = memoized_getter(|calls: &mut u32, b: &Bucket, filter: &u32| {
+                *calls += 1;
+                Ok(vec![format!("{}:{}", b.as_str(), filter)])
+            });
+            let mut calls: u32 = 0;
+            let v0: Vec<String> = getter(&mut calls, &b, &42).unwrap();
+            let v1: Vec<String> = getter(&mut calls, &b, &42).unwrap();
+            assert_eq!(v0, v1);
+            assert_eq!(calls, 1);
+
+            let v2: Vec<String> = getter(&mut calls, &b, &43).unwrap();
+            assert_eq!(v2, vec!["items_2022_12_27:43"]);
+            assert_eq!(calls, 2);
+        }
+    }
+
+    mod scan_to_channel {
+
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{december, Bucket};
+        use crate::evt::Event;
+        use crate::sub::scan_to_channel;
+
+        #[test]
+        fn test_all_rows() {
+            let (tx, rx) = channel::<Result<u32, Event>>();
+            let producer = thread::spawn(move || {
+                let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                    "items_2022_12_28" => BloomResult::Missing,
+                    _ => BloomResult::MayExist,
+                };
+                scan_to_channel(
+                    &bloom,
+                    &mut 0u32,
+                    &december(27..=29),
+                    &mut |next: &mut u32, _: &Bucket, _: &()| {
+                        *next += 2;
+                        Ok(vec![*next - 1, *next])
+                    },
+                    &(),
+                    tx,
+                )
+            });
+            let received: Vec<u32> = rx.iter().map(|r| r.unwrap()).collect();
+            assert_eq!(received, vec![1, 2, 3, 4]);
+            assert_eq!(producer.join().unwrap().unwrap(), 4);
+        }
+
+        #[test]
+        fn test_error() {
+            let (tx, rx) = channel::<Result<u32, Event>>();
+            let sent: u64 = scan_to_channel(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut (),
+                &december(27..=29),
+                &mut |_: &mut (), b: &Bucket, _: &()| match b.as_str() {
+                    "items_2022_12_28" => Err(Event::UnexpectedError("down".into())),
+                    _ => Ok(vec![1]),
+                },
+                &(),
+                tx,
+            )
+            .unwrap();
+            assert_eq!(sent, 1);
+            let received: Vec<Result<u32, Event>> = rx.iter().collect();
+            assert_eq!(received.len(), 2);
+            assert!(received[0].is_ok());
+            assert!(received[1].is_err());
+        }
+    }
+
+    mod scan_sample {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{december, Bucket};
+        use crate::evt::Event;
+        use crate::rng::{FixedSequence, SampleSource};
+        use crate::sub::scan_sample;
+
+        fn sample(fraction: f64, rng: &mut impl SampleSource) -> (Vec<String>, f64) {
+            scan_sample(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut (),
+                &december(1..=4),
+                fraction,
+                rng,
+                &mut |_: &mut (), b: &Bucket, _: &()| Ok::<_, Event>(vec![b.as_str().to_string()]),
+                &(),
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn test_nothing() {
+            let (v, effective) = sample(0.0, &mut || 0.0);
+            assert!(v.is_empty());
+            assert_eq!(effective, 0.0);
+        }
+
+        #[test]
+        fn test_everything() {
+            let (v, effective) = sample(1.0, &mut || 0.999);
+            assert_eq!(v.len(), 4);
+            assert_eq!(effective, 1.0);
+        }
+
+        #[test]
+        fn test_deterministic() {
+            let mut seq = [0.1, 0.9, 0.4, 0.6].into_iter();
+            let (v, effective) = sample(0.5, &mut || seq.next().unwrap());
+            assert_eq!(v, vec!["items_2022_12_01", "items_2022_12_03"]);
+            assert_eq!(effective, 0.5);
+        }
+
+        #[test]
+        fn test_fixed_sequence() {
+            let mut seq = FixedSequence::new(vec![0.25, 0.05, 0.35]);
+            let (v, effective) = sample(0.3, &mut seq);
+            assert_eq!(
+                v,
+                vec!["items_2022_12_01", "items_2022_12_02", "items_2022_12_04"]
+            );
+            assert_eq!(effective, 0.75);
+        }
+
+        #[test]
+        fn test_invalid_fraction() {
+            for fraction in [f64::NAN, -0.1, 1.1] {
+                let r = scan_sample(
+                    &|_: &Bucket, _: &()| BloomResult::MayExist,
+                    &mut (),
+                    &december(1..=4),
+                    fraction,
+                    &mut || 0.0,
+                    &mut |_: &mut (), _: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                        panic!("must not be called")
+                    },
+                    &(),
+                );
+                assert!(r.is_err(), "{}", fraction);
+            }
+        }
+    }
+
+    mod exists {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::exists;
+
+        fn probe(probed: &mut u32, _: &Bucket, _: &()) -> Result<bool, Event> {
+            *probed += 1;
+            Ok(true)
+        }
+
+        #[test]
+        fn test_skipped() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut probed: u32 = 0;
+            let found: bool = exists(
+                &|_: &Bucket| true,
+                &|_: &Bucket, _: &()| BloomResult::Missing,
+                &mut probed,
+                &b,
+                &mut probe,
+                &(),
+            )
+            .unwrap();
+            assert!(!found);
+            let found: bool = exists(
+                &|_: &Bucket| false,
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut probed,
+                &b,
+                &mut probe,
+                &(),
+            )
+            .unwrap();
+            assert!(!found);
+            assert_eq!(probed, 0);
+        }
+
+        #[test]
+        fn test_probed() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut probed: u32 = 0;
+            let found: bool = exists(
+                &|_: &Bucket| true,
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut probed,
+                &b,
+                &mut probe,
+                &(),
+            )
+            .unwrap();
+            assert!(found);
+            assert_eq!(probed, 1);
+        }
+    }
+
+    mod scan_in_chunks {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::scan_in_chunks;
+
+        fn scan(chunk_size: usize, received: &mut Vec<(usize, Vec<u32>)>) -> Result<usize, String> {
+            let buckets: Vec<Bucket> = (1..=5)
+                .map(|d| Bucket::new_checked(format!("items_2022_12_{:02}", d)))
+                .collect();
+            scan_in_chunks(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut (),
+                &buckets,
+                chunk_size,
+                &mut |_: &mut (), b: &Bucket, _: &()| {
+                    let (_, _, day) = b.date_part().unwrap();
+                    Ok(vec![u32::from(day)])
+                },
+                &(),
+                &mut |ix: usize, rows: Vec<u32>| received.push((ix, rows)),
+            )
+            .map_err(|e| format!("{:?}", e))
+        }
+
+        #[test]
+        fn test_chunks() {
+            let mut received: Vec<(usize, Vec<u32>)> = vec![];
+            assert_eq!(scan(2, &mut received), Ok(3));
+            assert_eq!(
+                received,
+                vec![(0, vec![1, 2]), (1, vec![3, 4]), (2, vec![5])]
+            );
+        }
+
+        #[test]
+        fn test_zero() {
+            let mut received: Vec<(usize, Vec<u32>)> = vec![];
+            assert!(scan(0, &mut received).is_err());
+            assert!(received.is_empty());
+        }
+    }
+
+    mod first_hit {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{december, Bucket};
+        use crate::sub::first_hit;
+
+        fn bloom(b: &Bucket, _: &()) -> BloomResult {
+            match b.as_str() {
+                "items_2022_12_01" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            }
+        }
+
+        #[test]
+        fn test_third() {
+            let mut scanned: Vec<String> = vec![];
+            let hit: Option<(Bucket, Vec<u8>)> = first_hit(
+                &bloom,
+                &mut scanned,
+                &december(1..=4),
+                &mut |scanned: &mut Vec<String>, b: &Bucket, _: &()| {
+                    scanned.push(b.as_str().into());
+                    match b.as_str() {
+                        "items_2022_12_02" => Ok(vec![]),
+                        _ => Ok(vec![b.date_part().unwrap().2]),
+                    }
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(
+                hit,
+                Some((Bucket::new_checked("items_2022_12_03".into()), vec![3]))
+            );
+            assert_eq!(scanned, vec!["items_2022_12_02", "items_2022_12_03"]);
+        }
+
+        #[test]
+        fn test_none() {
+            let hit: Option<(Bucket, Vec<u8>)> = first_hit(
+                &bloom,
+                &mut (),
+                &december(1..=4),
+                &mut |_: &mut (), _: &Bucket, _: &()| Ok(vec![]),
+                &(),
+            )
+            .unwrap();
+            assert_eq!(hit, None);
+        }
+    }
+
+    mod pushdown_by_storage_checked_new {
+
+        use crate::sub::{pushdown_by_storage_checked_new, pushdown_by_storage_new};
+
+        #[test]
+        fn test_valid() {
+            let checked =
+                pushdown_by_storage_checked_new(|ix: &f32| *ix, |_: &f32| 100.0, 4.0, 1.0, false);
+            let unchecked = pushdown_by_storage_new(|ix: &f32| *ix, |_: &f32| 100.0, 4.0, 1.0);
+            for ix in [1.0, 24.0, 25.0, 26.0, 1000.0] {
+                assert_eq!(checked(&ix), unchecked(&ix), "{}", ix);
+            }
+            assert!(checked(&1.0));
+            assert!(!checked(&26.0));
+        }
+
+        #[test]
+        fn test_invalid() {
+            for default in [false, true] {
+                let pushdown = pushdown_by_storage_checked_new(
+                    |ix: &f32| *ix,
+                    |_: &f32| 100.0,
+                    4.0,
+                    1.0,
+                    default,
+                );
+                for ix in [f32::NAN, f32::INFINITY, -1.0, f32::MAX] {
+                    assert_eq!(pushdown(&ix), default, "{}", ix);
+                }
+            }
+            let negative_cost =
+                pushdown_by_storage_checked_new(|_: &()| 1.0, |_: &()| 100.0, -4.0, 1.0, false);
+            assert!(!negative_cost(&()));
+        }
+    }
+
+    mod routed_scan {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{BackendId, RoutedScan};
+
+        type Backend = BTreeMap<String, Vec<String>>;
+
+        fn backend(name: &str, buckets: &[&str]) -> Backend {
+            buckets
+                .iter()
+                .map(|b| (b.to_string(), vec![format!("{}@{}", b, name)]))
+                .collect()
+        }
+
+        fn get(db: &mut Backend, b: &Bucket, _: &()) -> Result<Vec<String>, Event> {
+            db.get(b.as_str())
+                .cloned()
+                .ok_or_else(|| Event::UnexpectedError(format!("wrong backend: {}", b.as_str())))
+        }
+
+        fn route(b: &Bucket) -> BackendId {
+            usize::from(b.date_part().unwrap().2 % 2)
+        }
+
+        #[test]
+        fn test_routed() {
+            let mut even: Backend = backend("even", &["items_2022_12_02", "items_2022_12_04"]);
+            let mut odd: Backend = backend("odd", &["items_2022_12_01", "items_2022_12_03"]);
+            let mut routed: RoutedScan<Backend> = RoutedScan::new(vec![&mut even, &mut odd]);
+            assert_eq!(routed.len(), 2);
+            let buckets: Vec<Bucket> = (1..=4)
+                .map(|d| Bucket::new_checked(format!("items_2022_12_{:02}", d)))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "items_2022_12_03" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let v: Vec<String> = routed
+                .scan(&route, &bloom, &buckets, &mut get, &())
+                .unwrap();
+            assert_eq!(
+                v,
+                vec![
+                    "items_2022_12_01@odd",
+                    "items_2022_12_02@even",
+                    "items_2022_12_04@even",
+                ]
+            );
+        }
+
+        #[test]
+        fn test_no_backend() {
+            let mut only: Backend = backend("only", &[]);
+            let mut routed: RoutedScan<Backend> = RoutedScan::new(vec![&mut only]);
+            let buckets: Vec<Bucket> = vec![Bucket::new_checked("items_2022_12_01".into())];
+            let r = routed.scan(
+                &route,
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &buckets,
+                &mut get,
+                &(),
+            );
+            assert!(r.is_err());
+        }
+    }
+
+    mod scan_ordered {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::scan_ordered;
+
+        #[test]
+        fn test_order() {
+            let buckets: Vec<Bucket> = ["items_2022_12_29", "items_2022_12_27", "items_2022_12_28"]
+                .into_iter()
+                .map(|n| Bucket::new_checked(n.into()))
+                .collect();
+            let v: Vec<String> = scan_ordered(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut (),
+                &buckets,
+                &mut |_: &mut (), b: &Bucket, _: &()| {
+                    let day: u8 = b.date_part().unwrap().2;
+                    Ok(vec![format!("{}-z", day), format!("{}-a", day)])
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec!["29-z", "29-a", "27-z", "27-a", "28-z", "28-a"]);
+        }
+    }
+
+    mod filter_chain {
+
+        use std::cell::Cell;
+        use std::collections::BTreeSet;
+
+        use crate::bucket::{b, Bucket};
+        use crate::sub::{FilterChain, StageVerdict};
+
+        #[test]
+        fn test_early_reject() {
+            let negative: BTreeSet<Bucket> = [b("items_2022_12_27")].into();
+            let later = Cell::new(0);
+            let mut chain: FilterChain<()> = FilterChain::default();
+            chain.add_stage(|bucket: &Bucket, _: &()| match negative.contains(bucket) {
+                true => StageVerdict::Reject,
+                false => StageVerdict::Defer,
+            });
+            chain.add_stage(|_: &Bucket, _: &()| {
+                later.set(later.get() + 1);
+                StageVerdict::Accept
+            });
+            assert!(!chain.decide(&b("items_2022_12_27"), &()));
+            assert_eq!(later.get(), 0);
+            assert!(chain.decide(&b("items_2022_12_28"), &()));
+            assert_eq!(later.get(), 1);
+        }
+
+        #[test]
+        fn test_later_accept() {
+            let mut chain: FilterChain<u8> = FilterChain::default();
+            chain.add_stage(|_: &Bucket, _: &u8| StageVerdict::Defer);
+            chain.add_stage(|_: &Bucket, f: &u8| match *f {
+                0 => StageVerdict::Defer,
+                _ => StageVerdict::Accept,
+            });
+            chain.add_stage(|_: &Bucket, _: &u8| StageVerdict::Reject);
+            assert!(chain.decide(&b("items_2022_12_27"), &1));
+            assert!(!chain.decide(&b("items_2022_12_27"), &0));
+        }
+
+        #[test]
+        fn test_all_defer() {
+            let mut chain: FilterChain<()> = FilterChain::default();
+            assert!(chain.decide(&b("items_2022_12_27"), &()));
+            chain.add_stage(|_: &Bucket, _: &()| StageVerdict::Defer);
+            assert!(chain.decide(&b("items_2022_12_27"), &()));
+        }
+    }
+
+    mod circuit_breaker {
+
+        use std::time::{Duration, Instant};
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{scan_with_breaker, CircuitBreaker};
+
+        fn failing(calls: &mut u32, _: &Bucket, _: &()) -> Result<Vec<u8>, Event> {
+            *calls += 1;
+            Err(Event::UnexpectedError("corrupt".into()))
+        }
+
+        #[test]
+        fn test_open_and_close() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+            let t0: Instant = Instant::now();
+            let mut calls: u32 = 0;
+
+            for _ in 0..2 {
+                let r = scan_with_breaker(&mut breaker, t0, &mut calls, &b, &mut failing, &());
+                assert!(r.is_err());
+            }
+            assert_eq!(calls, 2);
+            assert!(breaker.is_open(&b, t0));
+
+            let r = scan_with_breaker(
+                &mut breaker,
+                t0 + Duration::from_secs(5),
+                &mut calls,
+                &b,
+                &mut failing,
+                &(),
+            );
+            assert!(matches!(r, Err(Event::UnableToConnect(m)) if m.starts_with("circuit open")));
+            assert_eq!(calls, 2);
+
+            let later: Instant = t0 + Duration::from_secs(10);
+            assert!(!breaker.is_open(&b, later));
+            let v: Vec<u8> = scan_with_breaker(
+                &mut breaker,
+                later,
+                &mut calls,
+                &b,
+                &mut |calls: &mut u32, _: &Bucket, _: &()| {
+                    *calls += 1;
+                    Ok(vec![1])
                 },
-                &mut |v: Vec<SubBucket>, f: &Filter| {
-                    v.into_iter()
-                        .filter(|s| {
-                            let lbi: u16 = f.id_lbi;
-                            let ubi: u16 = f.id_ubi;
-                            let id: u16 = s.id;
-                            lbi <= id && id <= ubi
-                        })
-                        .collect()
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![1]);
+            assert_eq!(calls, 3);
+
+            let r = scan_with_breaker(&mut breaker, later, &mut calls, &b, &mut failing, &());
+            assert!(r.is_err());
+            assert!(!breaker.is_open(&b, later));
+        }
+    }
+
+    mod optimal_pushdown {
+
+        use crate::bucket::{b, Bucket};
+        use crate::sub::optimal_pushdown;
+
+        #[test]
+        fn test_decisions() {
+            let estimates: Vec<(Bucket, f32, f32)> = vec![
+                (b("items_2022_12_27"), 10.0, 100.0),
+                (b("items_2022_12_28"), 50.0, 100.0),
+                (b("items_2022_12_29"), 25.0, 100.0),
+            ];
+            let (decisions, over) = optimal_pushdown(&estimates, 4.0, 1.0, 240.0);
+            assert_eq!(
+                decisions,
+                vec![
+                    (b("items_2022_12_27"), true),
+                    (b("items_2022_12_28"), false),
+                    (b("items_2022_12_29"), true),
+                ]
+            );
+            assert!(!over);
+
+            let (_, over) = optimal_pushdown(&estimates, 4.0, 1.0, 239.0);
+            assert!(over);
+        }
+    }
+
+    mod catch_filter_panics {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::catch_filter_panics;
+
+        #[test]
+        fn test_panic() {
+            let guarded = catch_filter_panics(|b: &Bucket, threshold: &u8| match *threshold {
+                0 => panic!("invalid threshold for {}", b.as_str()),
+                _ => BloomResult::MayExist,
+            });
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            assert_eq!(guarded(&b, &1).unwrap(), BloomResult::MayExist);
+            let r: Result<BloomResult, Event> = guarded(&b, &0);
+            assert!(matches!(r, Err(Event::FilterFailed(m)) if m.starts_with("filter panicked")));
+        }
+    }
+
+    mod scan_capped {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::scan_capped;
+
+        fn scan(rows: u8, max_rows: usize) -> Result<Vec<u8>, Event> {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            scan_capped(
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, rows: &u8| Ok((0..*rows).collect()),
+                &rows,
+                max_rows,
+            )
+        }
+
+        #[test]
+        fn test_under() {
+            assert_eq!(scan(3, 3).unwrap(), vec![0, 1, 2]);
+            assert!(scan(0, 0).unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_over() {
+            let r: Result<Vec<u8>, Event> = scan(4, 3);
+            assert!(matches!(r, Err(Event::QueryFailed(m)) if m.starts_with("result too large")));
+        }
+    }
+
+    mod get_with_scan_stats {
+
+        use crate::bucket::Bucket;
+        use crate::sub::{get_with_scan_stats, ScanStats};
+
+        #[test]
+        fn test_selectivity() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let (v, stats) = get_with_scan_stats(
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, _: &()| Ok((vec![1, 2], 8)),
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![1, 2]);
+            assert_eq!(
+                stats,
+                ScanStats {
+                    returned: 2,
+                    scanned: 8,
+                    selectivity: 0.25,
+                }
+            );
+
+            let (_, stats) = get_with_scan_stats(
+                &mut (),
+                &b,
+                &mut |_: &mut (), _: &Bucket, _: &()| Ok((Vec::<u8>::new(), 0)),
+                &(),
+            )
+            .unwrap();
+            assert_eq!(stats.selectivity, 0.0);
+        }
+    }
+
+    mod scan_with_report {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::{scan_with_report, ReportEntry, ScanReport};
+
+        #[test]
+        fn test_report() {
+            let buckets: Vec<Bucket> = (1..=3)
+                .map(|d| Bucket::new_checked(format!("items_2022_12_{:02}", d)))
+                .collect();
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "items_2022_12_02" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let (v, report): (Vec<u8>, ScanReport) = scan_with_report(
+                &bloom,
+                &mut (),
+                &buckets,
+                &mut |_: &mut (), b: &Bucket, _: &()| {
+                    let day: u8 = b.date_part().unwrap().2;
+                    Ok((0..day).collect())
                 },
-                &Filter {
-                    id_lbi: 0x0043,
-                    id_ubi: 0x0045,
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, vec![0, 0, 1, 2]);
+            let summary: Vec<(&str, BloomResult, bool, u64)> = report
+                .entries
+                .iter()
+                .map(|e: &ReportEntry| (e.bucket.as_str(), e.verdict, e.skipped, e.rows))
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    ("items_2022_12_01", BloomResult::MayExist, false, 1),
+                    ("items_2022_12_02", BloomResult::Missing, true, 0),
+                    ("items_2022_12_03", BloomResult::MayExist, false, 3),
+                ]
+            );
+            assert_eq!((report.scanned, report.skipped, report.rows), (2, 1, 4));
+            let total = report.entries.iter().map(|e| e.duration).sum();
+            assert_eq!(report.duration, total);
+        }
+    }
+
+    mod round_robin_scan {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{december, Bucket};
+        use crate::evt::Event;
+        use crate::sub::round_robin_scan;
+
+        fn get(handled: &mut Vec<u8>, b: &Bucket, _: &()) -> Result<Vec<u8>, Event> {
+            let day: u8 = b.date_part().unwrap().2;
+            handled.push(day);
+            Ok(vec![day])
+        }
+
+        #[test]
+        fn test_balanced() {
+            let mut backends: Vec<Vec<u8>> = vec![vec![], vec![], vec![]];
+            let v: Vec<u8> = round_robin_scan(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut backends,
+                &december(1..=9),
+                &mut get,
+                &(),
+            )
+            .unwrap();
+            assert_eq!(v, (1..=9).collect::<Vec<u8>>());
+            assert_eq!(backends, vec![vec![1, 4, 7], vec![2, 5, 8], vec![3, 6, 9]]);
+        }
+
+        #[test]
+        fn test_skipped() {
+            let mut backends: Vec<Vec<u8>> = vec![vec![], vec![]];
+            let v: Vec<u8> = round_robin_scan(
+                &|b: &Bucket, _: &()| match b.date_part().unwrap().2 {
+                    2 => BloomResult::Missing,
+                    _ => BloomResult::MayExist,
                 },
-                true,
-                true,
+                &mut backends,
+                &december(1..=4),
+                &mut get,
+                &(),
             )
             .unwrap();
+            assert_eq!(v, vec![1, 3, 4]);
+            assert_eq!(backends, vec![vec![1, 4], vec![3]]);
 
-            assert_eq!(v.len(), 3);
+            let mut none: Vec<Vec<u8>> = vec![];
+            let r = round_robin_scan(
+                &|_: &Bucket, _: &()| BloomResult::MayExist,
+                &mut none,
+                &december(1..=1),
+                &mut get,
+                &(),
+            );
+            assert!(r.is_err());
+        }
+    }
+
+    mod scan_prioritized {
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::sub::scan_prioritized;
+
+        #[test]
+        fn test_recent_first() {
+            let buckets: Vec<Bucket> = [
+                "items_2022_12_27",
+                "pg_database",
+                "items_2022_12_29",
+                "items_2022_12_28",
+            ]
+            .into_iter()
+            .map(|n| Bucket::new_checked(n.into()))
+            .collect();
+            let recent = |b: &Bucket| b.date_part().map(|(_, _, d)| i32::from(d)).unwrap_or(-1);
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "items_2022_12_28" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let mut scanned: Vec<String> = vec![];
+            let v: Vec<String> = scan_prioritized(
+                &bloom,
+                &mut scanned,
+                &buckets,
+                &recent,
+                &mut |scanned: &mut Vec<String>, b: &Bucket, _: &()| {
+                    scanned.push(b.as_str().into());
+                    Ok(vec![b.as_str().to_uppercase()])
+                },
+                &(),
+            )
+            .unwrap();
+            assert_eq!(
+                scanned,
+                vec!["items_2022_12_29", "items_2022_12_27", "pg_database"]
+            );
+            assert_eq!(v[0], "ITEMS_2022_12_29");
+        }
+    }
+
+    mod lenient_getter {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::lenient_getter;
+
+        #[test]
+        fn test_partial() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut errors: Vec<Event> = vec![];
+            {
+                let mut getter = lenient_getter(
+                    |_: &mut (), _: &Bucket, _: &()| {
+                        Ok(["1", "x", "3", "y"]
+                            .iter()
+                            .map(|s| {
+                                s.parse::<u8>()
+                                    .map_err(|e| Event::UnexpectedError(format!("{}: {}", s, e)))
+                            })
+                            .collect())
+                    },
+                    &mut errors,
+                );
+                let v: Vec<u8> = getter(&mut (), &b, &()).unwrap();
+                assert_eq!(v, vec![1, 3]);
+            }
+            assert_eq!(errors.len(), 2);
+        }
+
+        #[test]
+        fn test_inner_error() {
+            let b = Bucket::new_checked("items_2022_12_27".into());
+            let mut errors: Vec<Event> = vec![];
+            let mut getter = lenient_getter(
+                |_: &mut (), _: &Bucket, _: &()| -> Result<Vec<Result<u8, Event>>, Event> {
+                    Err(Event::UnableToConnect("down".into()))
+                },
+                &mut errors,
+            );
+            assert!(getter(&mut (), &b, &()).is_err());
+        }
+    }
+
+    #[cfg(feature = "csv")]
+    mod csv_getter {
+
+        use std::path::PathBuf;
+
+        use crate::bucket::Bucket;
+        use crate::sub::csv_getter;
+
+        struct TempDir(PathBuf);
+
+        impl Drop for TempDir {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.0);
+            }
+        }
+
+        #[test]
+        fn test_rows_per_bucket() {
+            let tmp = TempDir(
+                std::env::temp_dir().join(format!("rs-bucket-filter-csv-{}", std::process::id())),
+            );
+            let dir: PathBuf = tmp.0.clone();
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(
+                dir.join("items_2022_12_27.csv"),
+                "1,apple\r\n2,\"b,\"\"c\"\"\"\n",
+            )
+            .unwrap();
+            std::fs::write(dir.join("items_2022_12_28.csv"), "3,cherry").unwrap();
+
+            let mut getter = csv_getter::<()>(dir.clone());
+            let got = |getter: &mut dyn FnMut(&mut (), &Bucket, &()) -> _, name: &str| {
+                getter(&mut (), &Bucket::new_checked(name.into()), &())
+            };
+            assert_eq!(
+                got(&mut getter, "items_2022_12_27").unwrap(),
+                vec![vec!["1", "apple"], vec!["2", "b,\"c\""]]
+            );
+            assert_eq!(
+                got(&mut getter, "items_2022_12_28").unwrap(),
+                vec![vec!["3", "cherry"]]
+            );
+            assert!(got(&mut getter, "items_2022_12_29").unwrap().is_empty());
+
+            std::fs::write(dir.join("items_2022_12_30.csv"), "\"open").unwrap();
+            assert!(got(&mut getter, "items_2022_12_30").is_err());
+            for (i, content) in ["\"a\"b,c", "\"\"x", "1,\"a\" \n"].into_iter().enumerate() {
+                let name: String = format!("items_2022_12_1{}", i);
+                std::fs::write(dir.join(format!("{}.csv", name)), content).unwrap();
+                assert!(got(&mut getter, &name).is_err(), "{:?}", content);
+            }
+            std::fs::write(dir.join("items_2022_12_31.csv"), "\"a\",\"\"\r\n\"b\"").unwrap();
+            assert_eq!(
+                got(&mut getter, "items_2022_12_31").unwrap(),
+                vec![vec!["a", ""], vec!["b"]]
+            );
+
+            std::fs::write(dir.join("outside.csv"), "1,secret").unwrap();
+            std::fs::create_dir_all(dir.join("nested")).unwrap();
+            let mut nested = csv_getter::<()>(dir.join("nested"));
+            for name in ["../outside", "..", "a/b", "a\\b", "C:outside", ""] {
+                assert!(got(&mut nested, name).is_err(), "{}", name);
+            }
+        }
+    }
+
+    mod scan_unordered {
+
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::mpsc::{channel, Receiver, Sender};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::{buckets, Bucket};
+        use crate::evt::Event;
+        use crate::sub::scan_unordered;
+
+        fn may_exist(_: &Bucket, _: &()) -> BloomResult {
+            BloomResult::MayExist
+        }
+
+        #[test]
+        fn test_faster_first() {
+            let buckets: Vec<Bucket> =
+                buckets(["items_slow", "items_missing", "items_fast", "items_mid"]);
+            let bloom = |b: &Bucket, _: &()| match b.as_str() {
+                "items_missing" => BloomResult::Missing,
+                _ => BloomResult::MayExist,
+            };
+            let mut release: HashMap<&str, Sender<()>> = HashMap::new();
+            let mut gates: HashMap<String, Mutex<Receiver<()>>> = HashMap::new();
+            for name in ["items_fast", "items_mid", "items_slow"] {
+                let (tx, rx): (Sender<()>, Receiver<()>) = channel();
+                release.insert(name, tx);
+                gates.insert(name.into(), Mutex::new(rx));
+            }
+            let getter = move |b: &Bucket, _: &()| {
+                let gate: &Mutex<Receiver<()>> = &gates[b.as_str()];
+                gate.lock().unwrap().recv().unwrap();
+                Ok(vec![b.as_str().to_string()])
+            };
+
+            let mut got = scan_unordered(bloom, buckets, 3, getter, ()).unwrap();
+            for name in ["items_fast", "items_mid", "items_slow"] {
+                release[name].send(()).unwrap();
+                let (b, rows): (Bucket, Vec<String>) = got.next().unwrap().unwrap();
+                assert_eq!(b.as_str(), name);
+                assert_eq!(rows, vec![name]);
+            }
+            assert!(got.next().is_none());
+        }
+
+        #[test]
+        fn test_error() {
+            let buckets: Vec<Bucket> = buckets(["items_2022_12_27"]);
+            let getter = |_: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                Err(Event::UnexpectedError("down".into()))
+            };
+            let got: Vec<_> = scan_unordered(may_exist, buckets, 1, getter, ())
+                .unwrap()
+                .collect();
+            assert_eq!(got.len(), 1);
+            assert!(got[0].is_err());
+        }
+
+        #[test]
+        fn test_panic() {
+            let buckets: Vec<Bucket> = buckets(["items_a", "items_panic", "items_b"]);
+            let getter = |b: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                match b.as_str() {
+                    "items_panic" => panic!("broken getter"),
+                    _ => Ok(vec![1]),
+                }
+            };
+            let got: Vec<_> = scan_unordered(may_exist, buckets, 2, getter, ())
+                .unwrap()
+                .collect();
+            assert_eq!(got.len(), 3);
+            let failed: Vec<&Event> = got.iter().filter_map(|r| r.as_ref().err()).collect();
+            assert_eq!(failed.len(), 1);
+            assert!(
+                matches!(failed[0], Event::UnexpectedError(m) if m.starts_with("getter panicked"))
+            );
+        }
+
+        #[test]
+        fn test_workers() {
+            assert!(
+                scan_unordered(may_exist, vec![], 0, |_: &Bucket, _: &()| Ok(vec![0]), ()).is_err()
+            );
+
+            let running: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+            let peak: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+            let (r, p) = (running.clone(), peak.clone());
+            let getter = move |_: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                let now: usize = r.fetch_add(1, Ordering::SeqCst) + 1;
+                p.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                r.fetch_sub(1, Ordering::SeqCst);
+                Ok(vec![1])
+            };
+            let names: Vec<String> = (0..6).map(|i| format!("items_{}", i)).collect();
+            let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+            let got: usize = scan_unordered(may_exist, buckets(&names), 2, getter, ())
+                .unwrap()
+                .count();
+            assert_eq!(got, 6);
+            assert!(peak.load(Ordering::SeqCst) <= 2);
+        }
+
+        #[test]
+        fn test_dropped() {
+            let calls: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+            let (resume, resumed): (Sender<()>, Receiver<()>) = channel();
+            let resumed: Arc<Mutex<Receiver<()>>> = Arc::new(Mutex::new(resumed));
+            // disconnected once the worker(the last owner of the getter) exits
+            let (alive, exited): (Sender<()>, Receiver<()>) = channel();
+            let alive: Mutex<Sender<()>> = Mutex::new(alive);
+            let c = calls.clone();
+            let getter = move |_: &Bucket, _: &()| -> Result<Vec<u8>, Event> {
+                let _alive = &alive;
+                let nth: usize = c.fetch_add(1, Ordering::SeqCst);
+                if 0 < nth {
+                    resumed.lock().unwrap().recv().unwrap();
+                }
+                Ok(vec![1])
+            };
+            let names = ["items_1", "items_2", "items_3", "items_4"];
+            let mut results = scan_unordered(may_exist, buckets(names), 1, getter, ()).unwrap();
+            assert!(results.next().unwrap().is_ok());
+            drop(results);
+            resume.send(()).unwrap();
+
+            assert!(exited.recv().is_err());
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+    }
+
+    mod get_or_skip_with_prefilter {
+
+        use std::cell::Cell;
+
+        use crate::bloom::BloomResult;
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::get_or_skip_with_prefilter;
+
+        #[test]
+        fn test_prefilter() {
+            let bloom_calls: Cell<u32> = Cell::new(0);
+            let bloom = |b: &Bucket, _: &()| {
+                bloom_calls.set(bloom_calls.get() + 1);
+                match b.as_str() {
+                    "us_items_missing" => BloomResult::Missing,
+                    _ => BloomResult::MayExist,
+                }
+            };
+            let prefilter = |b: &Bucket| b.as_str().starts_with("us_");
+            let mut getter = |_: &mut (), b: &Bucket, _: &()| -> Result<Vec<String>, Event> {
+                Ok(vec![b.as_str().into()])
+            };
+            let mut get = |name: &str| {
+                let b = Bucket::new_checked(name.into());
+                get_or_skip_with_prefilter(&prefilter, &bloom, &mut (), &b, &mut getter, &())
+                    .unwrap()
+            };
+
+            assert!(get("eu_items").is_empty());
+            assert_eq!(bloom_calls.get(), 0);
+
+            assert_eq!(get("us_items"), vec!["us_items"]);
+            assert!(get("us_items_missing").is_empty());
+            assert_eq!(bloom_calls.get(), 2);
+        }
+    }
+
+    mod get_sub_buckets_erased {
+
+        use crate::bucket::Bucket;
+        use crate::evt::Event;
+        use crate::sub::{get_sub_buckets_erased, ErasedFilterConfig};
+
+        type Row = (u32, &'static str);
+
+        struct MinQuantity(u32);
+
+        impl ErasedFilterConfig<Row> for MinQuantity {
+            fn push_down(&self) -> bool {
+                true
+            }
+            fn keep(&self, sub: &Row) -> bool {
+                self.0 <= sub.0
+            }
+        }
+
+        struct TagIs(String);
+
+        impl ErasedFilterConfig<Row> for TagIs {
+            fn push_down(&self) -> bool {
+                false
+            }
+            fn keep(&self, sub: &Row) -> bool {
+                self.0 == sub.1
+            }
+        }
+
+        #[test]
+        fn test_heterogeneous() {
+            let scans: Vec<(Bucket, Box<dyn ErasedFilterConfig<Row>>)> = vec![
+                (
+                    Bucket::new_checked("items_2022_12_27".into()),
+                    Box::new(MinQuantity(3)),
+                ),
+                (
+                    Bucket::new_checked("items_2022_12_28".into()),
+                    Box::new(TagIs("fruit".into())),
+                ),
+            ];
+            let rows: Vec<Row> = vec![(1, "fruit"), (5, "fruit"), (7, "vegetable")];
+            let mut remote: Vec<(String, bool)> = vec![];
+            let mut get_sub = |_: &mut (),
+                               b: &Bucket,
+                               cfg: Option<&dyn ErasedFilterConfig<Row>>|
+             -> Result<Vec<Row>, Event> {
+                remote.push((b.as_str().into(), cfg.is_some()));
+                Ok(match cfg {
+                    None => rows.clone(),
+                    Some(c) => rows.iter().copied().filter(|r| c.keep(r)).collect(),
+                })
+            };
+
+            let got: Vec<Row> =
+                get_sub_buckets_erased(&mut (), &scans, &mut get_sub, false).unwrap();
+            assert_eq!(
+                got,
+                vec![(5, "fruit"), (7, "vegetable"), (1, "fruit"), (5, "fruit")]
+            );
+            assert_eq!(
+                remote,
+                vec![
+                    (String::from("items_2022_12_27"), true),
+                    (String::from("items_2022_12_28"), false),
+                ]
+            );
+        }
+    }
+
+    mod instrumented_sub_getter {
+
+        use std::cell::RefCell;
+
+        use crate::bucket::{b, Bucket};
+        use crate::evt::Event;
+        use crate::sub::{instrumented_sub_getter, SubGetterStats};
+
+        #[test]
+        fn test_stats() {
+            let remote: RefCell<Vec<bool>> = RefCell::new(vec![]);
+            let get_sub = |_: &mut (), b: &Bucket, cfg: Option<&u32>| -> Result<Vec<u32>, Event> {
+                remote.borrow_mut().push(cfg.is_some());
+                match b.as_str() {
+                    "items_broken" => Err(Event::UnexpectedError("down".into())),
+                    _ => Ok((0..10)
+                        .filter(|i| cfg.map(|c| c <= i).unwrap_or(true))
+                        .collect()),
+                }
+            };
+            let filter =
+                |subs: Vec<u32>, cfg: &u32| subs.into_iter().filter(|s| cfg <= s).collect();
+            let pushdown = |cfg: &u32| 5 < *cfg;
+            let mut getter = instrumented_sub_getter(get_sub, filter, pushdown);
+
+            assert_eq!(
+                getter.get(&mut (), &b("items_a"), &8, false).unwrap(),
+                vec![8, 9]
+            );
+            assert_eq!(
+                getter.get(&mut (), &b("items_b"), &3, false).unwrap().len(),
+                7
+            );
+            assert!(getter.get(&mut (), &b("items_broken"), &7, false).is_err());
+
+            let stat = |name: &str, pushdown: bool, rows: Option<usize>| SubGetterStats {
+                bucket: b(name),
+                pushdown,
+                rows,
+            };
+            assert_eq!(
+                getter.stats(),
+                &[
+                    stat("items_a", true, Some(2)),
+                    stat("items_b", false, Some(7)),
+                    stat("items_broken", true, None),
+                ]
+            );
+            let recorded: Vec<bool> = getter.stats().iter().map(|s| s.pushdown).collect();
+            assert_eq!(recorded, *remote.borrow());
         }
     }
 }
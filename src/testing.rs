@@ -0,0 +1,62 @@
+//! Fixtures for downstream tests.
+
+use crate::{bucket::Bucket, evt::Event};
+
+/// Creates a bloom bits getter which always returns the same pairs.
+///
+/// The getter can be used with [`crate::bloom::update_bloom_bits`].
+///
+/// # Arguments
+/// - pairs: The bloom bits for each bucket to be returned.
+pub fn static_bloom_getter<D, B>(
+    pairs: Vec<(Bucket, B)>,
+) -> impl FnMut(&mut D, &Bucket) -> Result<Vec<(Bucket, B)>, Event>
+where
+    B: Clone,
+{
+    move |_: &mut D, _: &Bucket| Ok(pairs.clone())
+}
+
+#[cfg(test)]
+mod test_testing {
+
+    mod static_bloom_getter {
+
+        use std::collections::BTreeMap;
+
+        use crate::bloom::{update_bloom_bits, BloomBits256};
+        use crate::bucket::Bucket;
+        use crate::testing::static_bloom_getter;
+
+        #[test]
+        fn test_update() {
+            let mut getter = static_bloom_getter(vec![
+                (
+                    Bucket::new_checked("pg_database".into()),
+                    BloomBits256::new([0x333, 0x634]),
+                ),
+                (
+                    Bucket::new_checked("pg_class".into()),
+                    BloomBits256::new([0x599, 0x3776]),
+                ),
+            ]);
+            let mut dummy: u8 = 0;
+            let mut bloom_bits: BTreeMap<Bucket, BloomBits256> = BTreeMap::new();
+            let bloom_bucket: Bucket = Bucket::new_checked("bloom_2022_12_27".into());
+            for _ in 0..2 {
+                let cnt: u64 =
+                    update_bloom_bits(&mut bloom_bits, &mut dummy, &mut getter, &bloom_bucket)
+                        .unwrap();
+                assert_eq!(cnt, 2);
+            }
+            assert_eq!(
+                bloom_bits.get(&Bucket::new_checked("pg_database".into())),
+                Some(&BloomBits256::new([0x333, 0x634])),
+            );
+            assert_eq!(
+                bloom_bits.get(&Bucket::new_checked("pg_class".into())),
+                Some(&BloomBits256::new([0x599, 0x3776])),
+            );
+        }
+    }
+}